@@ -0,0 +1,65 @@
+use wallrustler::index::{WallpaperIndex, WeightTree};
+use wallrustler::Wallpaper;
+
+const ENTRIES: usize = 100_000;
+const ITERATIONS: usize = 100_000;
+
+fn main() {
+    let names: Vec<String> = (0..ENTRIES)
+        .map(|i| format!("synthetic/{:06}.jpg", i))
+        .collect();
+    let wallpapers: Vec<Wallpaper> = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let mut wallpaper = Wallpaper::new(name.clone());
+            wallpaper.count = i % 32;
+            wallpaper
+        })
+        .collect();
+    // The directory doesn't exist, so refreshing metadata is reduced to a failed stat per entry
+    let dir = std::env::temp_dir().join("wallrustler-bench-missing");
+
+    bench("WeightTree::new", 1, || {
+        WeightTree::new(vec![1.0; ENTRIES]);
+    });
+
+    let mut tree = WeightTree::new(vec![1.0; ENTRIES]);
+    bench("WeightTree::update + find", ITERATIONS, || {
+        for i in 0..ITERATIONS {
+            tree.update(i % ENTRIES, (i % 7) as f64);
+            std::hint::black_box(tree.find((i % ENTRIES) as f64));
+        }
+    });
+
+    let mut index = WallpaperIndex::new(wallpapers);
+    bench("WallpaperIndex::sync_names", 1, || {
+        index.sync_names(&dir, names.clone());
+    });
+
+    index.add_pool("", |_| true);
+    index.add_pool("even", |wallpaper| wallpaper.count % 2 == 0);
+    bench("WallpaperIndex::pick", ITERATIONS, || {
+        for _ in 0..ITERATIONS {
+            std::hint::black_box(index.pick(&dir, ""));
+        }
+    });
+
+    bench("WallpaperIndex::remove + add", ITERATIONS, || {
+        for name in names.iter().take(ITERATIONS) {
+            index.remove(name);
+            index.add(&dir, name);
+        }
+    });
+}
+
+fn bench(name: &str, iterations: usize, mut f: impl FnMut()) {
+    let start = std::time::Instant::now();
+    f();
+    let elapsed = start.elapsed();
+    println!(
+        "{name:<40} {:>10.2?} total {:>10.2?}/iter",
+        elapsed,
+        elapsed / iterations as u32
+    );
+}
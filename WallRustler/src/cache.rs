@@ -0,0 +1,361 @@
+//! Wallpaper variants kept under `$XDG_CACHE_HOME/wallrustler`, one subdirectory per kind, named
+//! after a hash of the source path and modification time and everything else the variant
+//! depends on. Entries are touched when reused, so eviction by modification time drops the
+//! least recently used ones first
+
+pub const DEFAULT_MAX_SIZE: u64 = 512 * 1024 * 1024;
+/// Subdirectories holding variants, evicted by total size
+const KINDS: [&str; 2] = ["rendered", "oriented"];
+/// Downloads of remote sources in progress, only ever removed when stale
+const DOWNLOADS: &str = "downloads";
+const PARTIAL_EXTENSION: &str = "partial";
+/// A partial file older than this was left behind by an interrupted process
+const STALE_PARTIAL_AGE: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+static MAX_SIZE: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(DEFAULT_MAX_SIZE);
+
+pub fn set_max_size(max_size: u64) {
+    MAX_SIZE.store(max_size, std::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn max_size() -> u64 {
+    MAX_SIZE.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Key of a variant of `source`, `geometry` being the output size it was scaled to and
+/// `params` anything else it was rendered with
+pub fn variant_key(
+    source: &std::path::Path,
+    geometry: Option<(u32, u32)>,
+    params: impl std::hash::Hash,
+) -> u64 {
+    use std::hash::{Hash, Hasher};
+    let mut hasher = crate::StableHasher::default();
+    crate::hash_path(source, crate::metadata::get_modified(source)).hash(&mut hasher);
+    geometry.hash(&mut hasher);
+    params.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn entry_path(kind: &str, key: u64, extension: &str) -> std::path::PathBuf {
+    crate::get_cache_dir()
+        .join(kind)
+        .join(format!("{key:016x}.{extension}"))
+}
+
+/// The cached variant when present and readable, touched as recently used; a corrupt entry is
+/// removed so it gets rendered again
+pub fn lookup(kind: &str, key: u64, extension: &str) -> Option<std::path::PathBuf> {
+    let path = entry_path(kind, key, extension);
+    if !path.is_file() {
+        return None;
+    }
+    if !is_intact(&path, false) {
+        warn!("Removing corrupt cache entry {:?}", path);
+        let _ = std::fs::remove_file(&path);
+        return None;
+    }
+
+    if let Err(err) = std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .and_then(|file| file.set_modified(std::time::SystemTime::now()))
+    {
+        debug!("Unable to touch {:?}: {err}", path);
+    }
+    Some(path)
+}
+
+/// Writes a variant through `write`, which gets a temporary path so that an interrupted write
+/// never leaves a partial entry behind, then evicts the least recently used entries over the
+/// size limit
+pub fn store(
+    kind: &str,
+    key: u64,
+    extension: &str,
+    write: impl FnOnce(&std::path::Path) -> Result<(), std::io::Error>,
+) -> Result<std::path::PathBuf, std::io::Error> {
+    let path = entry_path(kind, key, extension);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let partial_path = path.with_extension(PARTIAL_EXTENSION);
+    if let Err(err) = write(&partial_path) {
+        let _ = std::fs::remove_file(&partial_path);
+        return Err(err);
+    }
+    std::fs::rename(&partial_path, &path)?;
+
+    match evict(&crate::get_cache_dir(), max_size(), Some(&path)) {
+        Ok((0, _)) => {}
+        Ok((count, size)) => debug!("Evicted {count} cache entries, {size} bytes"),
+        Err(err) => warn!("Unable to evict cache entries: {err}"),
+    }
+
+    Ok(path)
+}
+
+/// What `wallrustler cache stats` prints
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct CacheStats {
+    /// Kind, entry count and size in bytes
+    pub kinds: Vec<(&'static str, usize, u64)>,
+    pub oldest: Option<std::time::SystemTime>,
+}
+
+impl CacheStats {
+    pub fn size(&self) -> u64 {
+        self.kinds.iter().map(|(_, _, size)| size).sum()
+    }
+}
+
+pub fn stats() -> Result<CacheStats, std::io::Error> {
+    let mut stats = CacheStats::default();
+    let cache_dir = crate::get_cache_dir();
+    for kind in KINDS {
+        let entries = entries(&cache_dir, kind)?;
+        let size = entries.iter().map(|entry| entry.size).sum();
+        stats.kinds.push((kind, entries.len(), size));
+        stats.oldest = entries
+            .iter()
+            .map(|entry| entry.modified)
+            .chain(stats.oldest)
+            .min();
+    }
+
+    Ok(stats)
+}
+
+/// Removes every variant and download, returns the count and size in bytes of the removed files
+pub fn clear() -> Result<(usize, u64), std::io::Error> {
+    let mut removed = (0, 0);
+    let cache_dir = crate::get_cache_dir();
+    for kind in KINDS.into_iter().chain([DOWNLOADS]) {
+        for entry in entries(&cache_dir, kind)? {
+            std::fs::remove_file(&entry.path)?;
+            removed.0 += 1;
+            removed.1 += entry.size;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Removes corrupt entries and stale partial files after fully decoding every entry, then
+/// evicts down to the size limit
+pub fn prune() -> Result<(usize, u64), std::io::Error> {
+    prune_in(&crate::get_cache_dir(), max_size())
+}
+
+fn prune_in(cache_dir: &std::path::Path, max_size: u64) -> Result<(usize, u64), std::io::Error> {
+    let mut removed = (0, 0);
+    let now = std::time::SystemTime::now();
+    for kind in KINDS.into_iter().chain([DOWNLOADS]) {
+        for entry in entries(cache_dir, kind)? {
+            let is_partial = entry
+                .path
+                .extension()
+                .is_some_and(|extension| extension == PARTIAL_EXTENSION);
+            let remove = if is_partial {
+                now.duration_since(entry.modified)
+                    .is_ok_and(|age| age > STALE_PARTIAL_AGE)
+            } else {
+                kind != DOWNLOADS && !is_intact(&entry.path, true)
+            };
+            if remove {
+                debug!("Removing {:?}", entry.path);
+                std::fs::remove_file(&entry.path)?;
+                removed.0 += 1;
+                removed.1 += entry.size;
+            }
+        }
+    }
+
+    let (count, size) = evict(cache_dir, max_size, None)?;
+    Ok((removed.0 + count, removed.1 + size))
+}
+
+struct Entry {
+    path: std::path::PathBuf,
+    size: u64,
+    modified: std::time::SystemTime,
+}
+
+fn entries(cache_dir: &std::path::Path, kind: &str) -> Result<Vec<Entry>, std::io::Error> {
+    let dir_entries = match std::fs::read_dir(cache_dir.join(kind)) {
+        Ok(dir_entries) => dir_entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+
+    Ok(dir_entries
+        .filter_map(|dir_entry| dir_entry.ok())
+        .filter_map(|dir_entry| {
+            let metadata = dir_entry
+                .metadata()
+                .ok()
+                .filter(|metadata| metadata.is_file())?;
+            Some(Entry {
+                path: dir_entry.path(),
+                size: metadata.len(),
+                modified: metadata.modified().ok()?,
+            })
+        })
+        .collect())
+}
+
+/// Removes the least recently used variants until they fit into `max_size`, sparing `keep`
+fn evict(
+    cache_dir: &std::path::Path,
+    max_size: u64,
+    keep: Option<&std::path::Path>,
+) -> Result<(usize, u64), std::io::Error> {
+    let mut entries: Vec<Entry> = KINDS
+        .iter()
+        .map(|kind| entries(cache_dir, kind))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .flatten()
+        .filter(|entry| {
+            entry
+                .path
+                .extension()
+                .is_none_or(|extension| extension != PARTIAL_EXTENSION)
+        })
+        .collect();
+    let mut size: u64 = entries.iter().map(|entry| entry.size).sum();
+    entries.sort_by_key(|entry| entry.modified);
+
+    let mut evicted = (0, 0);
+    for entry in entries {
+        if size <= max_size {
+            break;
+        }
+        if Some(entry.path.as_path()) == keep {
+            continue;
+        }
+        std::fs::remove_file(&entry.path)?;
+        size -= entry.size;
+        evicted.0 += 1;
+        evicted.1 += entry.size;
+    }
+
+    Ok(evicted)
+}
+
+/// Whether the image header can be read, or the whole image decoded when `decode`
+fn is_intact(path: &std::path::Path, decode: bool) -> bool {
+    if decode {
+        image::open(path).is_ok()
+    } else {
+        image::image_dimensions(path).is_ok_and(|(width, height)| width != 0 && height != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("wallrustler-cache-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for kind in KINDS.into_iter().chain([DOWNLOADS]) {
+            std::fs::create_dir_all(dir.join(kind)).unwrap();
+        }
+        dir
+    }
+
+    /// Writes `contents` to `path`, last modified `age` ago
+    fn write(path: &std::path::Path, contents: &[u8], age: std::time::Duration) {
+        std::fs::write(path, contents).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() - age)
+            .unwrap();
+    }
+
+    fn png() -> Vec<u8> {
+        let mut png = std::io::Cursor::new(vec![]);
+        image::RgbImage::new(16, 16)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        png.into_inner()
+    }
+
+    fn file_names(dir: &std::path::Path) -> Vec<String> {
+        let mut file_names: Vec<String> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|dir_entry| dir_entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        file_names.sort();
+        file_names
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let dir = temp_dir("evict");
+        let minutes = |minutes: u64| std::time::Duration::from_secs(minutes * 60);
+        write(&dir.join("rendered/a.jpg"), &[0; 10], minutes(1));
+        write(&dir.join("rendered/b.jpg"), &[0; 10], minutes(3));
+        write(&dir.join("oriented/c.jpg"), &[0; 10], minutes(2));
+        write(&dir.join("oriented/d.jpg"), &[0; 10], minutes(4));
+        // Never counted nor evicted
+        write(&dir.join("rendered/e.partial"), &[0; 100], minutes(5));
+
+        let kept = dir.join("oriented/d.jpg");
+        let evicted = evict(&dir, 20, Some(&kept)).unwrap();
+        let rendered = file_names(&dir.join("rendered"));
+        let oriented = file_names(&dir.join("oriented"));
+        let evicted_again = evict(&dir, 20, None).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(evicted, (2, 20));
+        assert_eq!(rendered, ["a.jpg", "e.partial"]);
+        assert_eq!(oriented, ["d.jpg"]);
+        assert_eq!(evicted_again, (0, 0));
+    }
+
+    #[test]
+    fn truncated_entries_are_not_intact() {
+        let dir = temp_dir("intact");
+        let png = png();
+        let whole = dir.join("rendered/whole.png");
+        let truncated = dir.join("rendered/truncated.png");
+        let header = dir.join("rendered/header.png");
+        std::fs::write(&whole, &png).unwrap();
+        std::fs::write(&truncated, &png[..png.len() - 20]).unwrap();
+        std::fs::write(&header, &png[..8]).unwrap();
+
+        let intact = [
+            is_intact(&whole, false),
+            is_intact(&whole, true),
+            is_intact(&truncated, true),
+            is_intact(&header, false),
+        ];
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(intact, [true, true, false, false]);
+    }
+
+    #[test]
+    fn prune_spares_fresh_downloads() {
+        let dir = temp_dir("prune");
+        let now = std::time::Duration::ZERO;
+        let hours = |hours: u64| std::time::Duration::from_secs(hours * 60 * 60);
+        write(&dir.join("downloads/fresh.partial"), b"half", now);
+        write(&dir.join("downloads/stale.partial"), b"half", hours(2));
+        write(&dir.join("downloads/done.jpg"), b"not decoded", hours(2));
+        write(&dir.join("rendered/good.png"), &png(), now);
+        write(&dir.join("rendered/corrupt.png"), b"garbage", now);
+
+        let removed = prune_in(&dir, u64::MAX).unwrap();
+        let downloads = file_names(&dir.join("downloads"));
+        let rendered = file_names(&dir.join("rendered"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(removed.0, 2);
+        assert_eq!(downloads, ["done.jpg", "fresh.partial"]);
+        assert_eq!(rendered, ["good.png"]);
+    }
+}
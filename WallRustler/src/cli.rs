@@ -0,0 +1,1084 @@
+use crate::Error;
+
+const NAME: &str = env!("CARGO_PKG_NAME");
+/// Help texts wrap onto the next line past this column
+const HELP_COLUMN: usize = 34;
+
+/// What the command line asks for
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// `run` and `state`, the latter adding `Option::PrintState`
+    Run(Vec<crate::Option>),
+    Help,
+    Version,
+    Completions(Shell),
+    Man,
+    /// With the size limit in bytes to prune to
+    Cache(CacheCommand, Option<u64>),
+    #[cfg(target_os = "linux")]
+    Status(crate::status::StatusFormat),
+    /// `next`, `rate` and `ctl`, sent to the running daemon
+    #[cfg(target_os = "linux")]
+    Control(crate::dbus::Command),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CacheCommand {
+    Stats,
+    Clear,
+    Prune,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Shell {
+    Bash,
+    Fish,
+    Zsh,
+}
+
+impl Shell {
+    pub fn parse(s: &str) -> Option<Shell> {
+        match s {
+            "bash" => Some(Shell::Bash),
+            "fish" => Some(Shell::Fish),
+            "zsh" => Some(Shell::Zsh),
+            _ => None,
+        }
+    }
+}
+
+/// How the shell completes the value of an option
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Completion {
+    None,
+    File,
+    Dir,
+    Choices(&'static [&'static str]),
+}
+
+/// An option of `run` and `state`, the single source of the help, completions and man page
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct OptionSpec {
+    pub long: &'static str,
+    pub short: &'static [&'static str],
+    /// Placeholder of the value, `None` for flags
+    pub value: Option<&'static str>,
+    /// The value can be left out, it then has to be given as `--key=value`
+    pub optional_value: bool,
+    pub completion: Completion,
+    /// Lines after the first continue the description
+    pub help: &'static str,
+}
+
+const fn flag(long: &'static str, help: &'static str) -> OptionSpec {
+    OptionSpec {
+        long,
+        short: &[],
+        value: None,
+        optional_value: false,
+        completion: Completion::None,
+        help,
+    }
+}
+
+const fn with_value(long: &'static str, value: &'static str, help: &'static str) -> OptionSpec {
+    OptionSpec {
+        long,
+        short: &[],
+        value: Some(value),
+        optional_value: false,
+        completion: Completion::None,
+        help,
+    }
+}
+
+#[cfg(not(all(feature = "hyprpaper", target_os = "linux")))]
+const PROGRAMS: &[&str] = &["swww", "plasma-apply-wallpaperimage"];
+#[cfg(all(feature = "hyprpaper", target_os = "linux"))]
+const PROGRAMS: &[&str] = &["swww", "hyprpaper", "plasma-apply-wallpaperimage"];
+
+pub fn option_specs() -> Vec<OptionSpec> {
+    let mut specs = vec![
+        with_value(
+            "interval",
+            "DURATION[±JITTER]",
+            "Time between changes like 30s, 1h30m or 15m±5m (also 15m+-5m), defaults to 15m\nA bare number counts minutes",
+        ),
+        flag(
+            "align",
+            "Change on multiples of the interval since midnight, e.g. on the hour with 1h",
+        ),
+        with_value(
+            "cron",
+            "EXPR[|TAGS]",
+            "Change at the times of a cron expression like '0 */2 * * *', @daily or Mon-Fri 08:30\ninstead of every interval, picking only wallpapers with the tags; repeatable",
+        ),
+        flag("dry-run", "Print the next 10 scheduled changes and exit"),
+        with_value(
+            "seed",
+            "NUMBER",
+            "Pick reproducibly, the same state files and seed give the same wallpapers and jitter",
+        ),
+        OptionSpec {
+            optional_value: true,
+            ..with_value(
+                "sync-lead",
+                "ADDR:PORT",
+                "Announce every change to followers over UDP, on 239.255.77.77:47807 by default\nA unicast or broadcast address like 127.0.0.1:47807 works as well",
+            )
+        },
+        OptionSpec {
+            optional_value: true,
+            ..with_value(
+                "sync-follow",
+                "ADDR:PORT",
+                "Show the wallpapers the leader announces, which have to be in the directory too\nRotates on its own while the leader is gone",
+            )
+        },
+        with_value(
+            "tags",
+            "TAG,-TAG,...",
+            "Only pick wallpapers with any of the tags and none of the -tags",
+        ),
+        OptionSpec {
+            completion: Completion::Choices(&["output"]),
+            ..with_value(
+                "min-resolution",
+                "WIDTHxHEIGHT|output",
+                "Skip smaller wallpapers, evaluated per output",
+            )
+        },
+        with_value(
+            "aspect-tolerance",
+            "PERCENT",
+            "Skip wallpapers whose aspect ratio differs more from the output one",
+        ),
+        OptionSpec {
+            completion: Completion::Choices(&["fill", "fit", "blur", "center", "tile"]),
+            ..with_value(
+                "fit",
+                "MODE",
+                "Render the wallpaper to the output size: fill, fit, blur, center or tile\nblur letterboxes with a blurred copy",
+            )
+        },
+        with_value("brightness", "-100..100", "Brighten or darken every wallpaper"),
+        with_value(
+            "night-dim",
+            "PERCENT",
+            "Darken the wallpaper by the percentage during night hours",
+        ),
+        with_value("night-hours", "HH-HH", "Defaults to 20-6"),
+        flag("grayscale", "Desaturate every wallpaper"),
+        flag(
+            "show-new",
+            "Show wallpapers added to the directory at the next change",
+        ),
+        OptionSpec {
+            short: &["-v"],
+            ..flag(
+                "verbose",
+                "Log one level more than RUST_LOG, debug messages include the output of backend commands",
+            )
+        },
+        OptionSpec {
+            short: &["-q", "-qq"],
+            ..flag(
+                "quiet",
+                "Log one level less than RUST_LOG, or two levels less",
+            )
+        },
+        OptionSpec {
+            completion: Completion::File,
+            ..with_value(
+                "log-file",
+                "PATH",
+                "Also log to the file, rotated keeping 3 old files",
+            )
+        },
+        with_value(
+            "log-max-size",
+            "MiB",
+            "Size at which the log file is rotated, defaults to 1",
+        ),
+        flag(
+            "theme",
+            "Write a pywal compatible colors.json of every new wallpaper",
+        ),
+        OptionSpec {
+            completion: Completion::Dir,
+            ..with_value(
+                "theme-dir",
+                "DIR",
+                "Where colors.json and rendered templates go, defaults to ~/.cache/wal",
+            )
+        },
+        OptionSpec {
+            completion: Completion::Dir,
+            ..with_value(
+                "theme-templates",
+                "DIR",
+                "Templates to render, defaults to ~/.config/wal/templates",
+            )
+        },
+        with_value(
+            "pre-hook",
+            "COMMAND",
+            "Run through the shell before every change, a non-zero exit status vetoes the wallpaper",
+        ),
+        with_value(
+            "post-hook",
+            "COMMAND",
+            "Run through the shell after every change\nHooks get WALLPAPER, WALLRUSTLER_PREVIOUS, WALLRUSTLER_OUTPUT, WALLRUSTLER_BACKEND,\nWALLRUSTLER_WIDTH, WALLRUSTLER_HEIGHT and, post-change, WALLRUSTLER_COLORS",
+        ),
+        with_value(
+            "hook-timeout",
+            "SECONDS",
+            "Kill hooks running longer, defaults to 10\nHooks are skipped once a change spent three times as long in them",
+        ),
+        with_value(
+            "source",
+            "http|feed|json:URL",
+            "Mirror the images of a directory index, RSS/Atom feed or JSON endpoint\ninto a subdirectory named after the host, with curl; repeatable\njson:URL#/images/*/url selects the URLs by JSON pointer, every \"url\" by default\nAn existing subdirectory is only used when empty or created by a source",
+        ),
+        with_value(
+            "source-max-size",
+            "MiB",
+            "Size of the images kept per source, defaults to 256",
+        ),
+        with_value(
+            "source-refresh",
+            "MINUTES",
+            "How often sources are fetched again, defaults to 360",
+        ),
+        with_value(
+            "cache-max-size",
+            "MiB",
+            "Evict the least recently used rendered variants above, defaults to 512",
+        ),
+        flag("print-state", "Same as the state command"),
+    ];
+    #[cfg(target_os = "linux")]
+    specs.extend([
+        flag(
+            "restart-swww",
+            "Might resolve the issue with out-of-sync and overlapping animations/wallpapers",
+        ),
+        flag(
+            "install-service",
+            "Install a systemd user service running with the other options",
+        ),
+        with_value(
+            "pause-on-battery",
+            "PERCENT",
+            "Pause rotation while discharging below the charge",
+        ),
+        flag(
+            "pause-on-fullscreen",
+            "Pause rotation while a fullscreen window is focused (Hyprland, X11)",
+        ),
+        flag(
+            "pause-when-idle",
+            "Pause rotation while the session is idle or locked",
+        ),
+        flag(
+            "live",
+            "Include videos and animated images, played with mpvpaper or xwinwrap + mpv",
+        ),
+        with_value(
+            "live-max-load",
+            "LOAD",
+            "Pause live wallpapers while the load average per core is higher",
+        ),
+        flag("live-on-battery", "Keep playing live wallpapers on battery"),
+        OptionSpec {
+            optional_value: true,
+            completion: Completion::Choices(&["swaylock", "hyprlock", "kde", "sddm"]),
+            ..with_value(
+                "lock-screen",
+                "swaylock,hyprlock,kde,sddm",
+                "Copy every new wallpaper to the lock image and point the lock screens at it",
+            )
+        },
+        OptionSpec {
+            completion: Completion::File,
+            ..with_value(
+                "lock-image",
+                "PATH",
+                "Defaults to ~/.cache/wallrustler/lockscreen.png, SDDM needs a path its user can read",
+            )
+        },
+        OptionSpec {
+            optional_value: true,
+            ..with_value(
+                "lock-blur",
+                "SIGMA",
+                "Blur the lock image, defaults to a sigma of 8",
+            )
+        },
+        OptionSpec {
+            completion: Completion::Choices(PROGRAMS),
+            ..with_value("program", "PROGRAM", "Backend to set wallpapers with")
+        },
+    ]);
+
+    specs
+}
+
+/// Subcommands with their arguments and description
+fn commands() -> Vec<(&'static str, &'static str, &'static str)> {
+    #[allow(unused_mut)]
+    let mut commands = vec![
+        (
+            "run",
+            "[OPTIONS] DIRECTORY",
+            "Rotate the wallpapers of the directory, the default command",
+        ),
+        (
+            "state",
+            "[OPTIONS] DIRECTORY",
+            "Print the wallpapers matching the filters with their details",
+        ),
+        (
+            "cache",
+            "stats|clear|prune [--max-size MiB]",
+            "Inspect or shrink the cache of rendered variants",
+        ),
+    ];
+    #[cfg(target_os = "linux")]
+    commands.extend([
+        (
+            "status",
+            "[--format plain|waybar|i3blocks|polybar]",
+            "Query the running daemon",
+        ),
+        ("next", "", "Change the wallpaper now"),
+        (
+            "rate",
+            "RATING [WALLPAPER]",
+            "Rate a wallpaper from -1 to 5, the current one by default",
+        ),
+        (
+            "tag",
+            "TAGS [WALLPAPER]",
+            "Tag a wallpaper, the current one by default; -TAG removes a tag",
+        ),
+        (
+            "ctl",
+            "next|previous|pause|resume|set PATH|rate RATING [WALLPAPER]|tag TAGS [WALLPAPER]",
+            "Control the running daemon",
+        ),
+    ]);
+    commands.extend([
+        (
+            "completions",
+            "bash|fish|zsh",
+            "Print the shell completion script",
+        ),
+        ("man", "", "Print the man page"),
+    ]);
+
+    commands
+}
+
+fn find_spec<'a>(specs: &'a [OptionSpec], arg: &str) -> Option<&'a OptionSpec> {
+    specs
+        .iter()
+        .find(|spec| arg.strip_prefix("--") == Some(spec.long) || spec.short.contains(&arg))
+}
+
+/// Rewrites `--key value` into `--key=value`, arguments after `--` are kept as they are
+pub fn normalize_args(args: Vec<String>) -> Result<Vec<String>, Error> {
+    let specs = option_specs();
+    let mut normalized = vec![];
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            normalized.push(arg);
+            normalized.extend(args.by_ref());
+            break;
+        }
+        match find_spec(&specs, &arg) {
+            Some(spec) if spec.value.is_some() && !spec.optional_value => {
+                let value = args.next().ok_or(Error::MissingValue(arg.clone()))?;
+                normalized.push(format!("{arg}={value}"));
+            }
+            _ => normalized.push(arg),
+        }
+    }
+
+    Ok(normalized)
+}
+
+pub fn parse_args(args: Vec<String>) -> Result<Command, Error> {
+    let mut args = args.into_iter().peekable();
+    match args.peek().map(|arg| arg.as_str()) {
+        None => Ok(Command::Help),
+        Some("help" | "-h" | "--help") => Ok(Command::Help),
+        Some("version" | "-V" | "--version") => Ok(Command::Version),
+        Some("run") => {
+            args.next();
+            parse_run(args.collect(), false)
+        }
+        Some("state") => {
+            args.next();
+            parse_run(args.collect(), true)
+        }
+        Some("cache") => {
+            args.next();
+            parse_cache(args.collect())
+        }
+        Some("completions") => {
+            args.next();
+            let shell = args.next().ok_or(Error::InvalidOptionsStructure)?;
+            match (Shell::parse(&shell), args.next()) {
+                (Some(shell), None) => Ok(Command::Completions(shell)),
+                _ => Err(Error::InvalidOption(shell)),
+            }
+        }
+        Some("man") => {
+            args.next();
+            match args.next() {
+                None => Ok(Command::Man),
+                Some(arg) => Err(Error::InvalidOption(arg)),
+            }
+        }
+        #[cfg(target_os = "linux")]
+        Some("status") => {
+            args.next();
+            parse_status(args.collect())
+        }
+        #[cfg(target_os = "linux")]
+        Some("next") => {
+            args.next();
+            parse_control(std::iter::once("next".to_string()).chain(args).collect())
+        }
+        #[cfg(target_os = "linux")]
+        Some("rate") => {
+            args.next();
+            parse_control(std::iter::once("rate".to_string()).chain(args).collect())
+        }
+        #[cfg(target_os = "linux")]
+        Some("tag") => {
+            args.next();
+            parse_control(std::iter::once("tag".to_string()).chain(args).collect())
+        }
+        #[cfg(target_os = "linux")]
+        Some("ctl") => {
+            args.next();
+            parse_control(args.collect())
+        }
+        // Without a command, like before there were any
+        Some(arg) if arg.starts_with('-') || std::path::Path::new(arg).is_dir() => {
+            parse_run(args.collect(), false)
+        }
+        Some(arg) => Err(Error::UnknownCommand(arg.to_string())),
+    }
+}
+
+fn parse_run(args: Vec<String>, print_state: bool) -> Result<Command, Error> {
+    let mut options = vec![];
+    let mut path = None;
+    let mut positional = false;
+    for arg in normalize_args(args)? {
+        if arg == "--" && !positional {
+            positional = true;
+        } else if positional || !arg.starts_with('-') {
+            if path.is_some() {
+                return Err(Error::InvalidOptionsStructure);
+            }
+            path = Some(std::path::PathBuf::from(arg));
+        } else if arg == "-h" || arg == "--help" {
+            return Ok(Command::Help);
+        } else {
+            options.push(crate::parse_option(arg)?);
+        }
+    }
+
+    let path = path
+        .filter(|path| path.is_dir())
+        .ok_or(Error::InvalidOptionsStructure)?;
+    options.push(crate::Option::Path(path));
+    if print_state && !options.contains(&crate::Option::PrintState) {
+        options.push(crate::Option::PrintState);
+    }
+
+    Ok(Command::Run(options))
+}
+
+fn parse_cache(args: Vec<String>) -> Result<Command, Error> {
+    let mut command = None;
+    let mut max_size = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (key, value) = split_value(&arg, &mut args, "--max-size")?;
+        match (key.as_str(), command) {
+            ("stats", None) => command = Some(CacheCommand::Stats),
+            ("clear", None) => command = Some(CacheCommand::Clear),
+            ("prune", None) => command = Some(CacheCommand::Prune),
+            ("--max-size", _) => match value.and_then(|value| crate::parse_mib(&value)) {
+                Some(size) => max_size = Some(size),
+                _ => return Err(Error::InvalidOption(arg)),
+            },
+            _ => return Err(Error::InvalidOption(arg)),
+        }
+    }
+
+    command
+        .map(|command| Command::Cache(command, max_size))
+        .ok_or(Error::InvalidOptionsStructure)
+}
+
+#[cfg(target_os = "linux")]
+fn parse_status(args: Vec<String>) -> Result<Command, Error> {
+    let mut format = crate::status::StatusFormat::Plain;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match split_value(&arg, &mut args, "--format")? {
+            (key, Some(value)) if key == "--format" => {
+                format = crate::status::StatusFormat::parse(&value)
+                    .ok_or(Error::InvalidOption(arg.clone()))?;
+            }
+            _ => return Err(Error::InvalidOption(arg)),
+        }
+    }
+
+    Ok(Command::Status(format))
+}
+
+#[cfg(target_os = "linux")]
+fn parse_control(args: Vec<String>) -> Result<Command, Error> {
+    use crate::dbus::Command as Control;
+    let mut args = args.into_iter();
+    let command = args.next().ok_or(Error::InvalidOptionsStructure)?;
+    let control = match command.as_str() {
+        "next" => Control::Next,
+        "previous" => Control::Previous,
+        "pause" => Control::Pause,
+        "resume" => Control::Resume,
+        "set" => {
+            let path = args.next().ok_or(Error::MissingValue(command.clone()))?;
+            // The daemon resolves relative paths against the wallpaper directory
+            let path = std::path::Path::new(&path)
+                .canonicalize()
+                .unwrap_or_else(|_| std::path::PathBuf::from(&path));
+            Control::SetWallpaper(path)
+        }
+        "rate" => {
+            let rating = args.next().ok_or(Error::MissingValue(command.clone()))?;
+            let rating = rating
+                .parse::<i8>()
+                .ok()
+                .filter(|rating| (-1..=5).contains(rating))
+                .ok_or(Error::InvalidOption(rating))?;
+            Control::Rate(wallpaper_arg(args.next()), rating)
+        }
+        "tag" => {
+            let tags = args.next().ok_or(Error::MissingValue(command.clone()))?;
+            // Tags are given like a `--tags` filter, excluded ones are removed
+            let tags = crate::TagFilter::parse(&tags).ok_or(Error::InvalidOption(tags))?;
+            Control::Tag(wallpaper_arg(args.next()), tags.include, tags.exclude)
+        }
+        _ => return Err(Error::UnknownCommand(command)),
+    };
+    match args.next() {
+        None => Ok(Command::Control(control)),
+        Some(arg) => Err(Error::InvalidOption(arg)),
+    }
+}
+
+/// Absolute path of an existing wallpaper for the daemon, empty for the current one
+#[cfg(target_os = "linux")]
+fn wallpaper_arg(wallpaper: Option<String>) -> String {
+    let wallpaper = wallpaper.unwrap_or_default();
+    std::path::Path::new(&wallpaper)
+        .canonicalize()
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or(wallpaper)
+}
+
+/// Splits `--key=value` or takes the value of `--key value` from the following argument
+fn split_value(
+    arg: &str,
+    args: &mut impl Iterator<Item = String>,
+    key: &str,
+) -> Result<(String, Option<String>), Error> {
+    if let Some((arg_key, value)) = arg.split_once('=') {
+        return Ok((arg_key.to_string(), Some(value.to_string())));
+    }
+    if arg == key {
+        let value = args.next().ok_or(Error::MissingValue(arg.to_string()))?;
+        return Ok((arg.to_string(), Some(value)));
+    }
+
+    Ok((arg.to_string(), None))
+}
+
+fn usage(spec: &OptionSpec) -> String {
+    let mut names: Vec<String> = spec.short.iter().map(|short| short.to_string()).collect();
+    names.push(format!("--{}", spec.long));
+    let mut usage = names.join(", ");
+    match (spec.value, spec.optional_value) {
+        (Some(value), false) => usage.push_str(&format!(" <{value}>")),
+        (Some(value), true) => usage.push_str(&format!("[=<{value}>]")),
+        (None, _) => {}
+    }
+
+    usage
+}
+
+fn print_columns(rows: &[(String, &str)]) {
+    for (left, help) in rows {
+        let mut lines = help.lines();
+        if left.len() + 2 < HELP_COLUMN {
+            println!(
+                "  {left:<width$}{}",
+                lines.next().unwrap_or(""),
+                width = HELP_COLUMN - 2
+            );
+        } else {
+            println!("  {left}");
+        }
+        for line in lines {
+            println!("{:HELP_COLUMN$}{line}", "");
+        }
+    }
+}
+
+pub fn print_help() {
+    println!("Usage: {NAME} [run] [OPTIONS] DIRECTORY");
+    println!("       {NAME} <COMMAND> [ARGS]");
+    println!();
+    println!("Commands:");
+    let commands: Vec<(String, &str)> = commands()
+        .into_iter()
+        .map(|(name, args, help)| (format!("{name} {args}").trim_end().to_string(), help))
+        .collect();
+    print_columns(&commands);
+    println!();
+    println!("Options of run and state, given as --key value or --key=value:");
+    let mut options: Vec<(String, &str)> = vec![
+        ("-h, --help".to_string(), "Print this help"),
+        ("-V, --version".to_string(), "Print the version"),
+    ];
+    let specs = option_specs();
+    options.extend(specs.iter().map(|spec| (usage(spec), spec.help)));
+    print_columns(&options);
+}
+
+pub fn version() -> String {
+    format!("{NAME} {}", env!("CARGO_PKG_VERSION"))
+}
+
+pub fn completions(shell: Shell) -> String {
+    match shell {
+        Shell::Bash => bash_completions(),
+        Shell::Fish => fish_completions(),
+        Shell::Zsh => zsh_completions(),
+    }
+}
+
+fn bash_completions() -> String {
+    let specs = option_specs();
+    let commands: Vec<&str> = commands().iter().map(|(name, _, _)| *name).collect();
+    let mut words: Vec<String> = vec!["--help".to_string(), "--version".to_string()];
+    for spec in &specs {
+        words.extend(spec.short.iter().map(|short| short.to_string()));
+        words.push(if spec.optional_value {
+            format!("--{}=", spec.long)
+        } else {
+            format!("--{}", spec.long)
+        });
+    }
+
+    let mut value_cases = String::new();
+    for spec in specs.iter().filter(|spec| spec.value.is_some()) {
+        let reply = match spec.completion {
+            Completion::None => "return".to_string(),
+            Completion::File => "COMPREPLY=($(compgen -f -- \"$cur\")); return".to_string(),
+            Completion::Dir => "COMPREPLY=($(compgen -d -- \"$cur\")); return".to_string(),
+            Completion::Choices(choices) => format!(
+                "COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return",
+                choices.join(" ")
+            ),
+        };
+        value_cases.push_str(&format!("        --{}) {reply} ;;\n", spec.long));
+    }
+
+    format!(
+        r#"# bash completion for {NAME}
+_{NAME}() {{
+    local cur prev
+    cur="${{COMP_WORDS[COMP_CWORD]}}"
+    prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+
+    if [[ $COMP_CWORD -eq 1 ]]; then
+        COMPREPLY=($(compgen -W "{commands} --help --version" -- "$cur") $(compgen -d -- "$cur"))
+        return
+    fi
+    case "${{COMP_WORDS[1]}}" in
+        cache) COMPREPLY=($(compgen -W "stats clear prune --max-size" -- "$cur")); return ;;
+        completions) COMPREPLY=($(compgen -W "bash fish zsh" -- "$cur")); return ;;
+        status) COMPREPLY=($(compgen -W "--format plain waybar i3blocks polybar" -- "$cur")); return ;;
+        ctl) COMPREPLY=($(compgen -W "next previous pause resume set rate tag" -- "$cur")); return ;;
+        next|rate|tag|man) return ;;
+    esac
+    case "$prev" in
+{value_cases}    esac
+    COMPREPLY=($(compgen -W "{words}" -- "$cur") $(compgen -d -- "$cur"))
+    [[ ${{COMPREPLY[0]}} == *= ]] && compopt -o nospace
+}}
+complete -F _{NAME} {NAME}
+"#,
+        commands = commands.join(" "),
+        words = words.join(" "),
+    )
+}
+
+fn fish_completions() -> String {
+    let mut script = format!("# fish completion for {NAME}\ncomplete -c {NAME} -f\n");
+    for (name, _, help) in commands() {
+        script.push_str(&format!(
+            "complete -c {NAME} -n __fish_use_subcommand -a {name} -d {}\n",
+            fish_quote(help)
+        ));
+    }
+    script.push_str(&format!(
+        "complete -c {NAME} -n __fish_use_subcommand -a '(__fish_complete_directories)'\n"
+    ));
+    script.push_str(&format!(
+        "complete -c {NAME} -n '__fish_seen_subcommand_from completions' -a 'bash fish zsh'\n"
+    ));
+    script.push_str(&format!(
+        "complete -c {NAME} -n '__fish_seen_subcommand_from cache' -a 'stats clear prune'\n"
+    ));
+    #[cfg(target_os = "linux")]
+    {
+        script.push_str(&format!(
+            "complete -c {NAME} -n '__fish_seen_subcommand_from ctl' -a 'next previous pause resume set rate tag'\n"
+        ));
+        script.push_str(&format!(
+            "complete -c {NAME} -n '__fish_seen_subcommand_from status' -l format -x -a 'plain waybar i3blocks polybar'\n"
+        ));
+    }
+    script.push_str(&format!(
+        "complete -c {NAME} -s h -l help -d 'Print the help'\n"
+    ));
+    script.push_str(&format!(
+        "complete -c {NAME} -s V -l version -d 'Print the version'\n"
+    ));
+
+    let condition =
+        "'not __fish_seen_subcommand_from cache completions man status next rate tag ctl'";
+    for spec in option_specs() {
+        let mut line = format!("complete -c {NAME} -n {condition} -l {}", spec.long);
+        for short in spec.short.iter().filter(|short| short.len() == 2) {
+            line.push_str(&format!(" -s {}", &short[1..]));
+        }
+        match (spec.value, spec.completion) {
+            (None, _) => {}
+            (Some(_), Completion::File | Completion::Dir) => line.push_str(" -r -F"),
+            (Some(_), Completion::Choices(choices)) => {
+                line.push_str(&format!(" -x -a '{}'", choices.join(" ")))
+            }
+            (Some(_), Completion::None) => line.push_str(" -x"),
+        }
+        line.push_str(&format!(
+            " -d {}\n",
+            fish_quote(spec.help.lines().next().unwrap_or(""))
+        ));
+        script.push_str(&line);
+    }
+
+    script
+}
+
+fn fish_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn zsh_completions() -> String {
+    let commands: Vec<String> = commands()
+        .iter()
+        .map(|(name, _, help)| format!("        '{name}:{}'", zsh_escape(help)))
+        .collect();
+    let mut arguments = vec![
+        "'(- *)'{-h,--help}'[Print the help]'".to_string(),
+        "'(- *)'{-V,--version}'[Print the version]'".to_string(),
+    ];
+    for spec in option_specs() {
+        let help = zsh_escape(spec.help.lines().next().unwrap_or(""));
+        let action = match spec.completion {
+            Completion::None => " ".to_string(),
+            Completion::File => "_files".to_string(),
+            Completion::Dir => "_files -/".to_string(),
+            Completion::Choices(choices) => format!("({})", choices.join(" ")),
+        };
+        let name = match (spec.value, spec.optional_value) {
+            (None, _) => format!("--{}", spec.long),
+            (Some(_), false) => format!("--{}=", spec.long),
+            (Some(_), true) => format!("--{}=-", spec.long),
+        };
+        let value = spec
+            .value
+            .map(|value| format!(":{}:{action}", zsh_escape(value)))
+            .unwrap_or_default();
+        arguments.push(format!("'{name}[{help}]{value}'"));
+        for short in spec.short {
+            arguments.push(format!("'{short}[{help}]'"));
+        }
+    }
+
+    format!(
+        r#"#compdef {NAME}
+
+_{NAME}() {{
+    local -a commands
+    commands=(
+{commands}
+    )
+
+    if (( CURRENT == 2 )); then
+        _describe -t commands command commands
+        _files -/
+        return
+    fi
+    case $words[2] in
+        cache) _values command stats clear prune ;;
+        completions) _values shell bash fish zsh ;;
+        status) _arguments '--format=[Output format]:format:(plain waybar i3blocks polybar)' ;;
+        ctl) _values command next previous pause resume set rate tag ;;
+        next|rate|tag|man) ;;
+        *)
+            _arguments -s \
+                {arguments} \
+                '*:directory:_files -/'
+            ;;
+    esac
+}}
+
+_{NAME} "$@"
+"#,
+        commands = commands.join("\n"),
+        arguments = arguments.join(" \\\n                "),
+    )
+}
+
+fn zsh_escape(s: &str) -> String {
+    s.replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+pub fn man_page() -> String {
+    let mut page = format!(
+        ".TH {} 1 \"\" \"{}\" \"User Commands\"\n",
+        NAME.to_uppercase(),
+        version()
+    );
+    page.push_str(&format!(
+        ".SH NAME\n{NAME} \\- rotates wallpapers from a directory\n"
+    ));
+    page.push_str(&format!(
+        ".SH SYNOPSIS\n.B {NAME}\n[\\fBrun\\fR] [\\fIOPTIONS\\fR] \\fIDIRECTORY\\fR\n.br\n.B {NAME}\n\\fICOMMAND\\fR [\\fIARGS\\fR]\n"
+    ));
+    page.push_str(
+        ".SH DESCRIPTION\nPicks a wallpaper from \\fIDIRECTORY\\fR and its subdirectories every interval, \
+preferring the ones shown least often, and sets it on every output. \
+Subdirectory names and \\fIFILE\\fR.tags sidecar files tag the wallpapers. \
+The state is kept in \\fIDIRECTORY\\fR/state.bin.\n",
+    );
+
+    page.push_str(".SH COMMANDS\n");
+    for (name, args, help) in commands() {
+        page.push_str(&format!(
+            ".TP\n\\fB{name}\\fR {}\n{}\n",
+            roff_escape(args),
+            roff_escape(help)
+        ));
+    }
+
+    page.push_str(".SH OPTIONS\nOptions taking a value are given as \\fB\\-\\-key value\\fR or \\fB\\-\\-key=value\\fR, optional values only in the latter form.\n");
+    page.push_str(".TP\n\\fB\\-h\\fR, \\fB\\-\\-help\\fR\nPrint the help\n");
+    page.push_str(".TP\n\\fB\\-V\\fR, \\fB\\-\\-version\\fR\nPrint the version\n");
+    for spec in option_specs() {
+        let mut names: Vec<String> = spec
+            .short
+            .iter()
+            .map(|short| format!("\\fB{}\\fR", roff_escape(short)))
+            .collect();
+        names.push(format!("\\fB\\-\\-{}\\fR", roff_escape(spec.long)));
+        let value = match (spec.value, spec.optional_value) {
+            (Some(value), false) => format!(" \\fI{}\\fR", roff_escape(value)),
+            (Some(value), true) => format!("[=\\fI{}\\fR]", roff_escape(value)),
+            (None, _) => String::new(),
+        };
+        let help: Vec<String> = spec.help.lines().map(roff_escape).collect();
+        page.push_str(&format!(
+            ".TP\n{}{value}\n{}\n",
+            names.join(", "),
+            help.join("\n")
+        ));
+    }
+
+    page.push_str(
+        ".SH SIGNALS\n.TP\n\\fBSIGTERM\\fR, \\fBSIGINT\\fR\nSave the state and exit\n\
+.TP\n\\fBSIGHUP\\fR\nRescan the wallpaper directory\n\
+.TP\n\\fBSIGUSR1\\fR\nChange the wallpaper now\n\
+.TP\n\\fBSIGUSR2\\fR\nPause or resume the rotation\n",
+    );
+    page.push_str(
+        ".SH ENVIRONMENT\n.TP\n\\fBRUST_LOG\\fR\nLog level, \\fBinfo\\fR when unset, one step more verbose for each \\fB\\-v\\fR and less for each \\fB\\-q\\fR\n\
+.TP\n\\fBXDG_CACHE_HOME\\fR\nWhere rendered variants and the lock image are cached\n",
+    );
+    page.push_str(&format!(
+        ".SH FILES\n.TP\n\\fIDIRECTORY\\fR/state.bin\nHow often every wallpaper was shown, ratings and metadata\n\
+.TP\n\\fI$XDG_CACHE_HOME\\fR/{NAME}\nRendered variants, see the cache command\n"
+    ));
+
+    page
+}
+
+fn roff_escape(s: &str) -> String {
+    let escaped = s.replace('\\', "\\e").replace('-', "\\-");
+    if escaped.starts_with(['.', '\'']) {
+        format!("\\&{escaped}")
+    } else {
+        escaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn normalizes_separate_values() {
+        assert_eq!(
+            normalize_args(args(&["--interval", "1h", "-v", "--sync-lead", "dir"])).unwrap(),
+            args(&["--interval=1h", "-v", "--sync-lead", "dir"])
+        );
+        assert_eq!(
+            normalize_args(args(&["--interval=1h", "--", "--tags", "x"])).unwrap(),
+            args(&["--interval=1h", "--", "--tags", "x"])
+        );
+        assert!(matches!(
+            normalize_args(args(&["dir", "--interval"])),
+            Err(Error::MissingValue(_))
+        ));
+    }
+
+    #[test]
+    fn run_and_state() {
+        let dir = std::env::temp_dir();
+        let dir_arg = dir.to_string_lossy().into_owned();
+        assert_eq!(
+            parse_args(args(&["run", "--interval", "1h", "-v", &dir_arg])).unwrap(),
+            Command::Run(vec![
+                crate::parse_option("--interval=1h".to_string()).unwrap(),
+                crate::Option::Verbosity(1),
+                crate::Option::Path(dir.clone()),
+            ])
+        );
+        // Like before there were commands
+        assert_eq!(
+            parse_args(args(&[&dir_arg])).unwrap(),
+            Command::Run(vec![crate::Option::Path(dir.clone())])
+        );
+        assert_eq!(
+            parse_args(args(&["state", &dir_arg])).unwrap(),
+            Command::Run(vec![
+                crate::Option::Path(dir.clone()),
+                crate::Option::PrintState
+            ])
+        );
+        assert!(matches!(
+            parse_args(args(&["run", &dir_arg, &dir_arg])),
+            Err(Error::InvalidOptionsStructure)
+        ));
+        assert!(matches!(
+            parse_args(args(&["run", "--no-such-option", &dir_arg])),
+            Err(Error::InvalidOption(_))
+        ));
+        assert!(matches!(
+            parse_args(args(&["jump"])),
+            Err(Error::UnknownCommand(_))
+        ));
+        assert_eq!(parse_args(vec![]).unwrap(), Command::Help);
+        assert_eq!(
+            parse_args(args(&["completions", "fish"])).unwrap(),
+            Command::Completions(Shell::Fish)
+        );
+    }
+
+    #[test]
+    fn cache_commands() {
+        assert_eq!(
+            parse_args(args(&["cache", "prune", "--max-size", "2"])).unwrap(),
+            Command::Cache(CacheCommand::Prune, Some(2 * 1024 * 1024))
+        );
+        assert_eq!(
+            parse_args(args(&["cache", "stats"])).unwrap(),
+            Command::Cache(CacheCommand::Stats, None)
+        );
+        assert!(matches!(
+            parse_args(args(&[
+                "cache",
+                "prune",
+                &format!("--max-size={}", u64::MAX)
+            ])),
+            Err(Error::InvalidOption(_))
+        ));
+        assert!(matches!(
+            parse_args(args(&["cache", "stats", "clear"])),
+            Err(Error::InvalidOption(_))
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn control_commands() {
+        use crate::dbus::Command as Control;
+
+        assert_eq!(
+            parse_args(args(&["next"])).unwrap(),
+            Command::Control(Control::Next)
+        );
+        assert_eq!(
+            parse_args(args(&["ctl", "pause"])).unwrap(),
+            Command::Control(Control::Pause)
+        );
+        assert_eq!(
+            parse_args(args(&["rate", "-1"])).unwrap(),
+            Command::Control(Control::Rate(String::new(), -1))
+        );
+        assert_eq!(
+            parse_args(args(&["tag", "sea,-city", "does-not-exist.jpg"])).unwrap(),
+            Command::Control(Control::Tag(
+                "does-not-exist.jpg".to_string(),
+                vec!["sea".to_string()],
+                vec!["city".to_string()]
+            ))
+        );
+        assert!(matches!(
+            parse_args(args(&["rate", "6"])),
+            Err(Error::InvalidOption(_))
+        ));
+        assert!(matches!(
+            parse_args(args(&["rate"])),
+            Err(Error::MissingValue(_))
+        ));
+        assert!(matches!(
+            parse_args(args(&["next", "now"])),
+            Err(Error::InvalidOption(_))
+        ));
+        assert!(matches!(
+            parse_args(args(&["ctl", "jump"])),
+            Err(Error::UnknownCommand(_))
+        ));
+    }
+}
@@ -0,0 +1,307 @@
+use zbus::object_server::SignalEmitter;
+
+pub const BUS_NAME: &str = "org.wallrustler";
+pub const OBJECT_PATH: &str = "/org/wallrustler";
+
+/// Requests from D-Bus clients, carried out by the main loop
+#[derive(Debug, PartialEq, Clone)]
+pub enum Command {
+    Next,
+    Previous,
+    Pause,
+    Resume,
+    /// Absolute path or relative to the wallpaper directory
+    SetWallpaper(std::path::PathBuf),
+    /// Wallpaper file name relative to the wallpaper directory, the current one when empty
+    Rate(String, i8),
+    /// Wallpaper like for `Rate`, tags to add and tags to remove
+    Tag(String, Vec<String>, Vec<String>),
+}
+
+/// What the main loop reports back to D-Bus clients
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Status {
+    pub current_wallpapers: Vec<CurrentWallpaper>,
+    pub paused: bool,
+    pub next_change: Option<std::time::Instant>,
+}
+
+#[derive(
+    Debug, PartialEq, Clone, Default, serde::Serialize, serde::Deserialize, zbus::zvariant::Type,
+)]
+pub struct CurrentWallpaper {
+    pub path: std::path::PathBuf,
+    pub width: u32,
+    pub height: u32,
+    pub rating: i8,
+    pub count: u64,
+}
+
+impl Status {
+    fn paths(&self) -> Vec<String> {
+        self.current_wallpapers
+            .iter()
+            .map(|wallpaper| wallpaper.path.to_string_lossy().into_owned())
+            .collect()
+    }
+}
+
+/// The `org.wallrustler` object on the session bus, served from a zbus thread
+pub struct DbusService {
+    connection: zbus::blocking::Connection,
+    status: std::sync::Arc<std::sync::Mutex<Status>>,
+}
+
+impl DbusService {
+    /// `on_command` is called from the zbus thread with every command, handing it to the main
+    /// loop
+    pub fn new(on_command: impl Fn(Command) + Send + Sync + 'static) -> zbus::Result<DbusService> {
+        let status = std::sync::Arc::new(std::sync::Mutex::new(Status::default()));
+        let daemon = Daemon {
+            on_command: Box::new(on_command),
+            status: status.clone(),
+        };
+        let connection = zbus::blocking::connection::Builder::session()?
+            .name(BUS_NAME)?
+            .serve_at(OBJECT_PATH, daemon)?
+            .build()?;
+
+        Ok(DbusService { connection, status })
+    }
+
+    /// Publishes the status, emitting `Changed` and property changes when it differs
+    pub fn update(&self, status: Status) -> zbus::Result<()> {
+        let previous = std::mem::replace(&mut *self.status.lock().unwrap(), status.clone());
+        let daemon = self
+            .connection
+            .object_server()
+            .interface::<_, Daemon>(OBJECT_PATH)?;
+        let emitter = daemon.signal_emitter();
+
+        if previous.paths() != status.paths() {
+            zbus::block_on(daemon.get().current_wallpaper_changed(emitter))?;
+            zbus::block_on(daemon.get().current_wallpapers_changed(emitter))?;
+            zbus::block_on(Daemon::changed(
+                emitter,
+                &status.paths().first().cloned().unwrap_or_default(),
+            ))?;
+        }
+        if previous.paused != status.paused {
+            zbus::block_on(daemon.get().paused_changed(emitter))?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Daemon {
+    on_command: Box<dyn Fn(Command) + Send + Sync>,
+    status: std::sync::Arc<std::sync::Mutex<Status>>,
+}
+
+impl Daemon {
+    fn send(&self, command: Command) -> zbus::fdo::Result<()> {
+        (self.on_command)(command);
+
+        Ok(())
+    }
+}
+
+#[zbus::interface(name = "org.wallrustler")]
+impl Daemon {
+    fn next(&self) -> zbus::fdo::Result<()> {
+        self.send(Command::Next)
+    }
+
+    fn previous(&self) -> zbus::fdo::Result<()> {
+        self.send(Command::Previous)
+    }
+
+    fn pause(&self) -> zbus::fdo::Result<()> {
+        self.send(Command::Pause)
+    }
+
+    fn resume(&self) -> zbus::fdo::Result<()> {
+        self.send(Command::Resume)
+    }
+
+    fn set_wallpaper(&self, path: &str) -> zbus::fdo::Result<()> {
+        if path.is_empty() {
+            return Err(zbus::fdo::Error::InvalidArgs("Empty path".to_string()));
+        }
+        self.send(Command::SetWallpaper(std::path::PathBuf::from(path)))
+    }
+
+    /// -1 rejects the wallpaper, 0 marks it unrated, 1-5 stars; overrides the rating in the file
+    fn rate(&self, wallpaper: &str, rating: i32) -> zbus::fdo::Result<()> {
+        if !(-1..=5).contains(&rating) {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "Rating {rating} is not between -1 and 5"
+            )));
+        }
+        self.send(Command::Rate(wallpaper.to_string(), rating as i8))
+    }
+
+    /// Stores tags with the wallpaper, on top of the ones from its folders and files
+    fn tag(&self, wallpaper: &str, add: Vec<String>, remove: Vec<String>) -> zbus::fdo::Result<()> {
+        let normalize = |tags: Vec<String>| {
+            tags.iter()
+                .map(|tag| crate::normalize_tag(tag))
+                .collect::<Option<Vec<String>>>()
+                .ok_or_else(|| zbus::fdo::Error::InvalidArgs("Invalid tag".to_string()))
+        };
+        self.send(Command::Tag(
+            wallpaper.to_string(),
+            normalize(add)?,
+            normalize(remove)?,
+        ))
+    }
+
+    /// Current wallpapers with their details, whether rotation is paused and the seconds until
+    /// the next change
+    fn get_status(&self) -> (Vec<CurrentWallpaper>, bool, u64) {
+        let status = self.status.lock().unwrap();
+        let next_change = status.next_change.map_or(0, |next_change| {
+            next_change
+                .saturating_duration_since(std::time::Instant::now())
+                .as_secs()
+        });
+
+        (
+            status.current_wallpapers.clone(),
+            status.paused,
+            next_change,
+        )
+    }
+
+    /// The wallpaper of the first output, empty before the first change
+    #[zbus(property)]
+    fn current_wallpaper(&self) -> String {
+        let paths = self.status.lock().unwrap().paths();
+        paths.first().cloned().unwrap_or_default()
+    }
+
+    #[zbus(property)]
+    fn current_wallpapers(&self) -> Vec<String> {
+        self.status.lock().unwrap().paths()
+    }
+
+    #[zbus(property)]
+    fn paused(&self) -> bool {
+        self.status.lock().unwrap().paused
+    }
+
+    #[zbus(signal)]
+    async fn changed(emitter: &SignalEmitter<'_>, wallpaper: &str) -> zbus::Result<()>;
+}
+
+#[zbus::proxy(
+    interface = "org.wallrustler",
+    default_service = "org.wallrustler",
+    default_path = "/org/wallrustler"
+)]
+pub trait Daemon {
+    fn next(&self) -> zbus::Result<()>;
+    fn previous(&self) -> zbus::Result<()>;
+    fn pause(&self) -> zbus::Result<()>;
+    fn resume(&self) -> zbus::Result<()>;
+    fn set_wallpaper(&self, path: &str) -> zbus::Result<()>;
+    fn rate(&self, wallpaper: &str, rating: i32) -> zbus::Result<()>;
+    fn tag(&self, wallpaper: &str, add: &[String], remove: &[String]) -> zbus::Result<()>;
+    fn get_status(&self) -> zbus::Result<(Vec<CurrentWallpaper>, bool, u64)>;
+}
+
+/// The process ID of the daemon owning `org.wallrustler`, `None` when no daemon is running
+pub fn owner_pid() -> zbus::Result<Option<u32>> {
+    let connection = zbus::blocking::Connection::session()?;
+    let proxy = zbus::blocking::fdo::DBusProxy::new(&connection)?;
+    let name = zbus::names::BusName::try_from(BUS_NAME)?;
+    if !proxy.name_has_owner(name.clone())? {
+        return Ok(None);
+    }
+
+    Ok(Some(proxy.get_connection_unix_process_id(name)?))
+}
+
+/// Sends `command` to the running daemon, as `wallrustler ctl` does
+pub fn send(command: &Command) -> zbus::Result<()> {
+    let connection = zbus::blocking::Connection::session()?;
+    let proxy = DaemonProxyBlocking::new(&connection)?;
+    match command {
+        Command::Next => proxy.next(),
+        Command::Previous => proxy.previous(),
+        Command::Pause => proxy.pause(),
+        Command::Resume => proxy.resume(),
+        Command::SetWallpaper(path) => proxy.set_wallpaper(&path.to_string_lossy()),
+        Command::Rate(wallpaper, rating) => proxy.rate(wallpaper, *rating as i32),
+        Command::Tag(wallpaper, add, remove) => proxy.tag(wallpaper, add, remove),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn daemon() -> (Daemon, std::sync::mpsc::Receiver<Command>) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = std::sync::Mutex::new(tx);
+        let daemon = Daemon {
+            on_command: Box::new(move |command| tx.lock().unwrap().send(command).unwrap()),
+            status: Default::default(),
+        };
+
+        (daemon, rx)
+    }
+
+    #[test]
+    fn commands_are_validated_before_they_are_sent() {
+        let (daemon, rx) = daemon();
+        assert!(daemon.set_wallpaper("").is_err());
+        assert!(daemon.rate("a.jpg", 6).is_err());
+        assert!(daemon.rate("a.jpg", -2).is_err());
+        assert!(daemon
+            .tag("a.jpg", vec!["a,b".to_string()], vec![])
+            .is_err());
+        assert!(daemon.tag("a.jpg", vec![], vec![" ".to_string()]).is_err());
+        assert!(rx.try_recv().is_err());
+
+        daemon.next().unwrap();
+        daemon.rate("a.jpg", -1).unwrap();
+        daemon
+            .tag("a.jpg", vec![" Sea ".to_string()], vec!["City".to_string()])
+            .unwrap();
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![
+                Command::Next,
+                Command::Rate("a.jpg".to_string(), -1),
+                Command::Tag(
+                    "a.jpg".to_string(),
+                    vec!["sea".to_string()],
+                    vec!["city".to_string()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn status_is_reported() {
+        let (daemon, _rx) = daemon();
+        assert_eq!(daemon.current_wallpaper(), "");
+        assert_eq!(daemon.get_status(), (vec![], false, 0));
+
+        let wallpaper = CurrentWallpaper {
+            path: "/wallpapers/a.jpg".into(),
+            ..Default::default()
+        };
+        *daemon.status.lock().unwrap() = Status {
+            current_wallpapers: vec![wallpaper.clone()],
+            paused: true,
+            next_change: Some(std::time::Instant::now() + std::time::Duration::from_secs(90)),
+        };
+        assert_eq!(daemon.current_wallpaper(), "/wallpapers/a.jpg");
+        let (wallpapers, paused, next_change) = daemon.get_status();
+        assert_eq!((wallpapers, paused), (vec![wallpaper], true));
+        assert!((88..=90).contains(&next_change));
+    }
+}
@@ -0,0 +1,229 @@
+use crate::watcher::{Change, DirWatcher};
+
+/// How long the directory has to stay quiet before collected events are reported, so files
+/// still being copied aren't decoded half-written
+const DEBOUNCE: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// Signals the daemon acts on
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Signal {
+    /// SIGTERM or SIGINT, save the state and exit
+    Terminate,
+    /// SIGHUP, rescan the wallpaper directory
+    Reload,
+    /// SIGUSR1, change the wallpaper
+    Next,
+    /// SIGUSR2, pause or resume the rotation
+    TogglePause,
+}
+
+/// What ended an `EventLoop::wait`
+#[derive(Debug, PartialEq, Clone)]
+pub enum Event {
+    Changed(Change),
+    #[cfg(target_os = "linux")]
+    Signal(Signal),
+    #[cfg(target_os = "linux")]
+    Control(crate::dbus::Command),
+}
+
+enum Message {
+    Paths(Vec<std::path::PathBuf>),
+    #[cfg(target_os = "linux")]
+    Signal(Signal),
+    #[cfg(target_os = "linux")]
+    Control(crate::dbus::Command),
+    Wake,
+}
+
+/// Hands events to the loop from other threads
+#[derive(Clone)]
+pub struct EventSender(std::sync::mpsc::Sender<Message>);
+
+impl EventSender {
+    /// Interrupts the wait without an event, for state the loop polls like announcements
+    pub fn wake(&self) {
+        let _ = self.0.send(Message::Wake);
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn control(&self, command: crate::dbus::Command) {
+        let _ = self.0.send(Message::Control(command));
+    }
+}
+
+/// The single place the daemon blocks in, multiplexing the timer, signals, directory changes
+/// and control messages so any of them is handled as soon as it arrives
+pub struct EventLoop {
+    tx: std::sync::mpsc::Sender<Message>,
+    rx: std::sync::mpsc::Receiver<Message>,
+    watcher: Option<DirWatcher>,
+    /// Changed paths waiting for the directory to settle
+    paths: Vec<std::path::PathBuf>,
+}
+
+impl Default for EventLoop {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventLoop {
+    pub fn new() -> EventLoop {
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        EventLoop {
+            tx,
+            rx,
+            watcher: None,
+            paths: vec![],
+        }
+    }
+
+    pub fn sender(&self) -> EventSender {
+        EventSender(self.tx.clone())
+    }
+
+    /// Reports wallpapers added to or removed from `dir`
+    pub fn watch(&mut self, dir: &std::path::Path) -> notify::Result<()> {
+        let tx = self.tx.clone();
+        self.watcher = Some(DirWatcher::new(dir, move |paths| {
+            let _ = tx.send(Message::Paths(paths));
+        })?);
+
+        Ok(())
+    }
+
+    /// Whether directory changes are reported, the collection has to be rescanned otherwise
+    pub fn is_watching(&self) -> bool {
+        self.watcher.is_some()
+    }
+
+    /// Reports the signals of `Signal` instead of letting them terminate the process
+    #[cfg(target_os = "linux")]
+    pub fn handle_signals(&self) -> Result<(), std::io::Error> {
+        use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
+
+        let mut signals =
+            signal_hook::iterator::Signals::new([SIGTERM, SIGINT, SIGHUP, SIGUSR1, SIGUSR2])?;
+        let tx = self.tx.clone();
+        std::thread::spawn(move || {
+            for signal in signals.forever() {
+                let signal = match signal {
+                    SIGHUP => Signal::Reload,
+                    SIGUSR1 => Signal::Next,
+                    SIGUSR2 => Signal::TogglePause,
+                    _ => Signal::Terminate,
+                };
+                if tx.send(Message::Signal(signal)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Blocks until `deadline` passes, an event arrives or it is woken up. Signals and control
+    /// messages end the wait right away, together with the directory changes collected so far.
+    /// Paths still settling at the deadline are reported by a later wait
+    pub fn wait(&mut self, deadline: std::time::Instant) -> Vec<Event> {
+        let mut events = vec![];
+        loop {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            let settling = !self.paths.is_empty() && remaining >= DEBOUNCE;
+            let timeout = if settling { DEBOUNCE } else { remaining };
+            match self.rx.recv_timeout(timeout) {
+                Ok(Message::Paths(paths)) => {
+                    self.paths.extend(paths);
+                    if std::time::Instant::now() >= deadline {
+                        return events;
+                    }
+                }
+                #[cfg(target_os = "linux")]
+                Ok(Message::Signal(signal)) => {
+                    events.push(Event::Signal(signal));
+                    break;
+                }
+                #[cfg(target_os = "linux")]
+                Ok(Message::Control(command)) => {
+                    events.push(Event::Control(command));
+                    break;
+                }
+                Ok(Message::Wake) => break,
+                Err(_) => {
+                    // The loop holds a sender, so the channel only ever times out
+                    if !settling {
+                        return events;
+                    }
+                    let changes = self.get_changes();
+                    if !changes.is_empty() || std::time::Instant::now() >= deadline {
+                        events.extend(changes.into_iter().map(Event::Changed));
+                        return events;
+                    }
+                }
+            }
+        }
+
+        // Whatever else arrived meanwhile is handled in the same iteration
+        for message in self.rx.try_iter() {
+            match message {
+                Message::Paths(paths) => self.paths.extend(paths),
+                #[cfg(target_os = "linux")]
+                Message::Signal(signal) => events.push(Event::Signal(signal)),
+                #[cfg(target_os = "linux")]
+                Message::Control(command) => events.push(Event::Control(command)),
+                Message::Wake => {}
+            }
+        }
+        let changes = self.get_changes();
+        events.extend(changes.into_iter().map(Event::Changed));
+
+        events
+    }
+
+    fn get_changes(&mut self) -> Vec<Change> {
+        match &self.watcher {
+            Some(watcher) => watcher.get_changes(&mut self.paths),
+            None => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadline_ends_the_wait_while_paths_settle() {
+        let mut event_loop = EventLoop::new();
+        let sender = event_loop.tx.clone();
+        // Keeps the directory from settling for longer than the wait
+        let busy = std::thread::spawn(move || {
+            for _ in 0..20 {
+                let _ = sender.send(Message::Paths(vec!["/wallpapers/a.jpg".into()]));
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            }
+        });
+
+        let start = std::time::Instant::now();
+        let events = event_loop.wait(start + std::time::Duration::from_millis(200));
+        let elapsed = start.elapsed();
+        assert!(events.is_empty());
+        assert!(elapsed >= std::time::Duration::from_millis(200));
+        assert!(elapsed < DEBOUNCE);
+        assert!(!event_loop.paths.is_empty());
+        busy.join().unwrap();
+    }
+
+    #[test]
+    fn wake_ends_the_wait() {
+        let mut event_loop = EventLoop::new();
+        event_loop.sender().wake();
+        let start = std::time::Instant::now();
+        assert!(event_loop
+            .wait(start + std::time::Duration::from_secs(10))
+            .is_empty());
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,255 @@
+use crate::rotator::{ChangeContext, Extension, Pick};
+use crate::Output;
+
+/// How often a running hook is checked for completion
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+/// All hooks of a change together may run this many times `Hooks::timeout`, vetoes and
+/// multiple outputs would stall the change for much longer otherwise
+const CHANGE_TIMEOUTS: u32 = 3;
+
+/// User commands run through the shell around every wallpaper change, with `HookContext` passed
+/// as environment variables
+#[derive(Debug, PartialEq, Clone)]
+pub struct Hooks {
+    /// A non-zero exit status vetoes the picked wallpaper
+    pub pre_change: Option<String>,
+    pub post_change: Option<String>,
+    /// Hooks still running afterwards are killed together with their children, a pre-change
+    /// hook then accepts the pick
+    pub timeout: std::time::Duration,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Hooks {
+            pre_change: None,
+            post_change: None,
+            timeout: std::time::Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct HookContext<'a> {
+    pub wallpaper: &'a std::path::Path,
+    pub previous: Option<&'a std::path::Path>,
+    /// `None` when the same wallpaper is set on all outputs
+    pub output: Option<&'a Output>,
+    pub backend: &'a str,
+    /// Of the wallpaper, zero when unknown
+    pub width: u32,
+    pub height: u32,
+    /// `colors.json` of the wallpaper when the theme is enabled, post-change only
+    pub colors: Option<&'a std::path::Path>,
+}
+
+impl HookContext<'_> {
+    fn env(&self) -> Vec<(&'static str, std::ffi::OsString)> {
+        let mut env = vec![
+            ("WALLPAPER", self.wallpaper.as_os_str().to_owned()),
+            ("WALLRUSTLER_BACKEND", self.backend.into()),
+            ("WALLRUSTLER_WIDTH", self.width.to_string().into()),
+            ("WALLRUSTLER_HEIGHT", self.height.to_string().into()),
+        ];
+        if let Some(previous) = self.previous {
+            env.push(("WALLRUSTLER_PREVIOUS", previous.as_os_str().to_owned()));
+        }
+        if let Some(output) = self.output {
+            env.push(("WALLRUSTLER_OUTPUT", output.name.clone().into()));
+            env.push(("WALLRUSTLER_OUTPUT_WIDTH", output.width.to_string().into()));
+            env.push((
+                "WALLRUSTLER_OUTPUT_HEIGHT",
+                output.height.to_string().into(),
+            ));
+        }
+        if let Some(colors) = self.colors {
+            env.push(("WALLRUSTLER_COLORS", colors.as_os_str().to_owned()));
+        }
+
+        env
+    }
+}
+
+impl<'a> From<&Pick<'a>> for HookContext<'a> {
+    fn from(pick: &Pick<'a>) -> HookContext<'a> {
+        let metadata = pick.entry.map(|wallpaper| &wallpaper.metadata);
+        HookContext {
+            wallpaper: pick.wallpaper,
+            previous: pick.previous,
+            output: pick.output,
+            backend: pick.backend,
+            width: metadata.map_or(0, |metadata| metadata.width),
+            height: metadata.map_or(0, |metadata| metadata.height),
+            colors: None,
+        }
+    }
+}
+
+impl Hooks {
+    /// Until when the hooks of a change that started at `started` may run, later hooks are
+    /// skipped
+    pub fn change_deadline(&self, started: std::time::Instant) -> std::time::Instant {
+        started + self.timeout * CHANGE_TIMEOUTS
+    }
+
+    /// Whether the wallpaper may be set, a hook that can't be run or times out doesn't veto
+    pub fn pre_change(&self, context: &HookContext, deadline: std::time::Instant) -> bool {
+        let Some(command) = &self.pre_change else {
+            return true;
+        };
+        let Some(timeout) = self.remaining(deadline) else {
+            warn!("Skipping the pre-change hook, the change already spent too long in hooks");
+            return true;
+        };
+
+        match run(command, &context.env(), timeout) {
+            Ok(Some(status)) if !status.success() => {
+                info!("Pre-change hook vetoed {:?} ({status})", context.wallpaper);
+                false
+            }
+            Ok(Some(_)) => true,
+            Ok(None) => {
+                warn!("Pre-change hook timed out after {timeout:?}");
+                true
+            }
+            Err(err) => {
+                error!("Unable to run the pre-change hook: {err}");
+                true
+            }
+        }
+    }
+
+    pub fn post_change(&self, context: &HookContext, deadline: std::time::Instant) {
+        let Some(command) = &self.post_change else {
+            return;
+        };
+        let Some(timeout) = self.remaining(deadline) else {
+            warn!("Skipping the post-change hook, the change already spent too long in hooks");
+            return;
+        };
+
+        match run(command, &context.env(), timeout) {
+            Ok(Some(status)) if !status.success() => {
+                warn!("Post-change hook failed with {status}")
+            }
+            Ok(Some(_)) => {}
+            Ok(None) => warn!("Post-change hook timed out after {timeout:?}"),
+            Err(err) => error!("Unable to run the post-change hook: {err}"),
+        }
+    }
+
+    /// Timeout of the next hook, `None` once `deadline` passed
+    fn remaining(&self, deadline: std::time::Instant) -> Option<std::time::Duration> {
+        let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+        (!remaining.is_zero()).then(|| remaining.min(self.timeout))
+    }
+}
+
+impl Extension for Hooks {
+    fn accept(&mut self, change: &ChangeContext, pick: &Pick) -> bool {
+        self.pre_change(&pick.into(), self.change_deadline(change.started))
+    }
+
+    fn changed(&mut self, change: &mut ChangeContext, picks: &[Pick]) {
+        let deadline = self.change_deadline(change.started);
+        for pick in picks {
+            let context = HookContext {
+                colors: change.colors.as_deref(),
+                ..pick.into()
+            };
+            self.post_change(&context, deadline);
+        }
+    }
+}
+
+/// Runs `command` with its output going to ours, `None` when it was killed after `timeout`
+fn run(
+    command: &str,
+    env: &[(&str, std::ffi::OsString)],
+    timeout: std::time::Duration,
+) -> Result<Option<std::process::ExitStatus>, std::io::Error> {
+    #[cfg(target_os = "windows")]
+    let mut shell = {
+        let mut shell = std::process::Command::new("cmd");
+        shell.arg("/C");
+        shell
+    };
+    #[cfg(not(target_os = "windows"))]
+    let mut shell = {
+        use std::os::unix::process::CommandExt;
+
+        let mut shell = std::process::Command::new("sh");
+        // A separate process group, so whatever the hook started is killed with it
+        shell.arg("-c").process_group(0);
+        shell
+    };
+    debug!("Running hook {command:?}");
+    let mut child = shell
+        .arg(command)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .spawn()?;
+
+    let deadline = std::time::Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if std::time::Instant::now() >= deadline {
+            // The unreaped shell keeps the group alive, so this reaches its children as well
+            #[cfg(not(target_os = "windows"))]
+            crate::run_command(
+                std::process::Command::new("kill")
+                    .arg("-KILL")
+                    .arg("--")
+                    .arg(format!("-{}", child.id())),
+            )?;
+            #[cfg(target_os = "windows")]
+            child.kill()?;
+            child.wait()?;
+            return Ok(None);
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remaining_is_bounded_by_timeout_and_deadline() {
+        let hooks = Hooks::default();
+        let now = std::time::Instant::now();
+        assert_eq!(hooks.remaining(now), None);
+        assert_eq!(
+            hooks.remaining(now + hooks.timeout * 2),
+            Some(hooks.timeout)
+        );
+        let remaining = hooks.remaining(now + hooks.timeout / 2).unwrap();
+        assert!(remaining <= hooks.timeout / 2);
+    }
+
+    #[test]
+    fn passes_the_exit_status() {
+        let status = run("exit 3", &[], std::time::Duration::from_secs(5)).unwrap();
+        assert_eq!(status.and_then(|status| status.code()), Some(3));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn timeout_kills_the_children_of_the_hook() {
+        let pid_file =
+            std::env::temp_dir().join(format!("wallrustler-hook-{}", std::process::id()));
+        let command = format!("sleep 30 & echo $! > {pid_file:?}; wait");
+        let start = std::time::Instant::now();
+        let status = run(&command, &[], std::time::Duration::from_millis(300)).unwrap();
+        assert_eq!(status, None);
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let _ = std::fs::remove_file(&pid_file);
+        // Killed children linger as zombies until their new parent reaps them
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim()));
+        assert!(stat.is_err() || stat.unwrap().contains(") Z "));
+    }
+}
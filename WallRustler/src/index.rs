@@ -0,0 +1,531 @@
+use crate::Wallpaper;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+
+/// The offset counts are weighted from is reconsidered whenever the highest count grew by
+/// this much, weights only matter relative to each other so this merely keeps them far from
+/// underflowing
+const RECENTER_COUNT: usize = 10_000;
+
+/// Fenwick tree over selection weights, sampling and updating an entry are O(log n)
+#[derive(Debug, Default)]
+pub struct WeightTree {
+    tree: Vec<f64>,
+    weights: Vec<f64>,
+}
+
+impl WeightTree {
+    pub fn new(weights: Vec<f64>) -> WeightTree {
+        let mut tree = weights.clone();
+        for i in 0..tree.len() {
+            let parent = i | (i + 1);
+            if parent < tree.len() {
+                tree[parent] += tree[i];
+            }
+        }
+
+        WeightTree { tree, weights }
+    }
+
+    pub fn len(&self) -> usize {
+        self.weights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.weights.is_empty()
+    }
+
+    pub fn weight(&self, index: usize) -> f64 {
+        self.weights[index]
+    }
+
+    pub fn total(&self) -> f64 {
+        self.prefix_sum(self.len())
+    }
+
+    pub fn update(&mut self, index: usize, weight: f64) {
+        let delta = weight - self.weights[index];
+        self.weights[index] = weight;
+        let mut i = index;
+        while i < self.tree.len() {
+            self.tree[i] += delta;
+            i |= i + 1;
+        }
+    }
+
+    pub fn push(&mut self, weight: f64) {
+        let index = self.len();
+        // The new node covers the entries (index & (index + 1))..=index
+        let node = weight + self.prefix_sum(index) - self.prefix_sum(index & (index + 1));
+        self.tree.push(node);
+        self.weights.push(weight);
+    }
+
+    /// Removes the last entry, no other node covers it
+    pub fn pop(&mut self) {
+        self.tree.pop();
+        self.weights.pop();
+    }
+
+    /// Index of the entry whose cumulative weight range contains `target`, entries with zero
+    /// weight are never returned unless all are zero, which rounding can hide from `total`
+    pub fn find(&self, mut target: f64) -> usize {
+        let mut index = 0;
+        let mut step = self.len().next_power_of_two();
+        while step > 0 {
+            let next = index + step;
+            if next <= self.len() && self.tree[next - 1] <= target {
+                index = next;
+                target -= self.tree[next - 1];
+            }
+            step /= 2;
+        }
+
+        // Rounding can push the target past the last non zero entry
+        let mut index = index.min(self.len().saturating_sub(1));
+        while index > 0 && self.weights[index] <= 0.0 {
+            index -= 1;
+        }
+        index
+    }
+
+    /// Sum of the first `len` weights
+    fn prefix_sum(&self, len: usize) -> f64 {
+        let mut sum = 0.0;
+        let mut i = len;
+        while i > 0 {
+            sum += self.tree[i - 1];
+            i &= i - 1;
+        }
+        sum
+    }
+}
+
+struct Pool {
+    filter: Box<dyn Fn(&Wallpaper) -> bool>,
+    tree: WeightTree,
+}
+
+impl Pool {
+    fn weight(&self, wallpaper: &Wallpaper, count_offset: usize) -> f64 {
+        if (self.filter)(wallpaper) {
+            wallpaper.weight(count_offset)
+        } else {
+            0.0
+        }
+    }
+}
+
+/// The wallpaper collection with name lookups and per pool weight trees, updated per added
+/// or removed file so neither syncing nor picking has to walk the whole collection more than once
+pub struct WallpaperIndex {
+    wallpapers: Vec<Wallpaper>,
+    positions: HashMap<String, usize>,
+    pools: HashMap<String, Pool>,
+    /// Picks are drawn from it, seeded from entropy unless replaced
+    rng: Box<dyn rand::RngCore>,
+    /// Subtracted from the counts when weighting, see `recenter_counts`
+    count_offset: usize,
+    /// Count at which the offset is reconsidered
+    recenter_at: usize,
+}
+
+impl Default for WallpaperIndex {
+    fn default() -> WallpaperIndex {
+        WallpaperIndex::new(vec![])
+    }
+}
+
+impl WallpaperIndex {
+    pub fn new(wallpapers: Vec<Wallpaper>) -> WallpaperIndex {
+        let positions = wallpapers
+            .iter()
+            .enumerate()
+            .map(|(i, wallpaper)| (wallpaper.file_name.clone(), i))
+            .collect();
+
+        WallpaperIndex {
+            wallpapers,
+            positions,
+            pools: HashMap::new(),
+            rng: Box::new(rand_hc::Hc128Rng::from_entropy()),
+            count_offset: 0,
+            recenter_at: RECENTER_COUNT,
+        }
+    }
+
+    /// Replaces the random number generator picks are drawn from, a seeded one makes them
+    /// reproducible
+    pub fn set_rng(&mut self, rng: impl rand::RngCore + 'static) {
+        self.rng = Box::new(rng);
+    }
+
+    /// The generator picks are drawn from, for anything else a seed should reproduce
+    pub fn rng(&mut self) -> &mut dyn rand::RngCore {
+        self.rng.as_mut()
+    }
+
+    pub fn wallpapers(&self) -> &[Wallpaper] {
+        &self.wallpapers
+    }
+
+    pub fn into_wallpapers(self) -> Vec<Wallpaper> {
+        self.wallpapers
+    }
+
+    pub fn get(&self, file_name: &str) -> Option<&Wallpaper> {
+        self.positions
+            .get(file_name)
+            .map(|&index| &self.wallpapers[index])
+    }
+
+    /// Looks up a wallpaper by its full path below `wallpaper_dir_path`
+    pub fn get_by_path(
+        &self,
+        wallpaper_dir_path: &std::path::Path,
+        path: &std::path::Path,
+    ) -> Option<&Wallpaper> {
+        let file_name = path.strip_prefix(wallpaper_dir_path).ok()?.to_str()?;
+        self.get(file_name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.wallpapers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.wallpapers.is_empty()
+    }
+
+    /// Rescans `wallpaper_dir_path`, adding new and dropping missing wallpapers
+    pub fn sync(&mut self, wallpaper_dir_path: &std::path::Path) {
+        let wallpapers_names = crate::get_wallpapers_from_path(wallpaper_dir_path);
+        self.sync_names(wallpaper_dir_path, wallpapers_names);
+    }
+
+    /// Like `sync` with an already scanned list of file names
+    pub fn sync_names(
+        &mut self,
+        wallpaper_dir_path: &std::path::Path,
+        wallpapers_names: Vec<String>,
+    ) {
+        let names: HashSet<&String> = wallpapers_names.iter().collect();
+        self.wallpapers.retain(|wallpaper| {
+            let keep = names.contains(&wallpaper.file_name);
+            if !keep {
+                debug!("Popping {}", wallpaper.file_name);
+            }
+            keep
+        });
+        for file_name in wallpapers_names.iter() {
+            if !self.positions.contains_key(file_name) {
+                debug!("Pushing {}", file_name);
+                self.wallpapers.push(Wallpaper::new(file_name.clone()));
+            }
+        }
+
+        for wallpaper in self.wallpapers.iter_mut() {
+            crate::refresh_wallpaper(wallpaper_dir_path, wallpaper);
+        }
+        self.positions = self
+            .wallpapers
+            .iter()
+            .enumerate()
+            .map(|(i, wallpaper)| (wallpaper.file_name.clone(), i))
+            .collect();
+        self.rebuild_pools();
+    }
+
+    /// Adds a single wallpaper or refreshes it when already known, returns whether it is new
+    pub fn add(&mut self, wallpaper_dir_path: &std::path::Path, file_name: &str) -> bool {
+        if let Some(&index) = self.positions.get(file_name) {
+            crate::refresh_wallpaper(wallpaper_dir_path, &mut self.wallpapers[index]);
+            self.update_pools(index);
+            return false;
+        }
+
+        debug!("Pushing {}", file_name);
+        let mut wallpaper = Wallpaper::new(file_name.to_string());
+        crate::refresh_wallpaper(wallpaper_dir_path, &mut wallpaper);
+        for pool in self.pools.values_mut() {
+            let weight = pool.weight(&wallpaper, self.count_offset);
+            pool.tree.push(weight);
+        }
+        self.positions
+            .insert(file_name.to_string(), self.wallpapers.len());
+        self.wallpapers.push(wallpaper);
+
+        true
+    }
+
+    /// Removes the wallpaper, or every wallpaper below it when `file_name` was a directory
+    pub fn remove(&mut self, file_name: &str) {
+        let removed: Vec<String> = if self.positions.contains_key(file_name) {
+            vec![file_name.to_string()]
+        } else {
+            self.wallpapers
+                .iter()
+                .filter(|wallpaper| {
+                    std::path::Path::new(&wallpaper.file_name).starts_with(file_name)
+                })
+                .map(|wallpaper| wallpaper.file_name.clone())
+                .collect()
+        };
+
+        for file_name in removed {
+            let Some(index) = self.positions.remove(&file_name) else {
+                continue;
+            };
+            debug!("Popping {}", file_name);
+            let last = self.wallpapers.len() - 1;
+            for pool in self.pools.values_mut() {
+                let weight = pool.tree.weight(last);
+                pool.tree.update(index, weight);
+                pool.tree.pop();
+            }
+            self.wallpapers.swap_remove(index);
+            if index != last {
+                self.positions
+                    .insert(self.wallpapers[index].file_name.clone(), index);
+            }
+        }
+    }
+
+    /// Registers the pool of wallpapers selected by `filter` unless `pool` already exists, pools
+    /// are kept up to date afterwards so `filter` has to stay the same for a given key
+    pub fn add_pool(&mut self, pool: &str, filter: impl Fn(&Wallpaper) -> bool + 'static) {
+        if self.pools.contains_key(pool) {
+            return;
+        }
+
+        let mut new_pool = Pool {
+            filter: Box::new(filter),
+            tree: WeightTree::default(),
+        };
+        new_pool.tree = WeightTree::new(
+            self.wallpapers
+                .iter()
+                .map(|wallpaper| new_pool.weight(wallpaper, self.count_offset))
+                .collect(),
+        );
+        self.pools.insert(pool.to_string(), new_pool);
+    }
+
+    /// Picks a random wallpaper out of the pool, weighted by the view count and rating
+    pub fn pick(
+        &mut self,
+        wallpaper_dir_path: &std::path::Path,
+        pool: &str,
+    ) -> Option<std::path::PathBuf> {
+        let tree = &self.pools.get(pool)?.tree;
+        let total = tree.total();
+        if tree.is_empty() || total <= 0.0 {
+            return None;
+        }
+        let index = tree.find(self.rng.gen_range(0.0..total));
+        // Rounding left a positive total over weights that are all zero
+        if tree.weight(index) <= 0.0 {
+            return None;
+        }
+
+        Some(self.mark_shown(wallpaper_dir_path, index))
+    }
+
+    /// Takes the oldest queued new wallpaper that is part of the pool
+    pub fn take_new(
+        &mut self,
+        wallpaper_dir_path: &std::path::Path,
+        pool: &str,
+        new_wallpapers: &mut Vec<String>,
+    ) -> Option<std::path::PathBuf> {
+        let pool = self.pools.get(pool)?;
+        let position = new_wallpapers.iter().position(|file_name| {
+            self.positions
+                .get(file_name)
+                .is_some_and(|&index| pool.tree.weight(index) > 0.0)
+        })?;
+        let index = self.positions[&new_wallpapers.remove(position)];
+
+        Some(self.mark_shown(wallpaper_dir_path, index))
+    }
+
+    /// Overrides the rating of a known wallpaper, returns whether it was found
+    pub fn set_rating(&mut self, file_name: &str, rating: i8) -> bool {
+        let Some(&index) = self.positions.get(file_name) else {
+            return false;
+        };
+        self.wallpapers[index].rating = Some(rating);
+        self.update_pools(index);
+
+        true
+    }
+
+    /// Adds and removes tags stored in the state of a known wallpaper, returns whether it was
+    /// found
+    pub fn set_tags(&mut self, file_name: &str, add: &[String], remove: &[String]) -> bool {
+        let Some(&index) = self.positions.get(file_name) else {
+            return false;
+        };
+        let tags = &mut self.wallpapers[index].tags;
+        tags.retain(|tag| !remove.contains(tag));
+        tags.extend(add.iter().cloned());
+        tags.sort();
+        tags.dedup();
+        self.update_pools(index);
+
+        true
+    }
+
+    /// Moves the offset the weights are computed from to the lowest count, selection is
+    /// relative so only the pools are rebuilt and the counts stay as they are. Reconsidered
+    /// once a count grew by another `RECENTER_COUNT`, so it costs nothing per change
+    fn recenter_counts(&mut self) {
+        let min = self.wallpapers.iter().map(|w| w.count).min().unwrap_or(0);
+        let max = self.wallpapers.iter().map(|w| w.count).max().unwrap_or(0);
+        self.recenter_at = max + RECENTER_COUNT;
+        if min != self.count_offset {
+            self.count_offset = min;
+            self.rebuild_pools();
+        }
+    }
+
+    fn mark_shown(
+        &mut self,
+        wallpaper_dir_path: &std::path::Path,
+        index: usize,
+    ) -> std::path::PathBuf {
+        self.wallpapers[index].count += 1;
+        self.update_pools(index);
+        let path = wallpaper_dir_path.join(&self.wallpapers[index].file_name);
+        if self.wallpapers[index].count >= self.recenter_at {
+            self.recenter_counts();
+        }
+
+        path
+    }
+
+    fn update_pools(&mut self, index: usize) {
+        let wallpaper = &self.wallpapers[index];
+        for pool in self.pools.values_mut() {
+            let weight = pool.weight(wallpaper, self.count_offset);
+            pool.tree.update(index, weight);
+        }
+    }
+
+    fn rebuild_pools(&mut self) {
+        for pool in self.pools.values_mut() {
+            let weights = self
+                .wallpapers
+                .iter()
+                .map(|wallpaper| pool.weight(wallpaper, self.count_offset))
+                .collect();
+            pool.tree = WeightTree::new(weights);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naive_prefix_sums(weights: &[f64]) -> Vec<f64> {
+        weights
+            .iter()
+            .scan(0.0, |sum, weight| {
+                *sum += weight;
+                Some(*sum)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn weight_tree_matches_naive_sums() {
+        let mut weights: Vec<f64> = (0..37).map(|i| (i % 5) as f64 * 0.5).collect();
+        let mut tree = WeightTree::new(weights.clone());
+        tree.update(3, 7.0);
+        weights[3] = 7.0;
+        tree.push(2.5);
+        weights.push(2.5);
+        tree.pop();
+        weights.pop();
+        tree.push(1.0);
+        weights.push(1.0);
+
+        let sums = naive_prefix_sums(&weights);
+        for (len, sum) in sums.iter().enumerate() {
+            assert!((tree.prefix_sum(len + 1) - sum).abs() < 1e-9);
+        }
+        assert!((tree.total() - sums.last().unwrap()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn weight_tree_finds_the_range_and_skips_zero_weights() {
+        let tree = WeightTree::new(vec![1.0, 0.0, 2.0, 0.0]);
+        assert_eq!(tree.find(0.0), 0);
+        assert_eq!(tree.find(0.999), 0);
+        assert_eq!(tree.find(1.0), 2);
+        assert_eq!(tree.find(2.999), 2);
+        // Past the total, e.g. by rounding, the last non zero entry is returned
+        assert_eq!(tree.find(3.5), 2);
+    }
+
+    fn index(count: usize) -> WallpaperIndex {
+        let wallpapers = (0..count)
+            .map(|i| Wallpaper::new(format!("{i}.jpg")))
+            .collect();
+        let mut index = WallpaperIndex::new(wallpapers);
+        index.add_pool("", |_| true);
+        index
+    }
+
+    #[test]
+    fn zero_weight_wallpapers_are_never_picked() {
+        let dir = std::path::Path::new("/wallpapers");
+        let mut index = index(10);
+        for i in 0..500 {
+            index.pick(dir, "");
+            // Leaves residues of the float updates in the tree nodes
+            index.set_rating(&format!("{}.jpg", i % 10), (i % 6) as i8);
+        }
+        for i in 0..10 {
+            index.set_rating(&format!("{i}.jpg"), -1);
+        }
+
+        assert_eq!(index.pick(dir, ""), None);
+    }
+
+    #[test]
+    fn same_seed_same_picks() {
+        let dir = std::path::Path::new("/wallpapers");
+        let picks = |seed| {
+            let mut index = index(50);
+            let mut seeded_rng = crate::rng::SeededRng::new(seed);
+            (0..20)
+                .map(|_| {
+                    index.set_rng(seeded_rng.next_rng());
+                    index.pick(dir, "").unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let picks_1 = picks(1);
+        assert_eq!(picks_1, picks(1));
+        assert_ne!(picks_1, picks(2));
+    }
+
+    #[test]
+    fn counts_are_recentered() {
+        let dir = std::path::Path::new("/wallpapers");
+        let mut index = index(2);
+        index.wallpapers[0].count = RECENTER_COUNT - 1;
+        index.wallpapers[1].count = RECENTER_COUNT - 1;
+        index.rebuild_pools();
+
+        index.pick(dir, "");
+        let mut counts: Vec<usize> = index.wallpapers().iter().map(|w| w.count).collect();
+        counts.sort();
+        assert_eq!(counts, vec![RECENTER_COUNT - 1, RECENTER_COUNT]);
+        assert_eq!(index.count_offset, RECENTER_COUNT - 1);
+        assert!((index.pools[""].tree.total() - (1.0 + 1.001f64.powi(-1))).abs() < 1e-9);
+    }
+}
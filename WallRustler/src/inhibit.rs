@@ -0,0 +1,156 @@
+/// Conditions under which rotation is suspended, checked before every change
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Inhibitor {
+    /// Pause while discharging below this charge in percent
+    pub battery_threshold: Option<u8>,
+    pub fullscreen: bool,
+    /// Pause while the session is idle or locked
+    pub idle: bool,
+}
+
+impl Inhibitor {
+    pub fn is_enabled(&self) -> bool {
+        self.battery_threshold.is_some() || self.fullscreen || self.idle
+    }
+
+    /// Why rotation should be paused right now, `None` when it can go on
+    pub fn reason(&self) -> Option<&'static str> {
+        if let Some(threshold) = self.battery_threshold {
+            if crate::power::on_battery()
+                && crate::power::battery_capacity().is_some_and(|capacity| capacity < threshold)
+            {
+                return Some("battery is low");
+            }
+        }
+        if self.fullscreen && is_fullscreen_active() {
+            return Some("a fullscreen window is active");
+        }
+        if self.idle && is_session_idle() {
+            return Some("the session is idle or locked");
+        }
+
+        None
+    }
+}
+
+/// Checks the focused window through hyprctl on Hyprland and xprop on X11, other compositors
+/// don't expose it and are never considered fullscreen
+fn is_fullscreen_active() -> bool {
+    if std::env::var("HYPRLAND_INSTANCE_SIGNATURE").is_ok() {
+        return run("hyprctl", &["activewindow"])
+            .is_some_and(|active_window| is_hyprland_fullscreen(&active_window));
+    }
+    if std::env::var("DISPLAY").is_err() {
+        return false;
+    }
+
+    let Some(window) = run("xprop", &["-root", "_NET_ACTIVE_WINDOW"])
+        .and_then(|root| parse_active_window(&root).map(|s| s.to_string()))
+    else {
+        return false;
+    };
+    run("xprop", &["-id", &window, "_NET_WM_STATE"])
+        .is_some_and(|state| state.contains("_NET_WM_STATE_FULLSCREEN"))
+}
+
+/// `fullscreen: 0` when windowed, the fullscreen mode otherwise; older versions print booleans
+fn is_hyprland_fullscreen(active_window: &str) -> bool {
+    active_window.lines().any(|line| {
+        line.trim()
+            .strip_prefix("fullscreen:")
+            .is_some_and(|state| !matches!(state.trim(), "0" | "false"))
+    })
+}
+
+/// The window ID in `_NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007`, `None` when no
+/// window has the focus
+fn parse_active_window(root: &str) -> Option<&str> {
+    let (_, window) = root.split_once('#')?;
+    let window = window.split_whitespace().next()?;
+
+    (window != "0x0").then_some(window)
+}
+
+/// Asks logind for the idle and lock hints the desktop sets on the session
+fn is_session_idle() -> bool {
+    let session = std::env::var("XDG_SESSION_ID").unwrap_or_else(|_| "self".to_string());
+    let Some(hints) = run(
+        "loginctl",
+        &[
+            "show-session",
+            &session,
+            "-p",
+            "IdleHint",
+            "-p",
+            "LockedHint",
+        ],
+    ) else {
+        return false;
+    };
+
+    is_idle(&hints)
+}
+
+/// `IdleHint=yes` or `LockedHint=yes` in the `loginctl show-session` properties
+fn is_idle(hints: &str) -> bool {
+    hints
+        .lines()
+        .map(str::trim)
+        .any(|line| line == "IdleHint=yes" || line == "LockedHint=yes")
+}
+
+fn run(program: &str, args: &[&str]) -> Option<String> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hyprland_fullscreen() {
+        let active_window = |fullscreen: &str| {
+            format!(
+                "Window 55d3c1e0 -> ~:\n\tmapped: 1\n\tfloating: 0\n\tfullscreen: {fullscreen}\n\tfullscreenClient: 0\n\tpid: 1234\n"
+            )
+        };
+        assert!(!is_hyprland_fullscreen(&active_window("0")));
+        assert!(is_hyprland_fullscreen(&active_window("1")));
+        assert!(is_hyprland_fullscreen(&active_window("2")));
+        assert!(!is_hyprland_fullscreen(&active_window("false")));
+        assert!(is_hyprland_fullscreen(&active_window("true")));
+        assert!(!is_hyprland_fullscreen("Invalid\n"));
+    }
+
+    #[test]
+    fn x11_active_window() {
+        assert_eq!(
+            parse_active_window("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007\n"),
+            Some("0x3a00007")
+        );
+        assert_eq!(
+            parse_active_window("_NET_ACTIVE_WINDOW(WINDOW): window id # 0x0\n"),
+            None
+        );
+        assert_eq!(
+            parse_active_window("_NET_ACTIVE_WINDOW:  not found.\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn idle_hints() {
+        assert!(!is_idle("IdleHint=no\nLockedHint=no\n"));
+        assert!(is_idle("IdleHint=yes\nLockedHint=no\n"));
+        assert!(is_idle("IdleHint=no\nLockedHint=yes\n"));
+        assert!(!is_idle(""));
+    }
+}
@@ -0,0 +1,447 @@
+use crate::index::WallpaperIndex;
+use crate::rotator::{ChangeContext, Extension, Pick, Picks};
+use crate::Output;
+
+/// Administratively scoped multicast group picks are announced on, so every follower on the LAN
+/// receives them without knowing the leader
+pub const DEFAULT_GROUP: std::net::SocketAddr = std::net::SocketAddr::V4(
+    std::net::SocketAddrV4::new(std::net::Ipv4Addr::new(239, 255, 77, 77), 47807),
+);
+/// The leader repeats its picks this often, so late followers catch up and know it is alive
+pub const HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+/// Followers rotate on their own after missing this many heartbeats
+const MISSED_HEARTBEATS: u32 = 3;
+const MAGIC: &str = "wallrustler-sync 1";
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+/// The wallpapers the leader shows, by output name and path relative to the wallpaper directory
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Announcement {
+    pub picks: Vec<(Option<String>, String)>,
+}
+
+impl Announcement {
+    /// The magic line followed by a line of output name, empty for every output, tab and path
+    /// per pick
+    pub fn encode(&self) -> String {
+        let mut message = format!("{MAGIC}\n");
+        for (output, path) in &self.picks {
+            message.push_str(&format!("{}\t{path}\n", output.as_deref().unwrap_or("")));
+        }
+        message
+    }
+
+    /// `None` unless every path stays inside the wallpaper directory, anyone on the network can
+    /// send announcements
+    pub fn decode(message: &str) -> Option<Announcement> {
+        let mut lines = message.lines();
+        if lines.next()? != MAGIC {
+            return None;
+        }
+        let picks = lines
+            .map(|line| {
+                let (output, path) = line.split_once('\t')?;
+                if !is_relative_path(path) {
+                    return None;
+                }
+                let output = (!output.is_empty()).then(|| output.to_string());
+                Some((output, path.to_string()))
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(Announcement { picks })
+    }
+
+    /// The announced wallpapers of the collection, for the local outputs of the same name.
+    /// Picks for outputs missing here take the outputs no other pick got, preferring the one in
+    /// the same position. `outputs` is only queried when a pick names an output
+    pub fn picks_in(
+        &self,
+        wallpapers: &WallpaperIndex,
+        wallpapers_dir_path: &std::path::Path,
+        outputs: impl FnOnce() -> Vec<Output>,
+    ) -> Picks {
+        let outputs = if self.picks.iter().any(|(output, _)| output.is_some()) {
+            outputs()
+        } else {
+            vec![]
+        };
+        let mut assigned: Vec<Option<usize>> = self
+            .picks
+            .iter()
+            .map(|(output_name, _)| {
+                let output_name = output_name.as_ref()?;
+                outputs
+                    .iter()
+                    .position(|output| &output.name == output_name)
+            })
+            .collect();
+        for index in 0..self.picks.len() {
+            if self.picks[index].0.is_none() || assigned[index].is_some() {
+                continue;
+            }
+            let is_free = |output: &usize| !assigned.contains(&Some(*output));
+            assigned[index] = Some(index)
+                .filter(|&output| output < outputs.len() && is_free(&output))
+                .or_else(|| (0..outputs.len()).find(is_free));
+        }
+
+        let mut picks = vec![];
+        for ((output_name, path), output) in self.picks.iter().zip(assigned) {
+            // Only wallpapers of the collection, whatever else the path points to
+            if wallpapers.get(path).is_none() {
+                warn!("Announced wallpaper {path} is missing from the directory");
+                continue;
+            }
+            let output = output.map(|output| outputs[output].clone());
+            if output_name.is_some() && output.is_none() {
+                continue;
+            }
+            picks.push((output, wallpapers_dir_path.join(path)));
+        }
+
+        picks
+    }
+}
+
+/// Announces every change to the followers
+pub struct Leader {
+    socket: std::net::UdpSocket,
+    group: std::net::SocketAddr,
+    last: Option<(std::time::Instant, Announcement)>,
+}
+
+impl Leader {
+    pub fn new(group: std::net::SocketAddr) -> Result<Leader, std::io::Error> {
+        let socket = std::net::UdpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.set_broadcast(true)?;
+        // Followers on the leader's machine receive it too, routers don't forward it
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_multicast_ttl_v4(1)?;
+
+        Ok(Leader {
+            socket,
+            group,
+            last: None,
+        })
+    }
+
+    pub fn announce(&mut self, announcement: Announcement) -> Result<(), std::io::Error> {
+        self.last = Some((std::time::Instant::now(), announcement));
+        self.send()
+    }
+
+    /// Repeats the last announcement when a heartbeat is due
+    pub fn heartbeat(&mut self) -> Result<(), std::io::Error> {
+        match &self.last {
+            Some((sent, _)) if sent.elapsed() >= HEARTBEAT_INTERVAL => {
+                self.last = self
+                    .last
+                    .take()
+                    .map(|(_, announcement)| (std::time::Instant::now(), announcement));
+                self.send()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn next_heartbeat(&self) -> Option<std::time::Instant> {
+        self.last
+            .as_ref()
+            .map(|(sent, _)| *sent + HEARTBEAT_INTERVAL)
+    }
+
+    fn send(&self) -> Result<(), std::io::Error> {
+        if let Some((_, announcement)) = &self.last {
+            self.socket
+                .send_to(announcement.encode().as_bytes(), self.group)?;
+        }
+        Ok(())
+    }
+}
+
+/// Announces the wallpapers of the collection every change shows
+impl Extension for Leader {
+    fn changed(&mut self, _change: &mut ChangeContext, picks: &[Pick]) {
+        let announcement = Announcement {
+            picks: picks
+                .iter()
+                .filter_map(|pick| {
+                    Some((
+                        pick.output.map(|output| output.name.clone()),
+                        pick.entry?.file_name.clone(),
+                    ))
+                })
+                .collect(),
+        };
+        if let Err(err) = self.announce(announcement) {
+            error!("Unable to announce the current wallpaper: {err}");
+        }
+    }
+
+    fn next_deadline(&self) -> Option<std::time::Instant> {
+        self.next_heartbeat()
+    }
+
+    fn run_pending(&mut self) {
+        if let Err(err) = self.heartbeat() {
+            error!("Unable to announce the current wallpaper: {err}");
+        }
+    }
+}
+
+/// Receives announcements on a thread, applied by the main loop
+pub struct Follower {
+    state: std::sync::Arc<std::sync::Mutex<FollowerState>>,
+}
+
+#[derive(Default)]
+struct FollowerState {
+    /// Received and not taken yet
+    pending: Option<Announcement>,
+    last_seen: Option<std::time::Instant>,
+}
+
+impl Follower {
+    /// Listens on the port of `group`, joining it when it is a multicast group; `wake` is called
+    /// on every announcement to apply
+    pub fn spawn(
+        group: std::net::SocketAddr,
+        wake: impl Fn() + Send + 'static,
+    ) -> Result<Follower, std::io::Error> {
+        let bind_ip = if group.ip().is_loopback() {
+            group.ip()
+        } else {
+            std::net::Ipv4Addr::UNSPECIFIED.into()
+        };
+        let socket = std::net::UdpSocket::bind((bind_ip, group.port()))?;
+        if let std::net::IpAddr::V4(ip) = group.ip() {
+            if ip.is_multicast() {
+                socket.join_multicast_v4(&ip, &std::net::Ipv4Addr::UNSPECIFIED)?;
+            }
+        }
+
+        let state = std::sync::Arc::new(std::sync::Mutex::new(FollowerState::default()));
+        let thread_state = state.clone();
+        std::thread::spawn(move || {
+            let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+            let mut last_received: Option<Announcement> = None;
+            loop {
+                let (len, from) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(err) => {
+                        error!("Unable to receive announcements: {err}");
+                        std::thread::sleep(HEARTBEAT_INTERVAL);
+                        continue;
+                    }
+                };
+                let Some(announcement) = std::str::from_utf8(&buf[..len])
+                    .ok()
+                    .and_then(Announcement::decode)
+                else {
+                    debug!("Ignoring invalid announcement from {from}");
+                    continue;
+                };
+
+                let mut state = thread_state.lock().unwrap();
+                // The follower rotated on its own while the leader was gone
+                let returned = state
+                    .last_seen
+                    .is_none_or(|last_seen| last_seen.elapsed() > leader_timeout());
+                state.last_seen = Some(std::time::Instant::now());
+                if returned || last_received.as_ref() != Some(&announcement) {
+                    debug!("Announcement from {from}: {:?}", announcement.picks);
+                    last_received = Some(announcement.clone());
+                    state.pending = Some(announcement);
+                    drop(state);
+                    wake();
+                }
+            }
+        });
+
+        Ok(Follower { state })
+    }
+
+    pub fn take(&self) -> Option<Announcement> {
+        self.state.lock().unwrap().pending.take()
+    }
+
+    /// Until when the leader counts as present, `None` before it was ever heard from
+    pub fn leader_deadline(&self) -> Option<std::time::Instant> {
+        self.state
+            .lock()
+            .unwrap()
+            .last_seen
+            .map(|last_seen| last_seen + leader_timeout())
+    }
+
+    pub fn is_leader_present(&self) -> bool {
+        self.leader_deadline()
+            .is_some_and(|deadline| std::time::Instant::now() < deadline)
+    }
+}
+
+fn leader_timeout() -> std::time::Duration {
+    HEARTBEAT_INTERVAL * MISSED_HEARTBEATS
+}
+
+/// A path below the directory it is joined to, without `..`, a root or a drive
+fn is_relative_path(path: &str) -> bool {
+    let path = std::path::Path::new(path);
+    !path.as_os_str().is_empty()
+        && path
+            .components()
+            .all(|component| matches!(component, std::path::Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement() -> Announcement {
+        Announcement {
+            picks: vec![
+                (Some("DP-1".to_string()), "sea/a b.jpg".to_string()),
+                (None, "c.png".to_string()),
+            ],
+        }
+    }
+
+    #[test]
+    fn encodes_and_decodes() {
+        let message = announcement().encode();
+        assert_eq!(message, format!("{MAGIC}\nDP-1\tsea/a b.jpg\n\tc.png\n"));
+        assert_eq!(Announcement::decode(&message), Some(announcement()));
+        assert_eq!(
+            Announcement::decode(&format!("{MAGIC}\n")),
+            Some(Announcement::default())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_announcements() {
+        for message in [
+            "".to_string(),
+            "wallrustler-sync 2\n\ta.jpg\n".to_string(),
+            format!("{MAGIC}\na.jpg\n"),
+            format!("{MAGIC}\n\t\n"),
+            format!("{MAGIC}\n\t/etc/passwd\n"),
+            format!("{MAGIC}\n\t../a.jpg\n"),
+            format!("{MAGIC}\nDP-1\tsea/../../a.jpg\n"),
+            format!("{MAGIC}\n\ta.jpg\n\t./../a.jpg\n"),
+        ] {
+            assert_eq!(Announcement::decode(&message), None, "{message:?}");
+        }
+    }
+
+    #[test]
+    fn follower_receives_the_leader_over_loopback() {
+        // A free port, released again for the follower to bind
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let group: std::net::SocketAddr = ([127, 0, 0, 1], port).into();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let follower = Follower::spawn(group, move || {
+            let _ = tx.send(());
+        })
+        .unwrap();
+        assert!(!follower.is_leader_present());
+
+        let mut leader = Leader::new(group).unwrap();
+        leader.announce(announcement()).unwrap();
+        rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        assert_eq!(follower.take(), Some(announcement()));
+        assert_eq!(follower.take(), None);
+        assert!(follower.is_leader_present());
+
+        // Repeats only count as heartbeats
+        leader.send().unwrap();
+        assert!(rx
+            .recv_timeout(std::time::Duration::from_millis(200))
+            .is_err());
+        let changed = Announcement {
+            picks: vec![(None, "d.jpg".to_string())],
+        };
+        leader.announce(changed.clone()).unwrap();
+        rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        assert_eq!(follower.take(), Some(changed));
+    }
+
+    fn output(name: &str) -> Output {
+        Output {
+            name: name.to_string(),
+            width: 1920,
+            height: 1080,
+        }
+    }
+
+    fn collection() -> WallpaperIndex {
+        WallpaperIndex::new(vec![
+            crate::Wallpaper::new("a.jpg".to_string()),
+            crate::Wallpaper::new("b.jpg".to_string()),
+        ])
+    }
+
+    #[test]
+    fn picks_follow_outputs_by_name() {
+        let announcement = Announcement {
+            picks: vec![
+                (Some("DP-2".to_string()), "a.jpg".to_string()),
+                (Some("DP-1".to_string()), "b.jpg".to_string()),
+            ],
+        };
+        let dir = std::path::Path::new("/wallpapers");
+        assert_eq!(
+            announcement.picks_in(&collection(), dir, || vec![output("DP-1"), output("DP-2")]),
+            vec![
+                (Some(output("DP-2")), dir.join("a.jpg")),
+                (Some(output("DP-1")), dir.join("b.jpg")),
+            ]
+        );
+    }
+
+    #[test]
+    fn renamed_outputs_take_the_free_ones() {
+        let announcement = Announcement {
+            picks: vec![
+                (Some("DP-1".to_string()), "a.jpg".to_string()),
+                (Some("DP-2".to_string()), "b.jpg".to_string()),
+            ],
+        };
+        let dir = std::path::Path::new("/wallpapers");
+        // DP-2 would fall back to DP-1 by position, which the first pick got by name
+        assert_eq!(
+            announcement.picks_in(&collection(), dir, || vec![
+                output("HDMI-A-1"),
+                output("DP-1")
+            ]),
+            vec![
+                (Some(output("DP-1")), dir.join("a.jpg")),
+                (Some(output("HDMI-A-1")), dir.join("b.jpg")),
+            ]
+        );
+        // More announced outputs than local ones
+        assert_eq!(
+            announcement.picks_in(&collection(), dir, || vec![output("HDMI-A-1")]),
+            vec![(Some(output("HDMI-A-1")), dir.join("a.jpg"))]
+        );
+    }
+
+    #[test]
+    fn picks_skip_unknown_wallpapers() {
+        let announcement = Announcement {
+            picks: vec![
+                (None, "missing.jpg".to_string()),
+                (None, "a.jpg".to_string()),
+            ],
+        };
+        let dir = std::path::Path::new("/wallpapers");
+        assert_eq!(
+            announcement.picks_in(&collection(), dir, || panic!("no output is named")),
+            vec![(None, dir.join("a.jpg"))]
+        );
+    }
+}
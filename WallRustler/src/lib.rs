@@ -1,224 +1,929 @@
+#[macro_use]
+pub mod log;
+
 #[cfg_attr(target_os = "windows", path = "windows.rs")]
 #[cfg_attr(not(target_os = "windows"), path = "linux.rs")]
 pub mod wallpaper;
 
-use rand::prelude::*;
+pub mod cache;
+pub mod cli;
+#[cfg(target_os = "linux")]
+pub mod dbus;
+pub mod events;
+pub mod hooks;
+pub mod index;
+#[cfg(target_os = "linux")]
+pub mod inhibit;
+pub mod lan;
+#[cfg(target_os = "linux")]
+pub mod live;
+#[cfg(target_os = "linux")]
+pub mod lock;
+pub mod metadata;
+pub mod pipeline;
+pub mod power;
+pub mod rng;
+pub mod rotator;
+pub mod schedule;
+pub mod source;
+#[cfg(target_os = "linux")]
+pub mod status;
+#[cfg(target_os = "linux")]
+pub mod systemd;
+pub mod theme;
+pub mod watcher;
+
+use metadata::Metadata;
+use pipeline::FitMode;
 use serde::{Deserialize, Serialize};
 #[cfg(target_os = "linux")]
 use wallpaper::WallSetterProgram;
 
 const COUNT_FACTOR: f64 = 1.001;
+pub(crate) const TAGS_EXTENSION: &str = "tags";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Wallpaper {
     pub file_name: String,
     pub count: usize,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(skip)]
+    pub file_tags: Vec<String>,
+    #[serde(default)]
+    pub metadata: Metadata,
+    /// Rating given over D-Bus, takes precedence over the one in the file
+    #[serde(default)]
+    pub rating: std::option::Option<i8>,
+}
+
+impl Wallpaper {
+    pub fn new(file_name: String) -> Wallpaper {
+        Wallpaper {
+            file_name,
+            count: 0,
+            tags: vec![],
+            file_tags: vec![],
+            metadata: Metadata::default(),
+            rating: None,
+        }
+    }
+
+    /// Tags stored in the state, set with `wallrustler tag`, merged with the ones derived from
+    /// folder names, `.tags` sidecars and XMP keywords
+    pub fn all_tags(&self) -> Vec<&String> {
+        let mut tags: Vec<&String> = self.tags_iter().collect();
+        tags.sort();
+        tags.dedup();
+        tags
+    }
+
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags_iter().any(|t| t == tag)
+    }
+
+    /// Videos and animated images, played by the live backend when enabled
+    pub fn is_live(&self) -> bool {
+        self.metadata.animated
+    }
+
+    /// Videos can only be shown by the live backend, unlike animated images
+    pub fn is_video(&self) -> bool {
+        is_video_file(std::path::Path::new(&self.file_name))
+    }
+
+    pub fn rating(&self) -> i8 {
+        self.rating.unwrap_or(self.metadata.rating)
+    }
+
+    /// Selection weight, with the count taken relative to `count_offset` so it doesn't
+    /// underflow once every wallpaper has been shown many times
+    pub fn weight(&self, count_offset: usize) -> f64 {
+        if self.metadata.broken.is_some() {
+            return 0.0;
+        }
+        COUNT_FACTOR.powf(count_offset as f64 - self.count as f64)
+            * metadata::rating_weight(self.rating())
+    }
+
+    fn tags_iter(&self) -> impl Iterator<Item = &String> {
+        self.tags
+            .iter()
+            .chain(self.file_tags.iter())
+            .chain(self.metadata.keywords.iter())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct TagFilter {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+impl TagFilter {
+    /// Parses `nature,-anime`: plain tags are required (any of them), `-` prefixed ones are excluded
+    pub fn parse(s: &str) -> std::option::Option<TagFilter> {
+        let mut filter = TagFilter::default();
+        for tag in s.split(',').map(|tag| tag.trim()) {
+            if let Some(tag) = tag.strip_prefix('-') {
+                filter.exclude.push(normalize_tag(tag)?);
+            } else {
+                filter.include.push(normalize_tag(tag)?);
+            }
+        }
+
+        Some(filter)
+    }
+
+    pub fn matches(&self, wallpaper: &Wallpaper) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|tag| wallpaper.has_tag(tag)))
+            && !self.exclude.iter().any(|tag| wallpaper.has_tag(tag))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Output {
+    pub name: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MinResolution {
+    /// At least the resolution of the output the wallpaper is picked for
+    Output,
+    Size(u32, u32),
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ResolutionFilter {
+    pub min_resolution: std::option::Option<MinResolution>,
+    /// Maximum relative deviation of the wallpaper aspect ratio from the output one
+    pub aspect_tolerance: std::option::Option<f64>,
+}
+
+impl ResolutionFilter {
+    pub fn is_empty(&self) -> bool {
+        self.min_resolution.is_none() && self.aspect_tolerance.is_none()
+    }
+
+    /// Rules depending on the output are skipped when it is unknown
+    pub fn matches(&self, wallpaper: &Wallpaper, output: std::option::Option<&Output>) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let (width, height) = (wallpaper.metadata.width, wallpaper.metadata.height);
+        if width == 0 || height == 0 {
+            return false;
+        }
+
+        let min_resolution = match (self.min_resolution, output) {
+            (Some(MinResolution::Size(min_width, min_height)), _) => Some((min_width, min_height)),
+            (Some(MinResolution::Output), Some(output)) => Some((output.width, output.height)),
+            _ => None,
+        };
+        if let Some((min_width, min_height)) = min_resolution {
+            if width < min_width || height < min_height {
+                return false;
+            }
+        }
+
+        if let (Some(tolerance), Some(output)) = (self.aspect_tolerance, output) {
+            if output.width == 0 || output.height == 0 {
+                return true;
+            }
+            let aspect_ratio = width as f64 / height as f64;
+            let output_aspect_ratio = output.width as f64 / output.height as f64;
+            if (aspect_ratio / output_aspect_ratio - 1.0).abs() > tolerance {
+                return false;
+            }
+        }
+
+        true
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Option {
     Path(std::path::PathBuf),
+    /// Set by the `state` command
     PrintState,
-    PrintHelp,
-    Interval(u64),
+    Interval(schedule::Interval),
+    /// Change on clock boundaries of the interval
+    Align,
+    Cron(schedule::CronEntry),
+    /// Print the next scheduled changes instead of running
+    DryRun,
+    Seed(u64),
+    /// Announce every change on the group address
+    SyncLead(std::net::SocketAddr),
+    /// Show what the leader announces on the group address
+    SyncFollow(std::net::SocketAddr),
+    Tags(TagFilter),
+    MinResolution(MinResolution),
+    AspectTolerance(f64),
+    Fit(FitMode),
+    Brightness(i8),
+    NightDim(u8),
+    NightHours(u8, u8),
+    Grayscale,
+    ShowNew,
+    /// Added up from `-v` and `-q`
+    Verbosity(i8),
+    LogFile(std::path::PathBuf),
+    /// In bytes
+    LogMaxSize(u64),
+    Theme,
+    ThemeDir(std::path::PathBuf),
+    ThemeTemplates(std::path::PathBuf),
+    PreHook(String),
+    PostHook(String),
+    /// In seconds
+    HookTimeout(u64),
+    Source(source::Source),
+    /// In bytes, per source
+    SourceMaxSize(u64),
+    /// In minutes
+    SourceRefresh(u64),
+    /// In bytes
+    CacheMaxSize(u64),
     #[cfg(target_os = "linux")]
     RestartSWWW,
     #[cfg(target_os = "linux")]
+    InstallService,
+    #[cfg(target_os = "linux")]
+    PauseOnBattery(u8),
+    #[cfg(target_os = "linux")]
+    PauseOnFullscreen,
+    #[cfg(target_os = "linux")]
+    PauseWhenIdle,
+    #[cfg(target_os = "linux")]
+    Live,
+    #[cfg(target_os = "linux")]
+    LiveMaxLoad(f64),
+    #[cfg(target_os = "linux")]
+    LiveOnBattery,
+    /// Empty when only the lock image is written
+    #[cfg(target_os = "linux")]
+    LockScreen(Vec<lock::LockTarget>),
+    #[cfg(target_os = "linux")]
+    LockImage(std::path::PathBuf),
+    /// Blur sigma
+    #[cfg(target_os = "linux")]
+    LockBlur(f32),
+    #[cfg(target_os = "linux")]
     Program(WallSetterProgram),
 }
 
 #[derive(Debug)]
 pub enum Error {
     InvalidOption(String),
+    /// An option given as `--key` without its value
+    MissingValue(String),
+    UnknownCommand(String),
     InvalidOptionsStructure,
 }
 
-pub fn process_args() -> Result<Vec<Option>, Error> {
-    let mut options = vec![];
-    let mut args = std::env::args().skip(1).rev();
-
-    if let Some(wallpapers_dir_path) = args.next() {
-        let wallpapers_dir_path = std::path::PathBuf::from(wallpapers_dir_path);
-        if !wallpapers_dir_path.is_dir() {
-            return Err(Error::InvalidOptionsStructure);
-        }
-        options.push(Option::Path(wallpapers_dir_path));
-        for arg in args {
-            let arg = match arg.as_str() {
-                "--print-state" => Ok(Option::PrintState),
-                "--help" => Ok(Option::PrintState),
-                s if s.starts_with("--interval=") => {
-                    if let Some(suffix) = s.split_once('=').map(|(_, s)| s.parse::<u64>()) {
-                        if let Ok(min) = suffix {
-                            if min > 0 {
-                                Ok(Option::Interval(min))
-                            } else {
-                                Err(Error::InvalidOption(arg))
-                            }
-                        } else {
-                            Err(Error::InvalidOption(arg))
-                        }
-                    } else {
-                        Err(Error::InvalidOption(arg))
-                    }
+/// Parses a single `--key=value` or flag argument, see `cli` for the command line as a whole
+pub fn parse_option(arg: String) -> Result<Option, Error> {
+    match arg.as_str() {
+        "--print-state" => Ok(Option::PrintState),
+        s if s.starts_with("--interval=") => {
+            if let Some(interval) = s
+                .split_once('=')
+                .and_then(|(_, s)| schedule::Interval::parse(s))
+            {
+                Ok(Option::Interval(interval))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        "--align" => Ok(Option::Align),
+        s if s.starts_with("--cron=") => {
+            if let Some(entry) = s
+                .split_once('=')
+                .and_then(|(_, s)| schedule::CronEntry::parse(s))
+            {
+                Ok(Option::Cron(entry))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        "--dry-run" => Ok(Option::DryRun),
+        "--sync-lead" => Ok(Option::SyncLead(lan::DEFAULT_GROUP)),
+        s if s.starts_with("--sync-lead=") => {
+            if let Some(Ok(group)) = s.split_once('=').map(|(_, s)| s.parse()) {
+                Ok(Option::SyncLead(group))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        "--sync-follow" => Ok(Option::SyncFollow(lan::DEFAULT_GROUP)),
+        s if s.starts_with("--sync-follow=") => {
+            if let Some(Ok(group)) = s.split_once('=').map(|(_, s)| s.parse()) {
+                Ok(Option::SyncFollow(group))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--seed=") => {
+            if let Some(Ok(seed)) = s.split_once('=').map(|(_, s)| s.parse::<u64>()) {
+                Ok(Option::Seed(seed))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--tags=") => {
+            if let Some(filter) = s.split_once('=').and_then(|(_, s)| TagFilter::parse(s)) {
+                Ok(Option::Tags(filter))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        "--min-resolution=output" => Ok(Option::MinResolution(MinResolution::Output)),
+        s if s.starts_with("--min-resolution=") => {
+            if let Some((width, height)) = s.split_once('=').and_then(|(_, s)| parse_size(s)) {
+                Ok(Option::MinResolution(MinResolution::Size(width, height)))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--aspect-tolerance=") => {
+            let percent = s
+                .split_once('=')
+                .map(|(_, s)| s.trim_end_matches('%').parse::<f64>());
+            if let Some(Ok(percent)) = percent {
+                if (0.0..100.0).contains(&percent) {
+                    Ok(Option::AspectTolerance(percent / 100.0))
+                } else {
+                    Err(Error::InvalidOption(arg))
                 }
-                #[cfg(target_os = "linux")]
-                "--restart-swww" => Ok(Option::RestartSWWW),
-                #[cfg(target_os = "linux")]
-                s if s.starts_with("--program=") => {
-                    if s.ends_with("swww") {
-                        Ok(Option::Program(WallSetterProgram::SWWW))
-                    } else if s.ends_with("plasma-apply-wallpaperimage") {
-                        Ok(Option::Program(WallSetterProgram::PLASMA))
-                    } else if s.ends_with("hyprpaper") {
-                        #[allow(unused_mut, unused_assignments)]
-                        let mut option = Err(Error::InvalidOption(arg));
-                        #[cfg(all(feature = "hyprpaper", target_os = "linux"))]
-                        {
-                            option = Ok(Option::Program(WallSetterProgram::HYPRPAPER));
-                        }
-                        option
-                    } else {
-                        Err(Error::InvalidOption(arg))
-                    }
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--fit=") => {
+            if let Some(fit_mode) = s.split_once('=').and_then(|(_, s)| FitMode::parse(s)) {
+                Ok(Option::Fit(fit_mode))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--brightness=") => {
+            if let Some(Ok(brightness)) = s.split_once('=').map(|(_, s)| s.parse::<i8>()) {
+                if (-100..=100).contains(&brightness) {
+                    Ok(Option::Brightness(brightness))
+                } else {
+                    Err(Error::InvalidOption(arg))
                 }
-                _ => Err(Error::InvalidOption(arg)),
-            };
-            options.push(arg?);
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
         }
-        return Ok(options);
-    } else {
-        return Err(Error::InvalidOptionsStructure);
-    }
-}
-
-pub fn print_help() {
-    println!("Usage: {} [OPTIONS] DIRECTORY", env!("CARGO_PKG_NAME"));
-    println!("       {} --print-state DIRECTORY", env!("CARGO_PKG_NAME"));
-    println!("Options:");
-    println!("\t --help");
-    println!("\t --interval=<u64>");
-    println!("\t --restart-swww\t\t\t\tMight resolve the issue with out-of-sync and overlapping animations/wallpapers");
-    #[cfg(not(all(feature = "hyprpaper", target_os = "linux")))]
-    println!("\t --program=<swww|plasma-apply-wallpaperimage>");
-    #[cfg(all(feature = "hyprpaper", target_os = "linux"))]
-    println!("\t --program=<swww|hyprpaper|plasma-apply-wallpaperimage>");
-}
-
-pub fn pick_random_wallpaper(
-    wallpaper_dir_path: &std::path::Path,
-    wallpapers: &mut Vec<Wallpaper>,
-) -> std::path::PathBuf {
-    let total_count_w: f64 = wallpapers
-        .iter()
-        .map(|wallpaper| wallpaper.count as f64)
-        .fold(0.0, |acc, count: f64| acc + COUNT_FACTOR.powf(-count));
-
-    let rand_num = get_random_num(total_count_w);
-    let mut cum_count_w: f64 = 0.0;
-    let wallpaper = wallpapers
-        .iter_mut()
-        .skip_while(|wallpaper| {
-            cum_count_w += COUNT_FACTOR.powf(-(wallpaper.count as f64));
-            cum_count_w < rand_num
-        })
-        .next()
-        .unwrap();
-
-    wallpaper.count += 1;
-
-    wallpaper_dir_path.join(wallpaper.file_name.clone())
-}
-
-pub fn sync_wallpapers(
-    wallpaper_dir_path: &std::path::Path,
-    mut wallpapers: Vec<Wallpaper>,
-) -> Vec<Wallpaper> {
-    let wallpapers_names = get_wallpapers_from_path(&wallpaper_dir_path);
-
-    let old_wallpapers_names: Vec<&String> = wallpapers
-        .iter()
-        .map(|wallpaper_name| &wallpaper_name.file_name)
-        .collect();
+        s if s.starts_with("--night-dim=") => {
+            let percent = s
+                .split_once('=')
+                .map(|(_, s)| s.trim_end_matches('%').parse::<u8>());
+            if let Some(Ok(percent)) = percent {
+                if percent <= 100 {
+                    Ok(Option::NightDim(percent))
+                } else {
+                    Err(Error::InvalidOption(arg))
+                }
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--night-hours=") => {
+            let hours = s
+                .split_once('=')
+                .and_then(|(_, s)| s.split_once('-'))
+                .map(|(start, end)| (start.parse::<u8>(), end.parse::<u8>()));
+            if let Some((Ok(start), Ok(end))) = hours {
+                if start < 24 && end < 24 {
+                    Ok(Option::NightHours(start, end))
+                } else {
+                    Err(Error::InvalidOption(arg))
+                }
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        "--grayscale" => Ok(Option::Grayscale),
+        "--show-new" => Ok(Option::ShowNew),
+        "-v" | "--verbose" => Ok(Option::Verbosity(1)),
+        "-q" | "--quiet" => Ok(Option::Verbosity(-1)),
+        "-qq" => Ok(Option::Verbosity(-2)),
+        s if s.starts_with("--log-file=") => {
+            if let Some((_, path)) = s.split_once('=').filter(|(_, path)| !path.is_empty()) {
+                Ok(Option::LogFile(std::path::PathBuf::from(path)))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        "--theme" => Ok(Option::Theme),
+        s if s.starts_with("--theme-dir=") => {
+            if let Some((_, path)) = s.split_once('=').filter(|(_, path)| !path.is_empty()) {
+                Ok(Option::ThemeDir(std::path::PathBuf::from(path)))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--theme-templates=") => {
+            if let Some((_, path)) = s.split_once('=').filter(|(_, path)| !path.is_empty()) {
+                Ok(Option::ThemeTemplates(std::path::PathBuf::from(path)))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--pre-hook=") => {
+            if let Some((_, command)) = s.split_once('=').filter(|(_, command)| !command.is_empty())
+            {
+                Ok(Option::PreHook(command.to_string()))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--hook-timeout=") => {
+            if let Some(Ok(secs)) = s.split_once('=').map(|(_, s)| s.parse::<u64>()) {
+                if secs > 0 {
+                    Ok(Option::HookTimeout(secs))
+                } else {
+                    Err(Error::InvalidOption(arg))
+                }
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--post-hook=") => {
+            if let Some((_, command)) = s.split_once('=').filter(|(_, command)| !command.is_empty())
+            {
+                Ok(Option::PostHook(command.to_string()))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--log-max-size=") => {
+            if let Some(Some(size)) = s.split_once('=').map(|(_, s)| parse_mib(s)) {
+                if size > 0 {
+                    Ok(Option::LogMaxSize(size))
+                } else {
+                    Err(Error::InvalidOption(arg))
+                }
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--source=") => {
+            if let Some(source) = s
+                .split_once('=')
+                .and_then(|(_, s)| source::Source::parse(s))
+            {
+                Ok(Option::Source(source))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--source-max-size=") => {
+            if let Some(Some(size)) = s.split_once('=').map(|(_, s)| parse_mib(s)) {
+                if size > 0 {
+                    Ok(Option::SourceMaxSize(size))
+                } else {
+                    Err(Error::InvalidOption(arg))
+                }
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--source-refresh=") => {
+            if let Some(Ok(minutes)) = s.split_once('=').map(|(_, s)| s.parse::<u64>()) {
+                if minutes > 0 {
+                    Ok(Option::SourceRefresh(minutes))
+                } else {
+                    Err(Error::InvalidOption(arg))
+                }
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        s if s.starts_with("--cache-max-size=") => {
+            if let Some(Some(size)) = s.split_once('=').map(|(_, s)| parse_mib(s)) {
+                Ok(Option::CacheMaxSize(size))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        #[cfg(target_os = "linux")]
+        "--restart-swww" => Ok(Option::RestartSWWW),
+        #[cfg(target_os = "linux")]
+        "--install-service" => Ok(Option::InstallService),
+        #[cfg(target_os = "linux")]
+        s if s.starts_with("--pause-on-battery=") => {
+            let percent = s
+                .split_once('=')
+                .map(|(_, s)| s.trim_end_matches('%').parse::<u8>());
+            if let Some(Ok(percent)) = percent {
+                if percent <= 100 {
+                    Ok(Option::PauseOnBattery(percent))
+                } else {
+                    Err(Error::InvalidOption(arg))
+                }
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        #[cfg(target_os = "linux")]
+        "--pause-on-fullscreen" => Ok(Option::PauseOnFullscreen),
+        #[cfg(target_os = "linux")]
+        "--pause-when-idle" => Ok(Option::PauseWhenIdle),
+        #[cfg(target_os = "linux")]
+        "--live" => Ok(Option::Live),
+        #[cfg(target_os = "linux")]
+        "--live-on-battery" => Ok(Option::LiveOnBattery),
+        #[cfg(target_os = "linux")]
+        s if s.starts_with("--live-max-load=") => {
+            if let Some(Ok(load)) = s.split_once('=').map(|(_, s)| s.parse::<f64>()) {
+                if load > 0.0 {
+                    Ok(Option::LiveMaxLoad(load))
+                } else {
+                    Err(Error::InvalidOption(arg))
+                }
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        #[cfg(target_os = "linux")]
+        "--lock-screen" => Ok(Option::LockScreen(vec![])),
+        #[cfg(target_os = "linux")]
+        s if s.starts_with("--lock-screen=") => {
+            let targets = s.split_once('=').and_then(|(_, s)| {
+                s.split(',')
+                    .map(lock::LockTarget::parse)
+                    .collect::<std::option::Option<Vec<lock::LockTarget>>>()
+            });
+            if let Some(targets) = targets {
+                Ok(Option::LockScreen(targets))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        #[cfg(target_os = "linux")]
+        s if s.starts_with("--lock-image=") => {
+            if let Some((_, path)) = s.split_once('=').filter(|(_, path)| !path.is_empty()) {
+                Ok(Option::LockImage(std::path::PathBuf::from(path)))
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        #[cfg(target_os = "linux")]
+        "--lock-blur" => Ok(Option::LockBlur(lock::DEFAULT_BLUR_SIGMA)),
+        #[cfg(target_os = "linux")]
+        s if s.starts_with("--lock-blur=") => {
+            if let Some(Ok(sigma)) = s.split_once('=').map(|(_, s)| s.parse::<f32>()) {
+                if sigma > 0.0 {
+                    Ok(Option::LockBlur(sigma))
+                } else {
+                    Err(Error::InvalidOption(arg))
+                }
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        #[cfg(target_os = "linux")]
+        s if s.starts_with("--program=") => {
+            if s.ends_with("swww") {
+                Ok(Option::Program(WallSetterProgram::SWWW))
+            } else if s.ends_with("plasma-apply-wallpaperimage") {
+                Ok(Option::Program(WallSetterProgram::PLASMA))
+            } else if s.ends_with("hyprpaper") {
+                #[allow(unused_mut, unused_assignments)]
+                let mut option = Err(Error::InvalidOption(arg));
+                #[cfg(all(feature = "hyprpaper", target_os = "linux"))]
+                {
+                    option = Ok(Option::Program(WallSetterProgram::HYPRPAPER));
+                }
+                option
+            } else {
+                Err(Error::InvalidOption(arg))
+            }
+        }
+        _ => Err(Error::InvalidOption(arg)),
+    }
+}
 
-    let mut new_wallpapers: Vec<Wallpaper> = wallpapers_names
-        .iter()
-        .filter(|wallpaper_name| !old_wallpapers_names.contains(wallpaper_name))
-        .map(|wallpaper_name| Wallpaper {
-            file_name: wallpaper_name.clone(),
-            count: 0,
-        })
-        .collect();
-    new_wallpapers
-        .iter()
-        .for_each(|wallpaper| println!("Pushing {}", wallpaper.file_name));
-
-    let removed_wallpapers_names: Vec<String> = old_wallpapers_names
-        .iter()
-        .filter(|wallpaper_name| !wallpapers_names.contains(wallpaper_name))
-        .map(|wallpaper_name| wallpaper_name.to_string())
-        .collect();
+pub(crate) fn refresh_wallpaper(wallpaper_dir_path: &std::path::Path, wallpaper: &mut Wallpaper) {
+    wallpaper.file_tags = get_file_tags(wallpaper_dir_path, wallpaper);
+    let wallpaper_path = wallpaper_dir_path.join(&wallpaper.file_name);
+    if metadata::get_metadata_modified(&wallpaper_path) != wallpaper.metadata.modified {
+        wallpaper.metadata = metadata::read_metadata(&wallpaper_path);
+    }
+}
 
-    for to_remove in removed_wallpapers_names {
-        if let Some(to_remove_index) = wallpapers
-            .iter()
-            .position(|wallpaper| wallpaper.file_name == to_remove)
-        {
-            println!("Popping {}", to_remove);
-            wallpapers.swap_remove(to_remove_index);
+/// Returns image file names relative to `wallpaper_dir_path`, descending into subdirectories
+pub fn get_wallpapers_from_path(wallpaper_dir_path: &std::path::Path) -> Vec<String> {
+    let mut wallpapers = vec![];
+    let mut dirs = vec![std::path::PathBuf::new()];
+
+    // Symlinked directories are followed once, a link to an ancestor would loop forever
+    let mut visited: std::collections::HashSet<std::path::PathBuf> =
+        std::collections::HashSet::new();
+
+    while let Some(dir) = dirs.pop() {
+        let dir_path = wallpaper_dir_path.join(&dir);
+        match dir_path.canonicalize() {
+            Ok(canonical) => {
+                if !visited.insert(canonical) {
+                    debug!("Skipping {:?}, already scanned", dir_path);
+                    continue;
+                }
+            }
+            Err(err) => {
+                warn!("Unable to resolve {:?}: {err}", dir_path);
+                continue;
+            }
+        }
+        let dir_entries = match dir_path.read_dir() {
+            Ok(dir_entries) => dir_entries,
+            Err(err) => {
+                error!("Unable to read {:?}: {err}", dir_path);
+                continue;
+            }
+        };
+        for dir_entry in dir_entries.filter_map(|dir_entry| dir_entry.ok()) {
+            let path = dir_entry.path();
+            if path.is_dir() {
+                dirs.push(dir.join(dir_entry.file_name()));
+            } else if is_wallpaper_file(&path) {
+                let file_name = dir.join(dir_entry.file_name());
+                let file_name = file_name
+                    .into_os_string()
+                    .into_string()
+                    .unwrap_or_else(|_| panic!("Invalid Unicode file name: {:?}", dir_entry));
+                wallpapers.push(file_name);
+            }
         }
     }
-
-    wallpapers.append(&mut new_wallpapers);
+    // Directory order differs between file systems, new wallpapers are added in a stable one so
+    // that seeded rotation is reproducible
+    wallpapers.sort();
 
     wallpapers
 }
 
-pub fn mean_centering_counts(mut wallpapers: Vec<Wallpaper>) -> Vec<Wallpaper> {
-    if let Some(min) = wallpapers.iter().map(|w| w.count).min() {
-        if min != 0 {
-            wallpapers.iter_mut().for_each(|w| w.count -= min);
-        }
+/// Tags from the wallpaper's parent folder names and its `<file_name>.tags` sidecar,
+/// which lists tags separated by commas or new lines
+fn get_file_tags(wallpaper_dir_path: &std::path::Path, wallpaper: &Wallpaper) -> Vec<String> {
+    let file_name = std::path::Path::new(&wallpaper.file_name);
+    let mut tags: Vec<String> = file_name
+        .parent()
+        .into_iter()
+        .flat_map(|parent| parent.components())
+        .filter_map(|component| normalize_tag(&component.as_os_str().to_string_lossy()))
+        .collect();
+
+    let mut sidecar = wallpaper_dir_path.join(file_name).into_os_string();
+    sidecar.push(".");
+    sidecar.push(TAGS_EXTENSION);
+    if let Ok(sidecar) = std::fs::read_to_string(sidecar) {
+        tags.extend(sidecar.split([',', '\n']).filter_map(normalize_tag));
     }
-    wallpapers
+
+    tags.sort();
+    tags.dedup();
+    tags
 }
 
-pub fn get_wallpapers_from_path(wallpaper_dir_path: &std::path::Path) -> Vec<String> {
-    let wallpapers = wallpaper_dir_path.read_dir().unwrap();
-    let wallpapers = wallpapers.filter_map(|dir_entry| dir_entry.ok());
-    let wallpapers = wallpapers.filter(|dir_entry| {
-        dir_entry
-            .path()
-            .extension()
-            .map_or(false, |extension| is_img_file(extension))
-    });
-    let wallpapers = wallpapers.map(|dir_entry| {
-        dir_entry
-            .file_name()
-            .into_string()
-            .expect(&format!("Invalid Unicode file name: {:?}", dir_entry))
-    });
-
-    wallpapers.collect()
+pub(crate) fn normalize_tag(tag: &str) -> std::option::Option<String> {
+    let tag = tag.trim().to_lowercase();
+    if tag.is_empty() || tag.contains(',') {
+        None
+    } else {
+        Some(tag)
+    }
+}
+
+pub(crate) fn is_wallpaper_file(path: &std::path::Path) -> bool {
+    path.extension().is_some_and(is_img_file)
+        || is_video_file(path)
+        || metadata::has_image_signature(path)
+}
+
+pub(crate) fn is_video_file(path: &std::path::Path) -> bool {
+    path.extension().is_some_and(|extension| {
+        matches!(
+            extension.to_string_lossy().to_lowercase().as_str(),
+            "mp4" | "webm" | "mkv" | "mov"
+        )
+    })
 }
 
 fn is_img_file(extension: &std::ffi::OsStr) -> bool {
-    match extension.to_string_lossy().to_string().as_str() {
-        "jpg" => true,
-        "jpeg" => true,
-        "png" => true,
-        "gif" => true,
-        "pnm" => true,
-        "tga" => true,
-        "tiff" => true,
-        "webp" => true,
-        "bmp" => true,
-        "farbfeld" => true,
-        _ => false,
-    }
-}
-
-fn get_random_num(to: f64) -> f64 {
-    let mut rng = rand_hc::Hc128Rng::from_entropy();
-    rng.gen_range(0.0..to)
+    matches!(
+        extension.to_string_lossy().to_lowercase().as_str(),
+        "jpg" | "jpeg" | "png" | "gif" | "pnm" | "tga" | "tiff" | "webp" | "bmp" | "farbfeld"
+    )
+}
+
+/// Parses `WIDTHxHEIGHT`, e.g. `2560x1440`
+pub fn parse_size(s: &str) -> std::option::Option<(u32, u32)> {
+    let (width, height) = s.trim().split_once('x')?;
+    let width = width.trim().parse().ok()?;
+    let height = height.trim().parse().ok()?;
+
+    Some((width, height))
+}
+
+/// Parses a size in MiB into bytes, `None` unless it is a number that fits
+pub fn parse_mib(s: &str) -> std::option::Option<u64> {
+    s.parse::<u64>().ok()?.checked_mul(1024 * 1024)
+}
+
+/// Runs the command to completion, a failure carries the decoded stderr of the command
+pub(crate) fn run_command(
+    command: &mut std::process::Command,
+) -> Result<std::process::Output, std::io::Error> {
+    debug!("Running {:?}", command);
+    let output = command.output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let stderr = stderr.trim();
+
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "{:?} failed with {}: {}",
+            command, output.status, stderr
+        )));
+    }
+    if !stderr.is_empty() {
+        debug!("{:?}: {}", command.get_program(), stderr);
+    }
+
+    Ok(output)
+}
+
+/// `$XDG_CACHE_HOME/wallrustler`, falling back to `~/.cache/wallrustler`
+pub fn get_cache_dir() -> std::path::PathBuf {
+    #[cfg(target_os = "windows")]
+    let cache_dir = std::env::var_os("LOCALAPPDATA").map(std::path::PathBuf::from);
+    #[cfg(not(target_os = "windows"))]
+    let cache_dir = std::env::var_os("XDG_CACHE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(std::path::PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".cache"))
+        });
+
+    cache_dir
+        .unwrap_or_else(std::env::temp_dir)
+        .join(env!("CARGO_PKG_NAME"))
+}
+
+/// 64 bit FNV-1a, unlike `DefaultHasher` it gives the same hashes across Rust releases, so
+/// names derived from them stay valid after an upgrade
+#[derive(Debug, Clone, Copy)]
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher(0xcbf29ce484222325)
+    }
+}
+
+impl std::hash::Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    // Integers are hashed little endian on every platform
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+pub fn hash_path(path: &std::path::Path, modified: u64) -> u64 {
+    use std::hash::Hasher;
+    let mut hasher = StableHasher::default();
+    // The bytes of the path, its `Hash` implementation isn't promised to stay the same
+    hasher.write(path.as_os_str().as_encoded_bytes());
+    hasher.write_u64(modified);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stable_hashes() {
+        use std::hash::Hasher;
+        let fnv = |bytes: &[u8]| {
+            let mut hasher = StableHasher::default();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        // Reference values of FNV-1a
+        assert_eq!(fnv(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv(b"foobar"), 0x85944171f73967e8);
+
+        let path = std::path::Path::new("/wallpapers/sea.jpg");
+        assert_eq!(hash_path(path, 1), hash_path(path, 1));
+        assert_ne!(hash_path(path, 1), hash_path(path, 2));
+        assert_eq!(hash_path(path, 1), 0x25e4f956473ea101);
+    }
+
+    #[test]
+    fn mib_sizes_are_checked() {
+        assert_eq!(parse_mib("0"), Some(0));
+        assert_eq!(parse_mib("3"), Some(3 * 1024 * 1024));
+        assert_eq!(parse_mib("-3"), None);
+        assert_eq!(parse_mib(&(u64::MAX / 1024).to_string()), None);
+        assert!(matches!(
+            parse_option(format!("--log-max-size={}", u64::MAX)),
+            Err(Error::InvalidOption(_))
+        ));
+        assert!(matches!(
+            parse_option(format!("--source-max-size={}", u64::MAX / 2)),
+            Err(Error::InvalidOption(_))
+        ));
+        assert!(matches!(
+            parse_option(format!("--cache-max-size={}", u64::MAX / 2)),
+            Err(Error::InvalidOption(_))
+        ));
+        assert_eq!(
+            parse_option("--log-max-size=2".to_string()).unwrap(),
+            Option::LogMaxSize(2 * 1024 * 1024)
+        );
+    }
+
+    #[test]
+    fn broken_wallpapers_are_never_picked() {
+        let mut wallpaper = Wallpaper::new("a.jpg".to_string());
+        wallpaper.count = 2;
+        assert!(wallpaper.weight(2) > 0.0);
+        assert!(wallpaper.weight(0) > 0.0);
+
+        wallpaper.metadata.broken = Some("unexpected end of file".to_string());
+        assert_eq!(wallpaper.weight(2), 0.0);
+        wallpaper.rating = Some(5);
+        assert_eq!(wallpaper.weight(10), 0.0);
+    }
+
+    #[test]
+    fn resolution_filter() {
+        let wallpaper = |width, height| {
+            let mut wallpaper = Wallpaper::new("a.jpg".to_string());
+            (wallpaper.metadata.width, wallpaper.metadata.height) = (width, height);
+            wallpaper
+        };
+        let output = |width, height| Output {
+            name: "DP-1".to_string(),
+            width,
+            height,
+        };
+        let filter = |min_resolution, aspect_tolerance| ResolutionFilter {
+            min_resolution,
+            aspect_tolerance,
+        };
+
+        let empty = ResolutionFilter::default();
+        assert!(empty.matches(&wallpaper(0, 0), None));
+        // Unknown dimensions never match a rule
+        assert!(!filter(Some(MinResolution::Size(1, 1)), None).matches(&wallpaper(0, 0), None));
+
+        let size = filter(Some(MinResolution::Size(1920, 1080)), None);
+        assert!(size.matches(&wallpaper(1920, 1080), None));
+        assert!(!size.matches(&wallpaper(1919, 1080), None));
+        assert!(!size.matches(&wallpaper(3840, 1079), Some(&output(640, 480))));
+
+        let output_size = filter(Some(MinResolution::Output), None);
+        assert!(output_size.matches(&wallpaper(1280, 720), None));
+        assert!(output_size.matches(&wallpaper(2560, 1440), Some(&output(2560, 1440))));
+        assert!(!output_size.matches(&wallpaper(1920, 1080), Some(&output(2560, 1440))));
+
+        let aspect = filter(None, Some(0.1));
+        assert!(aspect.matches(&wallpaper(1920, 1200), Some(&output(1920, 1080))));
+        assert!(!aspect.matches(&wallpaper(1080, 1920), Some(&output(1920, 1080))));
+        assert!(aspect.matches(&wallpaper(1080, 1920), Some(&output(1080, 1920))));
+        assert!(aspect.matches(&wallpaper(1080, 1920), None));
+        assert!(aspect.matches(&wallpaper(1080, 1920), Some(&output(0, 0))));
+    }
 }
@@ -1,9 +1,26 @@
+use crate::Output;
+
+/// swww-daemon is restarted this long after a change, once the transition is over
+const SWWW_RESTART_DELAY: std::time::Duration = std::time::Duration::from_secs(10);
+/// Wallpapers hyprpaper no longer shows are unloaded this long after a change
+#[cfg(feature = "hyprpaper")]
+const HYPRPAPER_UNLOAD_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+/// How long a started wallpaper daemon gets to become ready
+const STARTUP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+/// Long enough for the running daemon to finish rendering a wallpaper and save its state
+const TAKEOVER_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const STARTUP_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
 pub struct WallSetter {
     child: Option<std::process::Child>,
     program: WallSetterProgram,
     restart_swww: bool,
+    /// Deferred rather than slept through, carried out by `run_pending`
+    swww_restart_at: Option<std::time::Instant>,
     #[cfg(feature = "hyprpaper")]
     hyprpaper: Option<std::process::Child>,
+    #[cfg(feature = "hyprpaper")]
+    hyprpaper_unload_at: Option<std::time::Instant>,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -14,14 +31,23 @@ pub enum WallSetterProgram {
     HYPRPAPER,
 }
 
+impl Default for WallSetter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl WallSetter {
     pub fn new() -> WallSetter {
         WallSetter {
             child: None,
             program: WallSetterProgram::SWWW,
             restart_swww: false,
+            swww_restart_at: None,
             #[cfg(feature = "hyprpaper")]
             hyprpaper: None,
+            #[cfg(feature = "hyprpaper")]
+            hyprpaper_unload_at: None,
         }
     }
 
@@ -53,11 +79,7 @@ impl WallSetter {
             match &self.program {
                 WallSetterProgram::SWWW => {
                     self.set_wallpaper_wayland(wallpaper)?;
-                    if self.restart_swww {
-                        std::thread::sleep(std::time::Duration::from_secs(10));
-                        self.kill_swww_daemon()?;
-                        self.swww_daemon_init()?;
-                    }
+                    self.swww_restart_if_enabled()?;
                 }
                 WallSetterProgram::PLASMA => {
                     self.set_wallpaper_wayland(wallpaper)?;
@@ -65,57 +87,167 @@ impl WallSetter {
                 #[cfg(feature = "hyprpaper")]
                 WallSetterProgram::HYPRPAPER => {
                     self.hyprpaper_preload(wallpaper)?;
-                    self.hyprpaper_set_wallpaper(wallpaper)?;
-                    std::thread::sleep(std::time::Duration::from_secs(2));
-                    self.hyprpaper_unload_all()?;
+                    self.hyprpaper_set_wallpaper(wallpaper, None)?;
+                    self.hyprpaper_unload_at =
+                        Some(std::time::Instant::now() + HYPRPAPER_UNLOAD_DELAY);
                 }
             }
         } else {
-            self.set_wallpaper_x11(wallpaper)?;
+            self.set_wallpaper_x11(&[wallpaper])?;
         }
 
         Ok(())
     }
 
+    /// Sets a separate wallpaper per output, plasma-apply-wallpaperimage has no notion of
+    /// outputs so the first wallpaper is applied everywhere
+    pub fn set_output_wallpapers(
+        &mut self,
+        wallpapers: &[(Output, std::path::PathBuf)],
+    ) -> Result<(), std::io::Error> {
+        let Some((_, first_wallpaper)) = wallpapers.first() else {
+            return Ok(());
+        };
+
+        if self.is_running_under_wayland() {
+            match &self.program {
+                WallSetterProgram::SWWW => {
+                    for (output, wallpaper) in wallpapers {
+                        self.swww_set_wallpaper(wallpaper, Some(&output.name))?;
+                    }
+                    self.swww_restart_if_enabled()?;
+                }
+                WallSetterProgram::PLASMA => {
+                    self.plasma_set_wallpaper(first_wallpaper)?;
+                }
+                #[cfg(feature = "hyprpaper")]
+                WallSetterProgram::HYPRPAPER => {
+                    for (output, wallpaper) in wallpapers {
+                        self.hyprpaper_preload(wallpaper)?;
+                        self.hyprpaper_set_wallpaper(wallpaper, Some(&output.name))?;
+                    }
+                    self.hyprpaper_unload_at =
+                        Some(std::time::Instant::now() + HYPRPAPER_UNLOAD_DELAY);
+                }
+            }
+        } else {
+            let wallpapers: Vec<&std::path::Path> = wallpapers
+                .iter()
+                .map(|(_, wallpaper)| wallpaper.as_path())
+                .collect();
+            self.set_wallpaper_x11(&wallpapers)?;
+        }
+
+        Ok(())
+    }
+
+    /// When work deferred after a change is due, for the main loop to wake up
+    pub fn next_deadline(&self) -> Option<std::time::Instant> {
+        #[allow(unused_mut)]
+        let mut deadline = self.swww_restart_at;
+        #[cfg(feature = "hyprpaper")]
+        if let Some(unload_at) = self.hyprpaper_unload_at {
+            deadline = Some(deadline.map_or(unload_at, |deadline| deadline.min(unload_at)));
+        }
+
+        deadline
+    }
+
+    /// Carries out the deferred work that is due
+    pub fn run_pending(&mut self) -> Result<(), std::io::Error> {
+        let now = std::time::Instant::now();
+        if self
+            .swww_restart_at
+            .is_some_and(|restart_at| restart_at <= now)
+        {
+            self.swww_restart_at = None;
+            self.kill_swww_daemon()?;
+            self.swww_daemon_init()?;
+        }
+        #[cfg(feature = "hyprpaper")]
+        if self
+            .hyprpaper_unload_at
+            .is_some_and(|unload_at| unload_at <= now)
+        {
+            self.hyprpaper_unload_at = None;
+            self.hyprpaper_unload_all()?;
+        }
+
+        Ok(())
+    }
+
+    /// Name of the program that sets the wallpapers
+    pub fn backend(&self) -> &'static str {
+        if !self.is_running_under_wayland() {
+            return "feh";
+        }
+        match &self.program {
+            WallSetterProgram::SWWW => "swww",
+            WallSetterProgram::PLASMA => "plasma-apply-wallpaperimage",
+            #[cfg(feature = "hyprpaper")]
+            WallSetterProgram::HYPRPAPER => "hyprpaper",
+        }
+    }
+
+    /// Connected outputs in the order the backend reports them, empty when they can't be queried
+    pub fn get_outputs(&self) -> Vec<Output> {
+        let outputs = if self.is_running_under_wayland() {
+            match &self.program {
+                WallSetterProgram::SWWW => self.swww_query_outputs(),
+                WallSetterProgram::PLASMA => Ok(vec![]),
+                #[cfg(feature = "hyprpaper")]
+                WallSetterProgram::HYPRPAPER => self.hyprctl_query_outputs(),
+            }
+        } else {
+            self.xrandr_query_outputs()
+        };
+
+        outputs.unwrap_or_else(|err| {
+            error!("Unable to query outputs: {err}");
+            vec![]
+        })
+    }
+
+    /// Whether another daemon owns the D-Bus name, unlike a process name this never matches
+    /// short-lived clients such as `wallrustler ctl`
     pub fn is_running(&self) -> bool {
-        let output = std::process::Command::new("pgrep")
-            .arg("-c")
-            .arg(env!("CARGO_PKG_NAME"))
-            .output();
-
-        output
-            .map(|output| {
-                output.status.success()
-                    && std::string::String::from_utf8(output.stdout)
-                        .map(|x| !x.starts_with("1"))
-                        .unwrap_or(false)
-            })
-            .unwrap_or(false)
+        match crate::dbus::owner_pid() {
+            Ok(pid) => pid.is_some(),
+            Err(err) => {
+                debug!("Unable to look for a running instance: {err}");
+                false
+            }
+        }
     }
 
+    /// Stops the running daemon, waiting for it to release the D-Bus name, and then the backend
+    /// daemon it left behind
     pub fn kill(&mut self) -> Result<(), std::io::Error> {
-        let output = std::process::Command::new("pkill")
-            .arg("-o")
-            .arg(env!("CARGO_PKG_NAME"))
-            .output()?;
-
-        if !output.status.success() {
-            eprintln!("{:?}", output.stderr);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("{:?}", output),
-            ));
+        if let Some(pid) = crate::dbus::owner_pid().map_err(std::io::Error::other)? {
+            crate::run_command(std::process::Command::new("kill").arg(pid.to_string()))?;
+            let stopped = wait_until(TAKEOVER_TIMEOUT, || {
+                crate::dbus::owner_pid().is_ok_and(|pid| pid.is_none())
+            });
+            if !stopped {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("process {pid} is still running after {TAKEOVER_TIMEOUT:?}"),
+                ));
+            }
         }
 
+        if !self.is_running_under_wayland() {
+            return Ok(());
+        }
         match &self.program {
-            WallSetterProgram::SWWW => {
+            WallSetterProgram::SWWW if is_process_running("swww-daemon") => {
                 self.kill_swww_daemon()?;
             }
-            WallSetterProgram::PLASMA => {}
             #[cfg(feature = "hyprpaper")]
-            WallSetterProgram::HYPRPAPER => {
+            WallSetterProgram::HYPRPAPER if is_process_running("hyprpaper") => {
                 self.kill_hyprpaper()?;
             }
+            _ => {}
         }
 
         Ok(())
@@ -127,17 +259,7 @@ impl WallSetter {
             child.wait()?;
             self.child = None;
         } else {
-            let output = std::process::Command::new("pkill")
-                .arg("swww-daemon")
-                .output()?;
-
-            if !output.status.success() {
-                eprintln!("{:?}", output.stderr);
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("{:?}", output),
-                ));
-            }
+            crate::run_command(std::process::Command::new("pkill").arg("swww-daemon"))?;
         }
 
         Ok(())
@@ -157,23 +279,65 @@ impl WallSetter {
     }
 
     fn swww_daemon_init(&mut self) -> Result<(), std::io::Error> {
-        std::thread::sleep(std::time::Duration::from_secs(2));
+        // A daemon that was just killed may still hold the socket
+        wait_until(STARTUP_TIMEOUT, || !is_process_running("swww-daemon"));
         self.child = Some(std::process::Command::new("swww-daemon").spawn()?);
-        std::thread::sleep(std::time::Duration::from_secs(2));
+        if !wait_until(STARTUP_TIMEOUT, || {
+            crate::run_command(std::process::Command::new("swww").arg("query")).is_ok()
+        }) {
+            warn!("swww-daemon isn't ready after {STARTUP_TIMEOUT:?}");
+        }
+
+        Ok(())
+    }
+
+    fn swww_restart_if_enabled(&mut self) -> Result<(), std::io::Error> {
+        if self.restart_swww {
+            self.swww_restart_at = Some(std::time::Instant::now() + SWWW_RESTART_DELAY);
+        }
 
         Ok(())
     }
 
-    fn swww_set_wallpaper(&self, wallpaper: &std::path::Path) -> Result<(), std::io::Error> {
-        std::process::Command::new("swww")
-            .arg("img")
-            .arg(wallpaper)
-            .spawn()?
-            .wait()?;
+    fn swww_set_wallpaper(
+        &self,
+        wallpaper: &std::path::Path,
+        output: Option<&str>,
+    ) -> Result<(), std::io::Error> {
+        let mut command = std::process::Command::new("swww");
+        command.arg("img");
+        if let Some(output) = output {
+            command.arg("--outputs").arg(output);
+        }
+        crate::run_command(command.arg(wallpaper))?;
 
         Ok(())
     }
 
+    fn swww_query_outputs(&self) -> Result<Vec<Output>, std::io::Error> {
+        let output = crate::run_command(std::process::Command::new("swww").arg("query"))?;
+
+        Ok(parse_swww_query(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    #[cfg(feature = "hyprpaper")]
+    fn hyprctl_query_outputs(&self) -> Result<Vec<Output>, std::io::Error> {
+        let output = crate::run_command(std::process::Command::new("hyprctl").arg("monitors"))?;
+
+        Ok(parse_hyprctl_monitors(&String::from_utf8_lossy(
+            &output.stdout,
+        )))
+    }
+
+    fn xrandr_query_outputs(&self) -> Result<Vec<Output>, std::io::Error> {
+        let output =
+            crate::run_command(std::process::Command::new("xrandr").arg("--listmonitors"))?;
+
+        Ok(parse_xrandr_monitors(&String::from_utf8_lossy(
+            &output.stdout,
+        )))
+    }
+
     #[cfg(feature = "hyprpaper")]
     fn hyprpaper_init(&mut self) -> Result<(), std::io::Error> {
         let output = std::process::Command::new("pgrep")
@@ -182,7 +346,17 @@ impl WallSetter {
 
         if !output.status.success() {
             self.hyprpaper = Some(std::process::Command::new("hyprpaper").spawn()?);
-            std::thread::sleep(std::time::Duration::from_secs(2));
+            let ready = wait_until(STARTUP_TIMEOUT, || {
+                crate::run_command(
+                    std::process::Command::new("hyprctl")
+                        .arg("hyprpaper")
+                        .arg("listloaded"),
+                )
+                .is_ok()
+            });
+            if !ready {
+                warn!("hyprpaper isn't ready after {STARTUP_TIMEOUT:?}");
+            }
         }
 
         Ok(())
@@ -195,17 +369,7 @@ impl WallSetter {
             hyprpaper.wait()?;
             self.hyprpaper = None;
         } else {
-            let output = std::process::Command::new("pkill")
-                .arg("hyprpaper")
-                .output()?;
-
-            if !output.status.success() {
-                eprintln!("{:?}", output.stderr);
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("{:?}", output),
-                ));
-            }
+            crate::run_command(std::process::Command::new("pkill").arg("hyprpaper"))?;
         }
 
         Ok(())
@@ -213,47 +377,50 @@ impl WallSetter {
 
     #[cfg(feature = "hyprpaper")]
     fn hyprpaper_preload(&self, wallpaper: &std::path::Path) -> Result<(), std::io::Error> {
-        std::process::Command::new("hyprctl")
-            .arg("hyprpaper")
-            .arg("preload")
-            .arg(wallpaper)
-            .spawn()?
-            .wait()?;
+        crate::run_command(
+            std::process::Command::new("hyprctl")
+                .arg("hyprpaper")
+                .arg("preload")
+                .arg(wallpaper),
+        )?;
 
-        println!("preload: {:?}", wallpaper);
+        debug!("Preloaded {:?}", wallpaper);
 
         Ok(())
     }
 
     #[cfg(feature = "hyprpaper")]
     fn hyprpaper_unload_all(&self) -> Result<(), std::io::Error> {
-        std::process::Command::new("hyprctl")
-            .arg("hyprpaper")
-            .arg("unload")
-            .arg("all")
-            .spawn()?
-            .wait()?;
+        crate::run_command(
+            std::process::Command::new("hyprctl")
+                .arg("hyprpaper")
+                .arg("unload")
+                .arg("all"),
+        )?;
 
         Ok(())
     }
 
     #[cfg(feature = "hyprpaper")]
-    fn hyprpaper_set_wallpaper(&self, wallpaper: &std::path::Path) -> Result<(), std::io::Error> {
-        std::process::Command::new("hyprctl")
-            .arg("hyprpaper")
-            .arg("wallpaper")
-            .arg(format!(",{}", wallpaper.display()))
-            .spawn()?
-            .wait()?;
+    fn hyprpaper_set_wallpaper(
+        &self,
+        wallpaper: &std::path::Path,
+        output: Option<&str>,
+    ) -> Result<(), std::io::Error> {
+        crate::run_command(
+            std::process::Command::new("hyprctl")
+                .arg("hyprpaper")
+                .arg("wallpaper")
+                .arg(format!("{},{}", output.unwrap_or(""), wallpaper.display())),
+        )?;
 
         Ok(())
     }
 
     fn plasma_set_wallpaper(&self, wallpaper: &std::path::Path) -> Result<(), std::io::Error> {
-        std::process::Command::new("plasma-apply-wallpaperimage")
-            .arg(wallpaper)
-            .spawn()?
-            .wait()?;
+        crate::run_command(
+            std::process::Command::new("plasma-apply-wallpaperimage").arg(wallpaper),
+        )?;
 
         Ok(())
     }
@@ -266,27 +433,190 @@ impl WallSetter {
     fn set_wallpaper_wayland(&self, wallpaper: &std::path::Path) -> Result<(), std::io::Error> {
         match &self.program {
             WallSetterProgram::SWWW => {
-                self.swww_set_wallpaper(wallpaper)?;
+                self.swww_set_wallpaper(wallpaper, None)?;
             }
             WallSetterProgram::PLASMA => {
                 self.plasma_set_wallpaper(wallpaper)?;
             }
             #[cfg(feature = "hyprpaper")]
             WallSetterProgram::HYPRPAPER => {
-                self.hyprpaper_set_wallpaper(wallpaper)?;
+                self.hyprpaper_set_wallpaper(wallpaper, None)?;
             }
         }
 
         Ok(())
     }
 
-    fn set_wallpaper_x11(&self, wallpaper: &std::path::Path) -> Result<(), std::io::Error> {
-        std::process::Command::new("feh")
-            .arg("--bg-fill")
-            .arg(wallpaper)
-            .spawn()?
-            .wait()?;
+    /// feh assigns the wallpapers to the monitors in Xinerama order
+    fn set_wallpaper_x11(&self, wallpapers: &[&std::path::Path]) -> Result<(), std::io::Error> {
+        crate::run_command(
+            std::process::Command::new("feh")
+                .arg("--bg-fill")
+                .args(wallpapers),
+        )?;
 
         Ok(())
     }
 }
+
+/// Polls `condition` until it holds or `timeout` passes, returns whether it held
+fn wait_until(timeout: std::time::Duration, condition: impl Fn() -> bool) -> bool {
+    let deadline = std::time::Instant::now() + timeout;
+    while !condition() {
+        if std::time::Instant::now() >= deadline {
+            return false;
+        }
+        std::thread::sleep(STARTUP_POLL_INTERVAL);
+    }
+
+    true
+}
+
+fn is_process_running(name: &str) -> bool {
+    std::process::Command::new("pgrep")
+        .arg("-x")
+        .arg(name)
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Parses `swww query` lines, `: eDP-1: 1920x1080, scale: 1, currently displaying: ...`
+/// (older versions omit the leading colon)
+fn parse_swww_query(stdout: &str) -> Vec<Output> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let (name, rest) = line.trim().trim_start_matches(':').split_once(':')?;
+            let (width, height) = crate::parse_size(rest.split(',').next()?)?;
+            Some(Output {
+                name: name.trim().to_string(),
+                width,
+                height,
+            })
+        })
+        .collect()
+}
+
+/// Parses `hyprctl monitors`, swapping the mode dimensions of outputs rotated by 90 degrees
+#[cfg(feature = "hyprpaper")]
+fn parse_hyprctl_monitors(stdout: &str) -> Vec<Output> {
+    let mut outputs: Vec<Output> = vec![];
+    for line in stdout.lines() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix("Monitor ") {
+            outputs.push(Output {
+                name: name.split_whitespace().next().unwrap_or("").to_string(),
+                width: 0,
+                height: 0,
+            });
+        } else if let Some(output) = outputs.last_mut() {
+            if let Some(transform) = line.strip_prefix("transform: ") {
+                if transform.trim().parse::<u8>().is_ok_and(|t| t % 2 == 1) {
+                    (output.width, output.height) = (output.height, output.width);
+                }
+            } else if output.width == 0 {
+                if let Some((width, height)) = line.split('@').next().and_then(crate::parse_size) {
+                    (output.width, output.height) = (width, height);
+                }
+            }
+        }
+    }
+
+    outputs
+}
+
+/// Parses `xrandr --listmonitors` lines, ` 0: +*eDP-1 1920/344x1080/193+0+0  eDP-1`
+fn parse_xrandr_monitors(stdout: &str) -> Vec<Output> {
+    stdout
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (width, height) = fields.get(2)?.split_once('x')?;
+            Some(Output {
+                name: fields.last()?.to_string(),
+                width: width.split('/').next()?.parse().ok()?,
+                height: height.split('/').next()?.parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(name: &str, width: u32, height: u32) -> Output {
+        Output {
+            name: name.to_string(),
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn parses_swww_query() {
+        let stdout = "\
+: eDP-1: 1920x1080, scale: 1, currently displaying: image: /home/user/a.jpg
+: HDMI-A-1: 1080x1920, scale: 1.5, currently displaying: color: 000000
+DP-2: 2560x1440, scale: 1, currently displaying: image: /home/user/b: c.jpg
+swww-daemon is not running
+";
+        assert_eq!(
+            parse_swww_query(stdout),
+            [
+                output("eDP-1", 1920, 1080),
+                output("HDMI-A-1", 1080, 1920),
+                output("DP-2", 2560, 1440),
+            ]
+        );
+    }
+
+    #[cfg(feature = "hyprpaper")]
+    #[test]
+    fn parses_hyprctl_monitors() {
+        let stdout = "\
+Monitor eDP-1 (ID 0):
+\t1920x1080@60.00800 at 0x0
+\tdescription: BOE 0x095F
+\tmake: BOE
+\tscale: 1.00
+\ttransform: 0
+\tfocused: yes
+
+Monitor DP-2 (ID 1):
+\t2560x1440@143.97200 at 1920x0
+\tdescription: Dell Inc. DELL S2721DGF 4x1
+\ttransform: 1
+\tfocused: no
+
+Monitor HDMI-A-1 (ID 2):
+\t1920x1200@59.95000 at 4480x0
+\ttransform: 6
+";
+        assert_eq!(
+            parse_hyprctl_monitors(stdout),
+            [
+                output("eDP-1", 1920, 1080),
+                // Rotated by 90 degrees
+                output("DP-2", 1440, 2560),
+                // Flipped
+                output("HDMI-A-1", 1920, 1200),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_xrandr_monitors() {
+        let stdout = "\
+Monitors: 2
+ 0: +*eDP-1 1920/344x1080/193+0+0  eDP-1
+ 1: +HDMI-1 1080/598x1920/336+1920+0  HDMI-1
+";
+        assert_eq!(
+            parse_xrandr_monitors(stdout),
+            [output("eDP-1", 1920, 1080), output("HDMI-1", 1080, 1920)]
+        );
+        assert_eq!(parse_xrandr_monitors("Monitors: 0\n"), []);
+    }
+}
@@ -0,0 +1,221 @@
+use crate::rotator::{Extension, Pick};
+use crate::Output;
+use std::os::unix::process::CommandExt;
+
+/// How often playing live wallpapers check whether they should be paused
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Plays video and animated wallpapers, with mpvpaper on Wayland and xwinwrap + mpv on X11
+pub struct LivePlayer {
+    children: Vec<std::process::Child>,
+    paused: bool,
+    pause_policy: PausePolicy,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct PausePolicy {
+    pub on_battery: bool,
+    /// Pause while the load average per core is above this
+    pub max_load: Option<f64>,
+}
+
+impl PausePolicy {
+    /// An unknown load never pauses playback
+    fn should_pause(&self, on_battery: bool, load_per_core: Option<f64>) -> bool {
+        let overloaded = self
+            .max_load
+            .zip(load_per_core)
+            .is_some_and(|(max_load, load)| load > max_load);
+
+        (self.on_battery && on_battery) || overloaded
+    }
+}
+
+impl Default for PausePolicy {
+    fn default() -> Self {
+        PausePolicy {
+            on_battery: true,
+            max_load: None,
+        }
+    }
+}
+
+impl LivePlayer {
+    pub fn new(pause_policy: PausePolicy) -> LivePlayer {
+        LivePlayer {
+            children: vec![],
+            paused: false,
+            pause_policy,
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        !self.children.is_empty()
+    }
+
+    /// Replaces whatever is playing, `None` as output plays on all of them. xwinwrap covers the
+    /// whole X screen, so only the first wallpaper is played there
+    pub fn play(
+        &mut self,
+        wallpapers: &[(Option<Output>, std::path::PathBuf)],
+    ) -> Result<(), std::io::Error> {
+        self.stop()?;
+
+        let wayland = std::env::var("WAYLAND_DISPLAY").is_ok();
+        let wallpapers = if wayland {
+            wallpapers
+        } else {
+            &wallpapers[..wallpapers.len().min(1)]
+        };
+        for (output, wallpaper) in wallpapers {
+            info!("Playing {:?}", wallpaper);
+            let mut command = if wayland {
+                let mut command = std::process::Command::new("mpvpaper");
+                command
+                    .arg("--auto-pause")
+                    .arg("-o")
+                    .arg("no-audio loop-file=inf")
+                    .arg(output.as_ref().map_or("ALL", |output| output.name.as_str()))
+                    .arg(wallpaper);
+                command
+            } else {
+                let mut command = std::process::Command::new("xwinwrap");
+                command
+                    .args(["-fs", "-ni", "-s", "-nf", "-b", "-un", "-ov", "-fdt"])
+                    .args(["--", "mpv", "-wid", "WID", "--loop-file=inf", "--no-audio"])
+                    .args(["--no-osc", "--no-osd-bar", "--no-input-default-bindings"])
+                    .arg(wallpaper);
+                command
+            };
+            // A separate process group, so the player and its children are paused together
+            self.children.push(command.process_group(0).spawn()?);
+        }
+        self.paused = false;
+        self.apply_pause_policy()
+    }
+
+    /// Kills the whole process group of every player, xwinwrap would leave mpv running otherwise
+    pub fn stop(&mut self) -> Result<(), std::io::Error> {
+        // Unreaped leaders keep their group alive, so the kill reaches stopped groups as well
+        self.signal("KILL")?;
+        self.paused = false;
+        for mut child in self.children.drain(..) {
+            child.wait()?;
+        }
+
+        Ok(())
+    }
+
+    /// Pauses or resumes playback depending on the power source and CPU load
+    pub fn apply_pause_policy(&mut self) -> Result<(), std::io::Error> {
+        if !self.is_playing() {
+            return Ok(());
+        }
+
+        let pause = self
+            .pause_policy
+            .should_pause(crate::power::on_battery(), crate::power::load_per_core());
+        if pause != self.paused {
+            info!(
+                "{} live wallpaper",
+                if pause { "Pausing" } else { "Resuming" }
+            );
+            self.signal(if pause { "STOP" } else { "CONT" })?;
+            self.paused = pause;
+        }
+
+        Ok(())
+    }
+
+    fn signal(&self, signal: &str) -> Result<(), std::io::Error> {
+        for child in self.children.iter() {
+            crate::run_command(
+                std::process::Command::new("kill")
+                    .arg(format!("-{signal}"))
+                    .arg("--")
+                    .arg(format!("-{}", child.id())),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Plays the live picks, and stops playing once a change has none
+impl Extension for LivePlayer {
+    fn show(&mut self, picks: &[Pick]) -> Vec<usize> {
+        let live: Vec<usize> = (0..picks.len())
+            .filter(|&index| {
+                picks[index]
+                    .entry
+                    .is_some_and(|wallpaper| wallpaper.is_live())
+            })
+            .collect();
+        let result = if live.is_empty() {
+            self.stop()
+        } else {
+            let live_picks: Vec<(Option<Output>, std::path::PathBuf)> = live
+                .iter()
+                .map(|&index| {
+                    let pick = &picks[index];
+                    (pick.output.cloned(), pick.wallpaper.to_path_buf())
+                })
+                .collect();
+            self.play(&live_picks)
+        };
+        if let Err(err) = result {
+            error!("Unable to play the live wallpaper: {err}");
+        }
+
+        live
+    }
+
+    fn next_deadline(&self) -> Option<std::time::Instant> {
+        self.is_playing()
+            .then(|| std::time::Instant::now() + POLL_INTERVAL)
+    }
+
+    fn run_pending(&mut self) {
+        if let Err(err) = self.apply_pause_policy() {
+            error!("Unable to pause live wallpaper: {err}");
+        }
+    }
+}
+
+impl Drop for LivePlayer {
+    fn drop(&mut self) {
+        if let Err(err) = self.stop() {
+            error!("Unable to stop live wallpaper: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pause_policy() {
+        let default = PausePolicy::default();
+        assert!(default.should_pause(true, Some(8.0)));
+        assert!(!default.should_pause(false, Some(8.0)));
+
+        let policy = PausePolicy {
+            on_battery: false,
+            max_load: Some(0.8),
+        };
+        assert!(!policy.should_pause(true, Some(0.5)));
+        assert!(!policy.should_pause(false, Some(0.8)));
+        assert!(policy.should_pause(false, Some(0.9)));
+        // Unknown load
+        assert!(!policy.should_pause(false, None));
+
+        let both = PausePolicy {
+            on_battery: true,
+            max_load: Some(0.8),
+        };
+        assert!(both.should_pause(true, None));
+        assert!(both.should_pause(false, Some(1.5)));
+        assert!(!both.should_pause(false, Some(0.1)));
+    }
+}
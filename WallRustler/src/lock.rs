@@ -0,0 +1,425 @@
+use crate::rotator::{ChangeContext, Extension, Pick};
+
+/// Longest side of the copy the lock screen blur is computed on, lock screens scale it back up
+const BLUR_SIZE: u32 = 960;
+pub const DEFAULT_BLUR_SIGMA: f32 = 8.0;
+const LOCK_IMAGE_FILE_NAME: &str = "lockscreen.png";
+
+/// Lock screens pointed at the lock image
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LockTarget {
+    /// `image=` in `~/.config/swaylock/config`
+    Swaylock,
+    /// `path =` of the `background` blocks in `~/.config/hypr/hyprlock.conf`
+    Hyprlock,
+    /// The image wallpaper plugin of the Plasma lock screen, through kwriteconfig
+    Kde,
+    /// `background=` in `theme.conf.user` of the current SDDM theme, which has to be writable
+    /// and the image readable by the sddm user
+    Sddm,
+}
+
+impl LockTarget {
+    pub fn parse(s: &str) -> Option<LockTarget> {
+        match s {
+            "swaylock" => Some(LockTarget::Swaylock),
+            "hyprlock" => Some(LockTarget::Hyprlock),
+            "kde" | "plasma" => Some(LockTarget::Kde),
+            "sddm" => Some(LockTarget::Sddm),
+            _ => None,
+        }
+    }
+
+    /// Points the lock screen at `image_path`, leaving the configuration alone when it already is
+    fn apply(&self, image_path: &std::path::Path) -> Result<(), std::io::Error> {
+        let image = image_path.to_string_lossy();
+        match self {
+            LockTarget::Swaylock => {
+                update_file(&config_dir()?.join("swaylock").join("config"), |config| {
+                    set_ini_key(config, None, "image", &image)
+                })
+            }
+            LockTarget::Hyprlock => update_file(
+                &config_dir()?.join("hypr").join("hyprlock.conf"),
+                |config| set_hyprlock_path(config, &image),
+            ),
+            LockTarget::Kde => {
+                let url = format!("file://{image}");
+                let args = [
+                    "--file",
+                    "kscreenlockerrc",
+                    "--group",
+                    "Greeter",
+                    "--group",
+                    "Wallpaper",
+                    "--group",
+                    "org.kde.image",
+                    "--group",
+                    "General",
+                ];
+                for key in ["Image", "PreviewImage"] {
+                    // Plasma 5 only ships kwriteconfig5
+                    let result = crate::run_command(
+                        std::process::Command::new("kwriteconfig6")
+                            .args(args)
+                            .args(["--key", key, &url]),
+                    );
+                    match result {
+                        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                            crate::run_command(
+                                std::process::Command::new("kwriteconfig5")
+                                    .args(args)
+                                    .args(["--key", key, &url]),
+                            )?;
+                        }
+                        result => {
+                            result?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            LockTarget::Sddm => update_file(
+                &std::path::Path::new("/usr/share/sddm/themes")
+                    .join(sddm_theme())
+                    .join("theme.conf.user"),
+                |config| set_ini_key(config, Some("General"), "background", &image),
+            ),
+        }
+    }
+}
+
+/// A copy of the current wallpaper, optionally blurred, kept at a stable path for lock screens
+#[derive(Debug, PartialEq, Clone)]
+pub struct LockScreen {
+    pub image_path: std::path::PathBuf,
+    /// Gaussian blur sigma, in pixels of a copy scaled down to `BLUR_SIZE`
+    pub blur: Option<f32>,
+    pub targets: Vec<LockTarget>,
+}
+
+impl LockScreen {
+    pub fn default_image_path() -> std::path::PathBuf {
+        crate::get_cache_dir().join(LOCK_IMAGE_FILE_NAME)
+    }
+
+    /// Writes the lock image of `wallpaper` and updates the targets, a failing target doesn't
+    /// keep the others from being updated
+    pub fn apply(&self, wallpaper: &std::path::Path) -> Result<(), std::io::Error> {
+        if crate::is_video_file(wallpaper) {
+            debug!("Keeping the lock image, {:?} is a video", wallpaper);
+            return Ok(());
+        }
+
+        let mut img = image::open(wallpaper).map_err(std::io::Error::other)?;
+        img.apply_orientation(crate::metadata::read_orientation(wallpaper));
+        if let Some(sigma) = self.blur {
+            let (width, height) = (img.width(), img.height());
+            let small = img.thumbnail(BLUR_SIZE, BLUR_SIZE);
+            let small = image::imageops::blur(&small.to_rgb8(), sigma);
+            img = image::DynamicImage::ImageRgb8(image::imageops::resize(
+                &small,
+                width,
+                height,
+                image::imageops::FilterType::Triangle,
+            ));
+        }
+
+        // Written next to it and renamed, so a lock screen never reads a partial image
+        std::fs::create_dir_all(
+            self.image_path
+                .parent()
+                .unwrap_or(std::path::Path::new(".")),
+        )?;
+        let mut partial_path = self.image_path.clone().into_os_string();
+        partial_path.push(".partial");
+        img.save_with_format(&partial_path, image::ImageFormat::Png)
+            .map_err(std::io::Error::other)?;
+        std::fs::rename(&partial_path, &self.image_path)?;
+        debug!("Wrote the lock image of {:?}", wallpaper);
+
+        for target in &self.targets {
+            if let Err(err) = target.apply(&self.image_path) {
+                error!("Unable to update the {target:?} lock screen: {err}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Updates the lock screen with the first wallpaper of every change
+impl Extension for LockScreen {
+    fn changed(&mut self, _change: &mut ChangeContext, picks: &[Pick]) {
+        if let Some(pick) = picks.first() {
+            if let Err(err) = self.apply(pick.wallpaper) {
+                error!(
+                    "Unable to update the lock screen with {:?}: {err}",
+                    pick.wallpaper
+                );
+            }
+        }
+    }
+}
+
+fn config_dir() -> Result<std::path::PathBuf, std::io::Error> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".config"))
+        })
+        .ok_or_else(|| std::io::Error::other("Neither XDG_CONFIG_HOME nor HOME is set"))
+}
+
+/// Rewrites the file through `update`, creating it when missing and only writing on changes
+fn update_file(
+    path: &std::path::Path,
+    update: impl FnOnce(&str) -> String,
+) -> Result<(), std::io::Error> {
+    let config = match std::fs::read_to_string(path) {
+        Ok(config) => config,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    let updated = update(&config);
+    if updated != config {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, updated)?;
+        info!("Pointed {:?} at the lock image", path);
+    }
+
+    Ok(())
+}
+
+/// Sets `key=value` in `section`, or before the first section when `None`, replacing every
+/// existing assignment and appending it otherwise
+fn set_ini_key(config: &str, section: Option<&str>, key: &str, value: &str) -> String {
+    let line = format!("{key}={value}");
+    let mut current_section = None;
+    let mut found = false;
+    let mut lines: Vec<String> = vec![];
+    let mut section_end = None;
+    for config_line in config.lines() {
+        let trimmed = config_line.trim();
+        if let Some(name) = trimmed.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+            if current_section == section && section_end.is_none() {
+                section_end = Some(lines.len());
+            }
+            current_section = Some(name);
+        }
+        let is_key = trimmed
+            .split_once('=')
+            .is_some_and(|(name, _)| name.trim() == key);
+        if current_section == section && is_key {
+            found = true;
+            lines.push(line.clone());
+        } else {
+            lines.push(config_line.to_string());
+        }
+    }
+
+    if !found {
+        match (section_end, section) {
+            // Before the blank lines separating the next section
+            (Some(mut end), _) => {
+                while end > 0 && lines[end - 1].trim().is_empty() {
+                    end -= 1;
+                }
+                lines.insert(end, line);
+            }
+            (None, Some(section)) if current_section != Some(section) => {
+                if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+                    lines.push(String::new());
+                }
+                lines.push(format!("[{section}]"));
+                lines.push(line);
+            }
+            (None, _) => lines.push(line),
+        }
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// Sets `path` in every top-level `background` block, adding one when there is none
+fn set_hyprlock_path(config: &str, path: &str) -> String {
+    let mut lines: Vec<String> = vec![];
+    let mut depth = 0;
+    // Index of the opening line and whether `path` was set, while inside a background block
+    let mut background: Option<(usize, bool)> = None;
+    let mut found = false;
+    // Whether the previous line was `background` with the brace still to come
+    let mut brace_pending = false;
+    for config_line in config.lines() {
+        let trimmed = config_line.split('#').next().unwrap_or("").trim();
+        let indent = &config_line[..config_line.len() - config_line.trim_start().len()];
+        let opens_background = if brace_pending && !trimmed.is_empty() {
+            brace_pending = false;
+            trimmed == "{"
+        } else if depth == 0 && trimmed == "background" {
+            brace_pending = true;
+            false
+        } else {
+            depth == 0 && trimmed.starts_with("background") && trimmed.ends_with('{')
+        };
+        if opens_background {
+            background = Some((lines.len(), false));
+            found = true;
+        } else if depth == 1
+            && background.is_some()
+            && trimmed
+                .split_once('=')
+                .is_some_and(|(name, _)| name.trim() == "path")
+        {
+            lines.push(format!("{indent}path = {path}"));
+            background = background.map(|(start, _)| (start, true));
+            continue;
+        }
+
+        depth += trimmed.matches('{').count();
+        depth = depth.saturating_sub(trimmed.matches('}').count());
+        lines.push(config_line.to_string());
+        if depth == 0 {
+            if let Some((start, false)) = background.take() {
+                lines.insert(start + 1, format!("    path = {path}"));
+            }
+        }
+    }
+
+    if !found {
+        if lines.last().is_some_and(|line| !line.trim().is_empty()) {
+            lines.push(String::new());
+        }
+        lines.push("background {".to_string());
+        lines.push(format!("    path = {path}"));
+        lines.push("}".to_string());
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// `Current` of the `[Theme]` section in the SDDM configuration, `breeze` when unset
+fn sddm_theme() -> String {
+    let mut config_paths = vec![std::path::PathBuf::from("/etc/sddm.conf")];
+    if let Ok(dir_entries) = std::fs::read_dir("/etc/sddm.conf.d") {
+        let mut paths: Vec<std::path::PathBuf> = dir_entries
+            .filter_map(|dir_entry| dir_entry.ok().map(|dir_entry| dir_entry.path()))
+            .collect();
+        paths.sort();
+        config_paths.extend(paths);
+    }
+
+    // Later files override earlier ones
+    let mut theme = None;
+    for config in config_paths
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+    {
+        let mut section = "";
+        for line in config.lines().map(str::trim) {
+            if let Some(name) = line.strip_prefix('[').and_then(|s| s.strip_suffix(']')) {
+                section = name;
+            } else if let Some(("Current", value)) =
+                line.split_once('=').map(|(key, value)| (key.trim(), value))
+            {
+                if section == "Theme" && !value.trim().is_empty() {
+                    theme = Some(value.trim().to_string());
+                }
+            }
+        }
+    }
+
+    theme.unwrap_or_else(|| "breeze".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sets_ini_keys() {
+        let config = "[General]\nbackground=old.png\ntype=image\n\n[Theme]\nCurrent=x\n";
+        assert_eq!(
+            set_ini_key(config, Some("General"), "background", "new.png"),
+            "[General]\nbackground=new.png\ntype=image\n\n[Theme]\nCurrent=x\n"
+        );
+        // Added at the end of the section, before the blank line separating the next one
+        assert_eq!(
+            set_ini_key(
+                "[General]\ntype=image\n\n[Theme]\n",
+                Some("General"),
+                "background",
+                "a.png"
+            ),
+            "[General]\ntype=image\nbackground=a.png\n\n[Theme]\n"
+        );
+        // Only in the given section
+        assert_eq!(
+            set_ini_key(
+                "[Other]\nbackground=b.png\n",
+                Some("General"),
+                "background",
+                "a.png"
+            ),
+            "[Other]\nbackground=b.png\n\n[General]\nbackground=a.png\n"
+        );
+        assert_eq!(
+            set_ini_key("", Some("General"), "background", "a.png"),
+            "[General]\nbackground=a.png\n"
+        );
+        // Comments are kept and never taken for the key
+        assert_eq!(
+            set_ini_key(
+                "# background=c.png\nbackground = b.png\n",
+                None,
+                "background",
+                "a.png"
+            ),
+            "# background=c.png\nbackground=a.png\n"
+        );
+        assert_eq!(
+            set_ini_key(
+                "type=image\n[General]\nbackground=b.png\n",
+                None,
+                "background",
+                "a.png"
+            ),
+            "type=image\nbackground=a.png\n[General]\nbackground=b.png\n"
+        );
+    }
+
+    #[test]
+    fn sets_hyprlock_paths() {
+        assert_eq!(
+            set_hyprlock_path(
+                "background {\n    path = old.png # keep?\n    blur_passes = 2\n}\n",
+                "a.png"
+            ),
+            "background {\n    path = a.png\n    blur_passes = 2\n}\n"
+        );
+        // Every top-level block, not the nested ones
+        assert_eq!(
+            set_hyprlock_path(
+                "background {\n    monitor = DP-1\n    inner {\n        path = x\n    }\n}\nbackground {\n    path = b.png\n}\n",
+                "a.png"
+            ),
+            "background {\n    path = a.png\n    monitor = DP-1\n    inner {\n        path = x\n    }\n}\nbackground {\n    path = a.png\n}\n"
+        );
+        // The brace on the next line
+        assert_eq!(
+            set_hyprlock_path("background\n{\n    path = b.png\n}\n", "a.png"),
+            "background\n{\n    path = a.png\n}\n"
+        );
+        assert_eq!(
+            set_hyprlock_path("background\n{\n    color = rgb(0, 0, 0)\n}\n", "a.png"),
+            "background\n{\n    path = a.png\n    color = rgb(0, 0, 0)\n}\n"
+        );
+        // Comments and other blocks don't count
+        assert_eq!(
+            set_hyprlock_path("# background {\ninput-field {\n    path = x\n}\n", "a.png"),
+            "# background {\ninput-field {\n    path = x\n}\n\nbackground {\n    path = a.png\n}\n"
+        );
+    }
+}
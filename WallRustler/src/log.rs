@@ -0,0 +1,333 @@
+//! Log messages go to journald with their source location as structured fields when the
+//! process was started by systemd, to stdout and stderr with timestamps otherwise, and
+//! additionally to the log file when one is configured
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+pub enum Level {
+    Error = 1,
+    Warning,
+    Info,
+    Debug,
+}
+
+/// Old log files kept next to the current one, `<file>.1` being the most recent
+const LOG_FILE_COUNT: u32 = 3;
+
+static MAX_LEVEL: std::sync::atomic::AtomicU8 = std::sync::atomic::AtomicU8::new(Level::Info as u8);
+static LOG_FILE: std::sync::Mutex<Option<LogFile>> = std::sync::Mutex::new(None);
+
+impl Level {
+    /// Accepts the level names used by `RUST_LOG`
+    pub fn parse(s: &str) -> Option<Level> {
+        match s.to_lowercase().as_str() {
+            "error" => Some(Level::Error),
+            "warn" | "warning" => Some(Level::Warning),
+            "info" => Some(Level::Info),
+            "debug" | "trace" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    /// syslog priority, as expected by journald
+    fn priority(&self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warning => 4,
+            Level::Info => 6,
+            Level::Debug => 7,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warning => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+
+    /// More verbose for a positive `verbosity`, less for a negative one, within the levels
+    fn shifted(self, verbosity: i8) -> Level {
+        Level::from_u8(
+            (self as i8)
+                .saturating_add(verbosity)
+                .clamp(Level::Error as i8, Level::Debug as i8) as u8,
+        )
+    }
+
+    fn from_u8(level: u8) -> Level {
+        match level {
+            0 | 1 => Level::Error,
+            2 => Level::Warning,
+            3 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Error, module_path!(), file!(), line!(), format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Warning, module_path!(), file!(), line!(), format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Info, module_path!(), file!(), line!(), format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::log($crate::log::Level::Debug, module_path!(), file!(), line!(), format_args!($($arg)*))
+    };
+}
+
+/// Sets the level from `RUST_LOG`, shifted by `verbosity` (`-v` is 1, `-q` is -1), and opens
+/// the log file, which is rotated once it grows past `max_size` bytes
+pub fn init(
+    verbosity: i8,
+    log_file: Option<&std::path::Path>,
+    max_size: u64,
+) -> Result<(), std::io::Error> {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|rust_log| parse_rust_log(&rust_log))
+        .unwrap_or(Level::Info);
+    set_max_level(level.shifted(verbosity));
+
+    if let Some(path) = log_file {
+        *LOG_FILE.lock().unwrap() = Some(LogFile::open(path, max_size)?);
+    }
+
+    Ok(())
+}
+
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, std::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn max_level() -> Level {
+    Level::from_u8(MAX_LEVEL.load(std::sync::atomic::Ordering::Relaxed))
+}
+
+/// Takes the last directive that is either a bare level or targets this crate, `warn,wallrustler=debug`
+fn parse_rust_log(rust_log: &str) -> Option<Level> {
+    rust_log
+        .split(',')
+        .rev()
+        .find_map(|directive| match directive.split_once('=') {
+            Some((target, level)) if target.trim().starts_with(env!("CARGO_CRATE_NAME")) => {
+                Level::parse(level.trim())
+            }
+            Some(_) => None,
+            None => Level::parse(directive.trim()),
+        })
+}
+
+pub fn log(level: Level, module: &str, file: &str, line: u32, args: std::fmt::Arguments) {
+    if level > max_level() {
+        return;
+    }
+
+    let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+    if let Some(log_file) = LOG_FILE.lock().unwrap().as_mut() {
+        if let Err(err) = log_file.write(&format!("{timestamp} {:<5} {args}\n", level.name())) {
+            eprintln!("Unable to write the log file: {err}");
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    if journal::send(level, module, file, line, &args.to_string()).is_ok() {
+        return;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (module, file, line);
+
+    match level {
+        Level::Error | Level::Warning => eprintln!("{timestamp} {:<5} {args}", level.name()),
+        Level::Info | Level::Debug => println!("{timestamp} {:<5} {args}", level.name()),
+    }
+}
+
+struct LogFile {
+    path: std::path::PathBuf,
+    file: std::fs::File,
+    size: u64,
+    max_size: u64,
+}
+
+impl LogFile {
+    fn open(path: &std::path::Path, max_size: u64) -> Result<LogFile, std::io::Error> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+
+        Ok(LogFile {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            file,
+            max_size,
+        })
+    }
+
+    fn write(&mut self, line: &str) -> Result<(), std::io::Error> {
+        use std::io::Write;
+
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    /// Shifts `<file>.N` to `<file>.N+1`, dropping the oldest, and starts over with an empty file
+    fn rotate(&mut self) -> Result<(), std::io::Error> {
+        let rotated = |n: u32| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{n}"));
+            std::path::PathBuf::from(path)
+        };
+        for n in (1..LOG_FILE_COUNT).rev() {
+            if rotated(n).exists() {
+                std::fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        std::fs::rename(&self.path, rotated(1))?;
+        *self = LogFile::open(&self.path, self.max_size)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod journal {
+    use super::Level;
+    use std::io::Write;
+
+    const JOURNAL_SOCKET_PATH: &str = "/run/systemd/journal/socket";
+
+    static SOCKET: std::sync::OnceLock<Option<std::os::unix::net::UnixDatagram>> =
+        std::sync::OnceLock::new();
+
+    /// Sends the entry using the native journal protocol, fails when the output isn't
+    /// connected to the journal
+    pub fn send(
+        level: Level,
+        module: &str,
+        file: &str,
+        line: u32,
+        message: &str,
+    ) -> Result<(), std::io::Error> {
+        let socket = SOCKET.get_or_init(|| {
+            // systemd sets JOURNAL_STREAM when stdout or stderr are connected to the journal
+            std::env::var_os("JOURNAL_STREAM")?;
+            std::os::unix::net::UnixDatagram::unbound().ok()
+        });
+        let Some(socket) = socket else {
+            return Err(std::io::ErrorKind::NotConnected.into());
+        };
+
+        let mut entry = vec![];
+        write_field(&mut entry, "MESSAGE", message)?;
+        write_field(&mut entry, "PRIORITY", &level.priority().to_string())?;
+        write_field(&mut entry, "SYSLOG_IDENTIFIER", "wallrustler")?;
+        write_field(&mut entry, "CODE_MODULE", module)?;
+        write_field(&mut entry, "CODE_FILE", file)?;
+        write_field(&mut entry, "CODE_LINE", &line.to_string())?;
+        socket.send_to(&entry, JOURNAL_SOCKET_PATH)?;
+
+        Ok(())
+    }
+
+    /// Values containing newlines are length prefixed instead of newline terminated
+    fn write_field(entry: &mut Vec<u8>, name: &str, value: &str) -> Result<(), std::io::Error> {
+        if value.contains('\n') {
+            writeln!(entry, "{name}")?;
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            writeln!(entry, "{value}")
+        } else {
+            writeln!(entry, "{name}={value}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rust_log() {
+        assert_eq!(parse_rust_log("debug"), Some(Level::Debug));
+        assert_eq!(parse_rust_log("WARN"), Some(Level::Warning));
+        assert_eq!(parse_rust_log("warn,wallrustler=debug"), Some(Level::Debug));
+        assert_eq!(
+            parse_rust_log("wallrustler::rotator=trace"),
+            Some(Level::Debug)
+        );
+        assert_eq!(parse_rust_log("error,zbus=debug"), Some(Level::Error));
+        assert_eq!(parse_rust_log("zbus=debug"), None);
+        assert_eq!(parse_rust_log("loud"), None);
+        assert_eq!(parse_rust_log(""), None);
+    }
+
+    #[test]
+    fn verbosity_stays_within_the_levels() {
+        assert_eq!(Level::Info.shifted(0), Level::Info);
+        assert_eq!(Level::Info.shifted(1), Level::Debug);
+        assert_eq!(Level::Info.shifted(-1), Level::Warning);
+        assert_eq!(Level::Info.shifted(-2), Level::Error);
+        assert_eq!(Level::Info.shifted(-5), Level::Error);
+        assert_eq!(Level::Debug.shifted(3), Level::Debug);
+        assert_eq!(Level::Error.shifted(i8::MIN), Level::Error);
+        assert_eq!(Level::Error.shifted(i8::MAX), Level::Debug);
+    }
+
+    #[test]
+    fn log_file_keeps_three_rotations() {
+        let dir = std::env::temp_dir().join(format!("wallrustler-log-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wallrustler.log");
+
+        let mut log_file = LogFile::open(&path, 10).unwrap();
+        for n in 0..6 {
+            log_file.write(&format!("line {n}\n")).unwrap();
+        }
+        drop(log_file);
+        let read = |file_name: &str| std::fs::read_to_string(dir.join(file_name)).ok();
+        let contents = [
+            read("wallrustler.log"),
+            read("wallrustler.log.1"),
+            read("wallrustler.log.2"),
+            read("wallrustler.log.3"),
+            read("wallrustler.log.4"),
+        ];
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            contents,
+            [
+                Some("line 5\n".to_string()),
+                Some("line 4\n".to_string()),
+                Some("line 3\n".to_string()),
+                Some("line 2\n".to_string()),
+                None,
+            ]
+        );
+    }
+}
@@ -2,51 +2,295 @@
 
 #[allow(unused_imports)]
 use std::env;
+use wallrustler::cache;
+use wallrustler::cli::{self, CacheCommand};
+use wallrustler::events::{Event, EventLoop, Signal};
+use wallrustler::hooks::Hooks;
+use wallrustler::index::WallpaperIndex;
+use wallrustler::lan::{self, Follower, Leader};
+use wallrustler::pipeline::{NightDim, Pipeline};
+use wallrustler::rng::SeededRng;
+use wallrustler::rotator::{self, Rotator};
+use wallrustler::schedule::Schedule;
+use wallrustler::source::{self, Source};
+use wallrustler::theme::Theme;
 use wallrustler::wallpaper::WallSetter;
-use wallrustler::{
-    get_wallpapers_from_path, mean_centering_counts, pick_random_wallpaper, print_help,
-    process_args, sync_wallpapers, Error, Option, Wallpaper,
-};
+use wallrustler::watcher::Change;
+use wallrustler::{error, info, log, warn};
+use wallrustler::{get_cache_dir, Error, Option, Output, ResolutionFilter, Wallpaper};
 
+#[cfg(target_os = "linux")]
+use wallrustler::dbus::{Command, CurrentWallpaper, DbusService, Status};
+#[cfg(target_os = "linux")]
+use wallrustler::inhibit::Inhibitor;
+#[cfg(target_os = "linux")]
+use wallrustler::live::{LivePlayer, PausePolicy};
+#[cfg(target_os = "linux")]
+use wallrustler::lock::LockScreen;
+#[cfg(target_os = "linux")]
+use wallrustler::status::{DaemonStatus, StatusFormat};
+#[cfg(target_os = "linux")]
+use wallrustler::systemd;
 #[cfg(target_os = "linux")]
 use wallrustler::wallpaper::WallSetterProgram;
 
-fn main() {
-    #[allow(unused_mut)]
-    let mut wall_setter = WallSetter::new();
-
-    let mut interval = 15 * 60;
+/// Scheduled changes printed by `--dry-run`
+const DRY_RUN_CHANGES: usize = 10;
+/// How often paused rotation checks whether it can resume
+#[cfg(target_os = "linux")]
+const INHIBIT_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
 
-    let options = process_args()
+fn main() {
+    let command = cli::parse_args(env::args().skip(1).collect())
         .map_err(|err| {
             match err {
                 Error::InvalidOption(option) => eprintln!("Provided option {option} is invalid"),
+                Error::MissingValue(option) => eprintln!("Option {option} needs a value"),
+                Error::UnknownCommand(command) => eprintln!("Unknown command {command}"),
                 Error::InvalidOptionsStructure => eprintln!("Invalid input"),
             }
-            print_help();
+            cli::print_help();
             std::process::exit(-1);
         })
         .unwrap();
-    if options.contains(&Option::PrintHelp) {
-        print_help();
+    let options = match command {
+        cli::Command::Run(options) => options,
+        cli::Command::Help => return cli::print_help(),
+        cli::Command::Version => return println!("{}", cli::version()),
+        cli::Command::Completions(shell) => return print!("{}", cli::completions(shell)),
+        cli::Command::Man => return print!("{}", cli::man_page()),
+        cli::Command::Cache(command, max_size) => return manage_cache(command, max_size),
+        #[cfg(target_os = "linux")]
+        cli::Command::Status(format) => return print_status(format),
+        #[cfg(target_os = "linux")]
+        cli::Command::Control(command) => return control(command),
+    };
+
+    #[allow(unused_mut)]
+    let mut wall_setter = WallSetter::new();
+
+    let log_file = options.iter().find_map(|o| match o {
+        Option::LogFile(path) => Some(path.as_path()),
+        _ => None,
+    });
+    let log_max_size = options
+        .iter()
+        .find_map(|o| match o {
+            Option::LogMaxSize(size) => Some(*size),
+            _ => None,
+        })
+        .unwrap_or(1024 * 1024);
+    let verbosity = options
+        .iter()
+        .map(|o| match o {
+            Option::Verbosity(verbosity) => *verbosity,
+            _ => 0,
+        })
+        .sum();
+    if let Err(err) = log::init(verbosity, log_file, log_max_size) {
+        eprintln!("Unable to open the log file: {err}");
         std::process::exit(-1);
     }
+    if let Some(max_size) = options.iter().find_map(|o| match o {
+        Option::CacheMaxSize(size) => Some(*size),
+        _ => None,
+    }) {
+        cache::set_max_size(max_size);
+    }
     #[cfg(target_os = "linux")]
     if options.contains(&Option::RestartSWWW) {
         wall_setter.set_restart_swww(true);
     }
 
-    if let Some(m) = options.iter().find_map(|o| match o {
-        Option::Interval(min) => Some(min),
-        _ => None,
-    }) {
-        interval = m * 60;
+    let schedule = Schedule {
+        interval: options
+            .iter()
+            .find_map(|o| match o {
+                Option::Interval(interval) => Some(*interval),
+                _ => None,
+            })
+            .unwrap_or_default(),
+        align: options.contains(&Option::Align),
+        cron: options
+            .iter()
+            .filter_map(|o| match o {
+                Option::Cron(entry) => Some(entry.clone()),
+                _ => None,
+            })
+            .collect(),
+    };
+    for (index, entry) in schedule.cron.iter().enumerate() {
+        if entry
+            .expr
+            .next_after(chrono::Local::now().naive_local())
+            .is_none()
+        {
+            warn!("Cron expression {} never matches", index + 1);
+        }
+    }
+    if options.contains(&Option::DryRun) {
+        use rand::SeedableRng;
+        // The jitter is reproducible with a seed as well
+        let mut rng: Box<dyn rand::RngCore> = match options.iter().find_map(|o| match o {
+            Option::Seed(seed) => Some(*seed),
+            _ => None,
+        }) {
+            Some(seed) => Box::new(SeededRng::new(seed).next_rng()),
+            None => Box::new(rand_hc::Hc128Rng::from_entropy()),
+        };
+        let mut now = chrono::Local::now().naive_local();
+        for _ in 0..DRY_RUN_CHANGES {
+            let change = schedule.next_change(now, rng.as_mut());
+            let tags = change
+                .entry
+                .and_then(|index| schedule.cron[index].tags.as_ref())
+                .map(|tags| {
+                    let tags: Vec<String> = tags
+                        .include
+                        .iter()
+                        .cloned()
+                        .chain(tags.exclude.iter().map(|tag| format!("-{tag}")))
+                        .collect();
+                    format!(" {}", tags.join(","))
+                })
+                .unwrap_or_default();
+            println!("{}{tags}", change.at.format("%Y-%m-%d %H:%M:%S %a"));
+            now = change.at;
+        }
+        return;
     }
 
+    let tag_filter = options
+        .iter()
+        .find_map(|o| match o {
+            Option::Tags(filter) => Some(filter.clone()),
+            _ => None,
+        })
+        .unwrap_or_default();
+
+    let resolution_filter = ResolutionFilter {
+        min_resolution: options.iter().find_map(|o| match o {
+            Option::MinResolution(min_resolution) => Some(*min_resolution),
+            _ => None,
+        }),
+        aspect_tolerance: options.iter().find_map(|o| match o {
+            Option::AspectTolerance(tolerance) => Some(*tolerance),
+            _ => None,
+        }),
+    };
+
+    let (night_start_hour, night_end_hour) = options
+        .iter()
+        .find_map(|o| match o {
+            Option::NightHours(start, end) => Some((*start, *end)),
+            _ => None,
+        })
+        .unwrap_or((20, 6));
+    let pipeline = Pipeline {
+        fit_mode: options.iter().find_map(|o| match o {
+            Option::Fit(fit_mode) => Some(*fit_mode),
+            _ => None,
+        }),
+        brightness: options
+            .iter()
+            .find_map(|o| match o {
+                Option::Brightness(brightness) => Some(*brightness),
+                _ => None,
+            })
+            .unwrap_or(0),
+        night_dim: options.iter().find_map(|o| match o {
+            Option::NightDim(percent) => Some(NightDim {
+                percent: *percent,
+                start_hour: night_start_hour,
+                end_hour: night_end_hour,
+            }),
+            _ => None,
+        }),
+        grayscale: options.contains(&Option::Grayscale),
+    };
+
+    #[cfg(target_os = "linux")]
+    let inhibitor = Inhibitor {
+        battery_threshold: options.iter().find_map(|o| match o {
+            Option::PauseOnBattery(percent) => Some(*percent),
+            _ => None,
+        }),
+        fullscreen: options.contains(&Option::PauseOnFullscreen),
+        idle: options.contains(&Option::PauseWhenIdle),
+    };
+    #[cfg(target_os = "linux")]
+    let mut inhibited = false;
+
+    #[cfg(target_os = "linux")]
+    let live = options.contains(&Option::Live);
+    #[cfg(not(target_os = "linux"))]
+    let live = false;
+    #[cfg(target_os = "linux")]
+    let live_player = LivePlayer::new(PausePolicy {
+        on_battery: !options.contains(&Option::LiveOnBattery),
+        max_load: options.iter().find_map(|o| match o {
+            Option::LiveMaxLoad(load) => Some(*load),
+            _ => None,
+        }),
+    });
+
+    #[cfg(target_os = "linux")]
+    let lock_screen = {
+        let targets = options.iter().find_map(|o| match o {
+            Option::LockScreen(targets) => Some(targets.clone()),
+            _ => None,
+        });
+        let image_path = options.iter().find_map(|o| match o {
+            Option::LockImage(path) => Some(path.clone()),
+            _ => None,
+        });
+        let blur = options.iter().find_map(|o| match o {
+            Option::LockBlur(sigma) => Some(*sigma),
+            _ => None,
+        });
+        (targets.is_some() || image_path.is_some() || blur.is_some()).then(|| LockScreen {
+            image_path: image_path.unwrap_or_else(LockScreen::default_image_path),
+            blur,
+            targets: targets.unwrap_or_default(),
+        })
+    };
+
+    let theme_dir = options.iter().find_map(|o| match o {
+        Option::ThemeDir(path) => Some(path.clone()),
+        _ => None,
+    });
+    let theme = (options.contains(&Option::Theme) || theme_dir.is_some()).then(|| Theme {
+        output_dir: theme_dir.unwrap_or_else(Theme::default_output_dir),
+        templates_dir: options
+            .iter()
+            .find_map(|o| match o {
+                Option::ThemeTemplates(path) => Some(path.clone()),
+                _ => None,
+            })
+            .or_else(Theme::default_templates_dir),
+    });
+    let hooks = Hooks {
+        pre_change: options.iter().find_map(|o| match o {
+            Option::PreHook(command) => Some(command.clone()),
+            _ => None,
+        }),
+        post_change: options.iter().find_map(|o| match o {
+            Option::PostHook(command) => Some(command.clone()),
+            _ => None,
+        }),
+        timeout: options
+            .iter()
+            .find_map(|o| match o {
+                Option::HookTimeout(secs) => Some(std::time::Duration::from_secs(*secs)),
+                _ => None,
+            })
+            .unwrap_or(Hooks::default().timeout),
+    };
+
     #[cfg(target_os = "linux")]
     if let Ok(val) = env::var("XDG_CURRENT_DESKTOP") {
         if val == "KDE" {
-            println!("KDE detected, switching to plasma-apply-wallpaperimage as wallpaper setting program\nThis behavior can be changed by using --program option");
+            info!("KDE detected, switching to plasma-apply-wallpaperimage as wallpaper setting program\nThis behavior can be changed by using --program option");
             wall_setter.set_program(WallSetterProgram::PLASMA);
         }
     }
@@ -56,7 +300,7 @@ fn main() {
         Option::Program(program) => Some(program),
         _ => None,
     }) {
-        println!("Using {p:?}");
+        info!("Using {p:?}");
         wall_setter.set_program(*p);
     }
 
@@ -68,52 +312,545 @@ fn main() {
         })
         .unwrap();
 
+    #[cfg(target_os = "linux")]
+    if options.contains(&Option::InstallService) {
+        // The options as given, with the directory made absolute for the service
+        let absolute_dir = wallpapers_dir_path
+            .canonicalize()
+            .unwrap_or_else(|_| wallpapers_dir_path.clone());
+        let mut args = vec!["run".to_string()];
+        args.extend(
+            cli::normalize_args(env::args().skip(1).collect())
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|arg| !matches!(arg.as_str(), "run" | "state" | "--install-service" | "--"))
+                .map(|arg| {
+                    if arg.starts_with('-') {
+                        arg
+                    } else {
+                        absolute_dir.to_string_lossy().into_owned()
+                    }
+                }),
+        );
+        match systemd::install_service(&args) {
+            Ok(unit_path) => {
+                println!("Installed {:?}", unit_path);
+                println!("Start it with: systemctl --user enable --now wallrustler.service");
+                println!("The compositor has to import its environment into the user manager, e.g. with dbus-update-activation-environment --systemd --all");
+                return;
+            }
+            Err(err) => {
+                eprintln!("Unable to install the service: {err}");
+                std::process::exit(-1);
+            }
+        }
+    }
+
+    if options.contains(&Option::PrintState) {
+        let wallpapers_state_path = wallpapers_dir_path.join(rotator::STATE_FILE_NAME);
+        let wallpapers = match rotator::load_state(&wallpapers_state_path) {
+            Ok(wallpapers) => wallpapers,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(err) => {
+                error!("Unable to read the state {wallpapers_state_path:?}: {err}");
+                std::process::exit(1);
+            }
+        };
+        let mut wallpapers = WallpaperIndex::new(wallpapers);
+        wallpapers.sync(wallpapers_dir_path);
+        let wallpapers: Vec<&Wallpaper> = wallpapers
+            .wallpapers()
+            .iter()
+            .filter(|wallpaper| tag_filter.matches(wallpaper))
+            .filter(|wallpaper| resolution_filter.matches(wallpaper, None))
+            .collect();
+        let max_len = wallpapers
+            .iter()
+            .map(|wallpaper| wallpaper.file_name.len())
+            .max()
+            .unwrap_or(0);
+        for wallpaper in wallpapers {
+            let mut state = format!("{:<max_len$}: {}", wallpaper.file_name, wallpaper.count);
+            if wallpaper.metadata.width != 0 {
+                state.push_str(&format!(
+                    " {}x{}",
+                    wallpaper.metadata.width, wallpaper.metadata.height
+                ));
+            }
+            if let Some(reason) = &wallpaper.metadata.broken {
+                state.push_str(&format!(" broken: {reason}"));
+            }
+            if wallpaper.rating() != 0 {
+                state.push_str(&format!(" rating={}", wallpaper.rating()));
+            }
+            if wallpaper.is_live() {
+                state.push_str(if wallpaper.is_video() {
+                    " video"
+                } else {
+                    " animated"
+                });
+            }
+            let tags: Vec<&str> = wallpaper
+                .all_tags()
+                .into_iter()
+                .map(|t| t.as_str())
+                .collect();
+            if !tags.is_empty() {
+                state.push_str(&format!(" [{}]", tags.join(", ")));
+            }
+            println!("{state}");
+        }
+        return;
+    }
+
+    // Only a daemon takes over, everything handled above leaves a running instance alone
     if !wall_setter.is_running() {
         wall_setter.init();
     } else {
-        println!("Killing already running instance");
-        wall_setter.kill().unwrap();
+        info!("Killing already running instance");
+        if let Err(err) = wall_setter.kill() {
+            error!("Unable to stop the running instance: {err}");
+            std::process::exit(1);
+        }
         wall_setter.init();
     }
+    #[cfg(target_os = "linux")]
+    if let Err(err) = systemd::notify("READY=1") {
+        error!("Unable to notify systemd: {err}");
+    }
+    #[cfg(target_os = "linux")]
+    let watchdog_interval = systemd::watchdog_interval();
+
+    let mut event_loop = EventLoop::new();
+    if let Err(err) = event_loop.watch(wallpapers_dir_path) {
+        error!("Unable to watch {:?}: {err}", wallpapers_dir_path);
+    }
+    #[cfg(target_os = "linux")]
+    if let Err(err) = event_loop.handle_signals() {
+        error!("Unable to handle signals: {err}");
+    }
+    let source_max_size = options.iter().find_map(|o| match o {
+        Option::SourceMaxSize(size) => Some(*size),
+        _ => None,
+    });
+    let mut sources: Vec<Source> = vec![];
+    for source in options.iter().filter_map(|o| match o {
+        Option::Source(source) => Some(source),
+        _ => None,
+    }) {
+        // Sources on the same host would otherwise remove each other's wallpapers
+        let mut name = source.name.clone();
+        let mut i = 1;
+        while sources.iter().any(|source| source.name == name) {
+            i += 1;
+            name = format!("{}-{i}", source.name);
+        }
+        sources.push(Source {
+            name,
+            max_size: source_max_size.unwrap_or(source.max_size),
+            ..source.clone()
+        });
+    }
+    if !sources.is_empty() {
+        let refresh = options
+            .iter()
+            .find_map(|o| match o {
+                Option::SourceRefresh(minutes) => {
+                    Some(std::time::Duration::from_secs(minutes * 60))
+                }
+                _ => None,
+            })
+            .unwrap_or(source::DEFAULT_REFRESH);
+        source::spawn_fetcher(sources, wallpapers_dir_path.clone(), refresh);
+    }
+
+    let leader = options
+        .iter()
+        .find_map(|o| match o {
+            Option::SyncLead(group) => Some(group),
+            _ => None,
+        })
+        .and_then(|group| {
+            Leader::new(*group)
+                .map_err(|err| error!("Unable to announce on {group}: {err}"))
+                .ok()
+        });
+
+    // Outputs only matter to the resolution filter and the rendered variants
+    let per_output = !resolution_filter.is_empty() || pipeline.is_enabled();
+    let mut builder = Rotator::builder(wallpapers_dir_path)
+        .schedule(schedule)
+        .tags(tag_filter)
+        .resolution(resolution_filter)
+        .per_output(per_output)
+        .live(live)
+        .show_new(options.contains(&Option::ShowNew))
+        // Kept up to date by the directory watcher otherwise
+        .rescan(!event_loop.is_watching())
+        .backend(wall_setter)
+        .extension(pipeline)
+        .on_event(log_event);
+    #[cfg(target_os = "linux")]
+    if live {
+        builder = builder.extension(live_player);
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(lock_screen) = lock_screen {
+        builder = builder.extension(lock_screen);
+    }
+    // Before the hooks, which are passed the colour scheme
+    if let Some(theme) = theme {
+        builder = builder.extension(theme);
+    }
+    builder = builder.extension(hooks);
+    if let Some(leader) = leader {
+        builder = builder.extension(leader);
+    }
+    if let Some(seed) = options.iter().find_map(|o| match o {
+        Option::Seed(seed) => Some(*seed),
+        _ => None,
+    }) {
+        builder = builder.seed(seed);
+    }
+    // Loading the state decodes every new image, which takes a while for a large collection
+    #[cfg(target_os = "linux")]
+    let keepalive = watchdog_interval.map(systemd::Keepalive::start);
+    let mut rotator = match builder.build() {
+        Ok(rotator) => rotator,
+        Err(err) => {
+            error!(
+                "Unable to read the state of {:?}: {err}",
+                wallpapers_dir_path
+            );
+            std::process::exit(1);
+        }
+    };
+    #[cfg(target_os = "linux")]
+    drop(keepalive);
+
+    #[cfg(target_os = "linux")]
+    let dbus = {
+        let sender = event_loop.sender();
+        DbusService::new(move |command| sender.control(command))
+            .map_err(|err| error!("Unable to register on D-Bus: {err}"))
+            .ok()
+    };
+
+    let follower = options
+        .iter()
+        .find_map(|o| match o {
+            Option::SyncFollow(group) => Some(group),
+            _ => None,
+        })
+        .and_then(|group| {
+            let sender = event_loop.sender();
+            Follower::spawn(*group, move || sender.wake())
+                .map_err(|err| error!("Unable to follow {group}: {err}"))
+                .ok()
+        });
+    if follower.is_some() {
+        // Give the leader a heartbeat to be heard from before picking on our own
+        rotator.postpone(std::time::Instant::now() + lan::HEARTBEAT_INTERVAL);
+    }
+    let mut following = false;
+
+    loop {
+        if let Some(follower) = &follower {
+            let present = follower.is_leader_present();
+            if present != following {
+                following = present;
+                if following {
+                    info!("Following the leader");
+                } else {
+                    warn!("The leader is gone, rotating independently");
+                }
+            }
+            if let Some(announcement) = follower.take().filter(|_| !rotator.is_paused()) {
+                let picks =
+                    announcement.picks_in(rotator.wallpapers(), wallpapers_dir_path, || {
+                        rotator.outputs()
+                    });
+                if !picks.is_empty() {
+                    rotator.show(picks);
+                }
+            }
+            // Rotating on our own only once the leader is gone
+            if let Some(leader_deadline) = follower.leader_deadline().filter(|_| following) {
+                rotator.postpone(leader_deadline);
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if inhibitor.is_enabled() && rotator.is_due() {
+            match inhibitor.reason() {
+                Some(reason) => {
+                    if !inhibited {
+                        info!("Pausing rotation, {reason}");
+                        inhibited = true;
+                    }
+                    rotator.postpone(std::time::Instant::now() + INHIBIT_POLL_INTERVAL);
+                }
+                None if inhibited => {
+                    info!("Resuming rotation");
+                    inhibited = false;
+                }
+                None => {}
+            }
+        }
+        // Without a watcher every change rescans the directory
+        #[cfg(target_os = "linux")]
+        let keepalive = watchdog_interval
+            .filter(|_| rotator.is_due() && !event_loop.is_watching())
+            .map(systemd::Keepalive::start);
+        rotator.tick();
+        #[cfg(target_os = "linux")]
+        drop(keepalive);
 
-    let mut wallpapers_state_path = wallpapers_dir_path.clone();
-    wallpapers_state_path.push("state.bin");
+        #[cfg(target_os = "linux")]
+        if let Some(dbus) = &dbus {
+            let status = Status {
+                current_wallpapers: rotator
+                    .current()
+                    .iter()
+                    .map(|(_, path)| {
+                        let wallpaper = rotator.wallpapers().get_by_path(wallpapers_dir_path, path);
+                        CurrentWallpaper {
+                            path: path.clone(),
+                            width: wallpaper.map_or(0, |wallpaper| wallpaper.metadata.width),
+                            height: wallpaper.map_or(0, |wallpaper| wallpaper.metadata.height),
+                            rating: wallpaper.map_or(0, |wallpaper| wallpaper.rating()),
+                            count: wallpaper.map_or(0, |wallpaper| wallpaper.count as u64),
+                        }
+                    })
+                    .collect(),
+                paused: rotator.is_paused(),
+                next_change: (!rotator.is_paused()).then_some(rotator.next_change()),
+            };
+            if let Err(err) = dbus.update(status) {
+                error!("Unable to update the D-Bus status: {err}");
+            }
+        }
+
+        let mut deadline = rotator.deadline();
+        if let Some(leader_deadline) = follower
+            .as_ref()
+            .and_then(|follower| follower.leader_deadline())
+            .filter(|leader_deadline| *leader_deadline > std::time::Instant::now())
+        {
+            deadline = deadline.min(leader_deadline);
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(watchdog_interval) = watchdog_interval {
+            if let Err(err) = systemd::notify("WATCHDOG=1") {
+                error!("Unable to notify systemd: {err}");
+            }
+            deadline = deadline.min(std::time::Instant::now() + watchdog_interval);
+        }
+
+        let mut changes: Vec<Change> = vec![];
+        #[cfg(target_os = "linux")]
+        let mut commands: Vec<Command> = vec![];
+        #[cfg(target_os = "linux")]
+        let mut terminating = false;
+        for event in event_loop.wait(deadline) {
+            match event {
+                Event::Changed(change) => changes.push(change),
+                #[cfg(target_os = "linux")]
+                Event::Control(command) => {
+                    info!("D-Bus command {:?}", command);
+                    commands.push(command);
+                }
+                #[cfg(target_os = "linux")]
+                Event::Signal(signal) => {
+                    info!("Signal {:?}", signal);
+                    match signal {
+                        Signal::Terminate => terminating = true,
+                        Signal::Reload => changes.push(Change::Rescan),
+                        Signal::Next => commands.push(Command::Next),
+                        Signal::TogglePause if rotator.is_paused() => {
+                            commands.push(Command::Resume)
+                        }
+                        Signal::TogglePause => commands.push(Command::Pause),
+                    }
+                }
+            }
+        }
+        #[cfg(target_os = "linux")]
+        let keepalive = watchdog_interval
+            .filter(|_| changes.contains(&Change::Rescan))
+            .map(systemd::Keepalive::start);
+        for change in changes {
+            rotator.file_changed(&change);
+        }
+        #[cfg(target_os = "linux")]
+        drop(keepalive);
+
+        #[cfg(target_os = "linux")]
+        for command in commands {
+            match command {
+                Command::Next => {
+                    rotator.change();
+                }
+                Command::Previous => {
+                    if !rotator.previous() {
+                        warn!("Unable to go back to the previous wallpaper");
+                    }
+                }
+                Command::Pause => rotator.pause(),
+                Command::Resume => rotator.resume(),
+                Command::SetWallpaper(path) => {
+                    let path = wallpapers_dir_path.join(path);
+                    if path.is_file() {
+                        rotator.show(vec![(None, path)]);
+                    } else {
+                        warn!("{:?} is not a file", path);
+                    }
+                }
+                Command::Rate(file_name, rating) => {
+                    let file_name =
+                        command_file_name(file_name, rotator.current(), wallpapers_dir_path);
+                    if rotator.wallpapers_mut().set_rating(&file_name, rating) {
+                        save_state(&rotator);
+                    } else {
+                        warn!("Unable to rate unknown wallpaper {file_name:?}");
+                    }
+                }
+                Command::Tag(file_name, add, remove) => {
+                    let file_name =
+                        command_file_name(file_name, rotator.current(), wallpapers_dir_path);
+                    if rotator.wallpapers_mut().set_tags(&file_name, &add, &remove) {
+                        save_state(&rotator);
+                    } else {
+                        warn!("Unable to tag unknown wallpaper {file_name:?}");
+                    }
+                }
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        if terminating {
+            break;
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        info!("Exiting");
+        if let Err(err) = systemd::notify("STOPPING=1") {
+            error!("Unable to notify systemd: {err}");
+        }
+        save_state(&rotator);
+        // Stops the live wallpaper
+        drop(rotator);
+    }
+}
 
-    let mut wallpapers: Vec<Wallpaper> = if let Ok(state) = std::fs::read(&wallpapers_state_path) {
-        println!("Using previous state");
-        serde_binary::from_vec(state, serde_binary::binary_stream::Endian::Little).unwrap()
+/// The wallpaper a `rate` or `tag` command refers to, relative to the wallpaper directory
+#[cfg(target_os = "linux")]
+fn command_file_name(
+    file_name: String,
+    current_wallpapers: &[(std::option::Option<Output>, std::path::PathBuf)],
+    wallpapers_dir_path: &std::path::Path,
+) -> String {
+    if file_name.is_empty() {
+        current_wallpapers
+            .first()
+            .and_then(|(_, current)| current.strip_prefix(wallpapers_dir_path).ok())
+            .map(|current| current.to_string_lossy().into_owned())
+            .unwrap_or_default()
     } else {
-        let wallpapers_paths = get_wallpapers_from_path(&wallpapers_dir_path);
-        let wallpapers = wallpapers_paths
-            .into_iter()
-            .map(|wallpaper_path| Wallpaper {
-                file_name: wallpaper_path,
-                count: 0,
-            });
-        wallpapers.collect()
+        // Absolute paths are sent by `wallrustler rate` and `wallrustler tag`
+        std::path::Path::new(&file_name)
+            .strip_prefix(wallpapers_dir_path)
+            .map(|relative| relative.to_string_lossy().into_owned())
+            .unwrap_or(file_name)
+    }
+}
+
+fn save_state(rotator: &Rotator) {
+    if let Err(err) = rotator.save() {
+        error!("Unable to save the state: {err}");
+    }
+}
+
+/// `cache stats|clear|prune [--max-size <MiB>]`
+fn manage_cache(command: CacheCommand, max_size: std::option::Option<u64>) {
+    if let Some(max_size) = max_size {
+        cache::set_max_size(max_size);
+    }
+
+    let result = match command {
+        CacheCommand::Stats => cache::stats().map(|stats| {
+            for (kind, count, size) in &stats.kinds {
+                println!("{kind:<10} {count:>6} entries {:>10}", format_size(*size));
+            }
+            println!(
+                "{:<10} {:>6} entries {:>10} of {}",
+                "total",
+                stats.kinds.iter().map(|(_, count, _)| count).sum::<usize>(),
+                format_size(stats.size()),
+                format_size(cache::max_size())
+            );
+            if let Some(oldest) = stats.oldest {
+                let age = oldest.elapsed().unwrap_or_default().as_secs();
+                println!(
+                    "Least recently used entry was used {} days ago",
+                    age / 86400
+                );
+            }
+        }),
+        CacheCommand::Clear => cache::clear()
+            .map(|(count, size)| println!("Removed {count} files, {}", format_size(size))),
+        CacheCommand::Prune => cache::prune()
+            .map(|(count, size)| println!("Removed {count} files, {}", format_size(size))),
     };
+    if let Err(err) = result {
+        eprintln!("Unable to manage the cache in {:?}: {err}", get_cache_dir());
+        std::process::exit(1);
+    }
+}
 
-    if options.contains(&Option::PrintState) {
-        wallpapers = sync_wallpapers(&wallpapers_dir_path, wallpapers);
-        let states: Vec<(String, usize)> = wallpapers
-            .iter()
-            .map(|wallpaper| (wallpaper.file_name.to_owned(), wallpaper.count))
-            .collect();
-        let max_len = states.iter().map(|(name, _)| name.len()).max().unwrap();
-        for (name, count) in states {
-            println!("{:<max_len$}: {count}", name);
+fn format_size(size: u64) -> String {
+    format!("{:.1} MiB", size as f64 / (1024.0 * 1024.0))
+}
+
+/// `status [--format ...]`, prints what the running daemon reports over D-Bus
+#[cfg(target_os = "linux")]
+fn print_status(format: StatusFormat) {
+    match DaemonStatus::query() {
+        Ok(status) => println!("{}", status.format(format)),
+        Err(err) => {
+            eprintln!("Unable to query the daemon: {err}");
+            std::process::exit(1);
         }
-        return;
     }
+}
+
+/// `next`, `rate` and `ctl`, sends the command to the running daemon over D-Bus
+#[cfg(target_os = "linux")]
+fn control(command: Command) {
+    if let Err(err) = wallrustler::dbus::send(&command) {
+        eprintln!("Unable to reach the daemon: {err}");
+        std::process::exit(1);
+    }
+}
 
-    loop {
-        wallpapers = sync_wallpapers(&wallpapers_dir_path, wallpapers);
-        wallpapers = mean_centering_counts(wallpapers);
-        let wallpaper = pick_random_wallpaper(&wallpapers_dir_path, &mut wallpapers);
-        wall_setter.set_wallpaper(&wallpaper).unwrap();
-        let state =
-            serde_binary::to_vec(&wallpapers, serde_binary::binary_stream::Endian::Little).unwrap();
-        std::fs::write(&wallpapers_state_path, state).unwrap();
-        std::thread::sleep(std::time::Duration::from_secs(interval));
+fn log_event(event: &rotator::Event) {
+    match event {
+        rotator::Event::Changed(_) => {}
+        rotator::Event::NoMatch(Some(output)) => warn!(
+            "No wallpapers match the provided filters for {}",
+            output.name
+        ),
+        rotator::Event::NoMatch(None) => warn!("No wallpapers match the provided filters"),
+        rotator::Event::Vetoed(Some(output)) => warn!(
+            "Every pick was vetoed for {}, keeping the current wallpaper",
+            output.name
+        ),
+        rotator::Event::Vetoed(None) => {
+            warn!("Every pick was vetoed, keeping the current wallpaper")
+        }
+        rotator::Event::Failed(err) => error!("Unable to set the wallpaper: {err}"),
+        rotator::Event::SaveFailed(err) => error!("Unable to save the state: {err}"),
     }
 }
@@ -0,0 +1,424 @@
+use serde::{Deserialize, Serialize};
+
+/// EXIF `Rating` tag written by Windows Explorer and most photo managers
+const EXIF_RATING: exif::Tag = exif::Tag(exif::Context::Tiff, 0x4746);
+const XMP_START: &str = "<x:xmpmeta";
+const XMP_END: &str = "</x:xmpmeta>";
+const ORIENTED_KIND: &str = "oriented";
+/// Embedded XMP is looked for this far into the file, JPEG and PNG keep it near the start
+const XMP_HEAD_SIZE: u64 = 1024 * 1024;
+/// and this far from its end, WebP and some video containers append it
+const XMP_TAIL_SIZE: u64 = 256 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct Metadata {
+    /// Latest modification time of the file the metadata was read from and its XMP sidecars, in
+    /// seconds since the epoch
+    pub modified: u64,
+    pub keywords: Vec<String>,
+    /// Star rating in the XMP convention: -1 rejected, 0 unrated, 1-5 stars
+    pub rating: i8,
+    /// Dimensions as displayed, i.e. after applying the EXIF orientation, 0 when unknown
+    pub width: u32,
+    pub height: u32,
+    /// Why the file couldn't be decoded, broken wallpapers are never picked
+    pub broken: Option<String>,
+    /// Videos and images with more than one frame
+    pub animated: bool,
+}
+
+/// Selection weight multiplier, unrated and 3 star wallpapers are weighted equally
+pub fn rating_weight(rating: i8) -> f64 {
+    match rating {
+        r if r < 0 => 0.0,
+        0 => 1.0,
+        r => 2.0f64.powi(r.min(5) as i32 - 3),
+    }
+}
+
+pub fn get_modified(path: &std::path::Path) -> u64 {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|modified| modified.as_secs())
+        .unwrap_or(0)
+}
+
+/// Metadata is read again when this changes, i.e. when the file or one of its sidecars was
+/// edited, added or removed
+pub fn get_metadata_modified(path: &std::path::Path) -> u64 {
+    get_xmp_sidecars(path)
+        .iter()
+        .map(|sidecar| get_modified(sidecar))
+        .fold(get_modified(path), u64::max)
+}
+
+/// Reads keywords and rating from embedded XMP, `.xmp` sidecars and EXIF
+pub fn read_metadata(path: &std::path::Path) -> Metadata {
+    let mut metadata = Metadata {
+        modified: get_metadata_modified(path),
+        ..Default::default()
+    };
+
+    let mut xmp_sources = read_head_and_tail(path).unwrap_or_default();
+    for sidecar in get_xmp_sidecars(path) {
+        if let Ok(content) = std::fs::read(sidecar) {
+            xmp_sources.push(content);
+        }
+    }
+    for content in xmp_sources {
+        if let Some(xmp) = find_xmp(&content) {
+            if let Some(rating) = get_xmp_rating(&xmp) {
+                metadata.rating = rating;
+            }
+            metadata.keywords.extend(get_xmp_keywords(&xmp));
+        }
+    }
+    metadata.keywords.sort();
+    metadata.keywords.dedup();
+
+    let exif = read_exif(path);
+    if metadata.rating == 0 {
+        if let Some(rating) = exif
+            .as_ref()
+            .and_then(|exif| get_exif_uint(exif, EXIF_RATING))
+            .and_then(|rating| i8::try_from(rating).ok())
+        {
+            metadata.rating = rating;
+        }
+    }
+
+    if crate::is_video_file(path) {
+        metadata.animated = true;
+        if let Some((width, height)) = probe_video_dimensions(path) {
+            (metadata.width, metadata.height) = (width, height);
+        }
+        return metadata;
+    }
+    metadata.animated = is_animated(path);
+
+    match decode_dimensions(path) {
+        Ok((width, height)) => {
+            (metadata.width, metadata.height) = (width, height);
+            // Orientations 5-8 rotate the image by 90 degrees
+            if exif
+                .as_ref()
+                .and_then(|exif| get_exif_uint(exif, exif::Tag::Orientation))
+                .is_some_and(|orientation| (5..=8).contains(&orientation))
+            {
+                (metadata.width, metadata.height) = (height, width);
+            }
+        }
+        Err(err) => {
+            let reason = err
+                .to_string()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+            warn!("Quarantining {:?}: {reason}", path);
+            metadata.broken = Some(reason);
+        }
+    }
+
+    metadata
+}
+
+/// Checks whether the file content starts with the signature of a format that can be decoded,
+/// regardless of its extension
+pub fn has_image_signature(path: &std::path::Path) -> bool {
+    let mut header = [0; 32];
+    let Ok(len) = std::fs::File::open(path).and_then(|mut file| {
+        use std::io::Read;
+        file.read(&mut header)
+    }) else {
+        return false;
+    };
+
+    image::guess_format(&header[..len]).is_ok_and(|format| format.reading_enabled())
+}
+
+/// Dimensions of the first video stream, requires ffprobe
+fn probe_video_dimensions(path: &std::path::Path) -> Option<(u32, u32)> {
+    let output = std::process::Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "v:0"])
+        .args(["-show_entries", "stream=width,height", "-of", "csv=s=x:p=0"])
+        .arg(path)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    crate::parse_size(&String::from_utf8_lossy(&output.stdout))
+}
+
+pub(crate) fn is_animated(path: &std::path::Path) -> bool {
+    let Ok(reader) = image::ImageReader::open(path).and_then(|reader| reader.with_guessed_format())
+    else {
+        return false;
+    };
+    let format = reader.format();
+    let reader = reader.into_inner();
+
+    match format {
+        Some(image::ImageFormat::Gif) => image::codecs::gif::GifDecoder::new(reader)
+            .map(|decoder| {
+                use image::AnimationDecoder;
+                decoder.into_frames().take(2).count() > 1
+            })
+            .unwrap_or(false),
+        Some(image::ImageFormat::WebP) => image::codecs::webp::WebPDecoder::new(reader)
+            .is_ok_and(|decoder| decoder.has_animation()),
+        Some(image::ImageFormat::Png) => image::codecs::png::PngDecoder::new(reader)
+            .and_then(|decoder| decoder.is_apng())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Fully decodes the image, the format is detected from the content rather than the extension
+fn decode_dimensions(path: &std::path::Path) -> Result<(u32, u32), image::ImageError> {
+    let img = image::ImageReader::open(path)?
+        .with_guessed_format()?
+        .decode()?;
+
+    Ok((img.width(), img.height()))
+}
+
+/// Returns the wallpaper path to hand to the backend, writing an upright copy into the cache
+/// when the EXIF orientation says the image is stored rotated or mirrored
+pub fn apply_orientation(path: &std::path::Path) -> Result<std::path::PathBuf, std::io::Error> {
+    let orientation = read_orientation(path);
+    if orientation == image::metadata::Orientation::NoTransforms {
+        return Ok(path.to_path_buf());
+    }
+
+    let key = crate::cache::variant_key(path, None, ());
+    if let Some(oriented_path) = crate::cache::lookup(ORIENTED_KIND, key, "png") {
+        return Ok(oriented_path);
+    }
+
+    info!("Correcting orientation of {:?}", path);
+    let mut img = image::open(path).map_err(std::io::Error::other)?;
+    img.apply_orientation(orientation);
+    crate::cache::store(ORIENTED_KIND, key, "png", |oriented_path| {
+        img.save_with_format(oriented_path, image::ImageFormat::Png)
+            .map_err(std::io::Error::other)
+    })
+}
+
+pub(crate) fn read_orientation(path: &std::path::Path) -> image::metadata::Orientation {
+    read_exif(path)
+        .and_then(|exif| get_exif_uint(&exif, exif::Tag::Orientation))
+        .and_then(|orientation| u8::try_from(orientation).ok())
+        .and_then(image::metadata::Orientation::from_exif)
+        .unwrap_or(image::metadata::Orientation::NoTransforms)
+}
+
+fn read_exif(path: &std::path::Path) -> Option<exif::Exif> {
+    let file = std::fs::File::open(path).ok()?;
+    exif::Reader::new()
+        .read_from_container(&mut std::io::BufReader::new(file))
+        .ok()
+}
+
+fn get_exif_uint(exif: &exif::Exif, tag: exif::Tag) -> Option<u32> {
+    exif.get_field(tag, exif::In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+}
+
+/// digiKam writes `image.jpg.xmp`, Lightroom and darktable-style tools `image.xmp`
+fn get_xmp_sidecars(path: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".xmp");
+
+    vec![
+        std::path::PathBuf::from(sidecar),
+        path.with_extension("xmp"),
+    ]
+}
+
+/// The parts of the file embedded XMP is found in, without reading all of a large video
+fn read_head_and_tail(path: &std::path::Path) -> Result<Vec<Vec<u8>>, std::io::Error> {
+    use std::io::{Read, Seek};
+
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    if len <= XMP_HEAD_SIZE + XMP_TAIL_SIZE {
+        let mut content = vec![];
+        file.read_to_end(&mut content)?;
+        return Ok(vec![content]);
+    }
+
+    let mut head = vec![];
+    file.by_ref().take(XMP_HEAD_SIZE).read_to_end(&mut head)?;
+    let mut tail = vec![];
+    file.seek(std::io::SeekFrom::End(-(XMP_TAIL_SIZE as i64)))?;
+    file.read_to_end(&mut tail)?;
+
+    Ok(vec![head, tail])
+}
+
+fn find_xmp(content: &[u8]) -> Option<String> {
+    let start = find_bytes(content, XMP_START.as_bytes())?;
+    let end = find_bytes(&content[start..], XMP_END.as_bytes())? + start + XMP_END.len();
+
+    Some(String::from_utf8_lossy(&content[start..end]).to_string())
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Handles both the attribute (`xmp:Rating="4"`) and the element (`<xmp:Rating>4</xmp:Rating>`) form
+fn get_xmp_rating(xmp: &str) -> Option<i8> {
+    let value = if let Some((_, rest)) = xmp.split_once("xmp:Rating=\"") {
+        rest.split_once('"')?.0
+    } else {
+        xmp.split_once("<xmp:Rating>")?.1.split_once('<')?.0
+    };
+
+    value.trim().parse::<f64>().ok().map(|rating| rating as i8)
+}
+
+fn get_xmp_keywords(xmp: &str) -> Vec<String> {
+    let subject = xmp
+        .split_once("<dc:subject>")
+        .and_then(|(_, rest)| rest.split_once("</dc:subject>"))
+        .map(|(subject, _)| subject)
+        .unwrap_or("");
+
+    subject
+        .split("<rdf:li")
+        .skip(1)
+        .filter_map(|item| item.split_once('>'))
+        .filter_map(|(_, item)| item.split_once("</rdf:li>"))
+        .filter_map(|(keyword, _)| crate::normalize_tag(&unescape_xml(keyword)))
+        .collect()
+}
+
+fn unescape_xml(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XMP: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF>
+<rdf:Description xmp:Rating="4"><dc:subject><rdf:Bag>
+<rdf:li>Beach</rdf:li><rdf:li xml:lang="en">Sun &amp; Sea</rdf:li>
+</rdf:Bag></dc:subject></rdf:Description></rdf:RDF></x:xmpmeta>"#;
+
+    #[test]
+    fn xmp_rating_and_keywords() {
+        let content = format!("\u{ff}\u{d8}junk{XMP}junk");
+        let xmp = find_xmp(content.as_bytes()).unwrap();
+        assert_eq!(get_xmp_rating(&xmp), Some(4));
+        assert_eq!(get_xmp_keywords(&xmp), vec!["beach", "sun & sea"]);
+        assert_eq!(get_xmp_rating("<xmp:Rating>-1</xmp:Rating>"), Some(-1));
+        assert_eq!(find_xmp(b"no metadata"), None);
+    }
+
+    #[test]
+    fn xmp_is_found_at_either_end_of_large_files() {
+        let dir = std::env::temp_dir().join(format!("wallrustler-xmp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("large.webp");
+        let padding = vec![0u8; (XMP_HEAD_SIZE + XMP_TAIL_SIZE) as usize];
+        std::fs::write(&path, [&padding[..], XMP.as_bytes()].concat()).unwrap();
+
+        let parts = read_head_and_tail(&path).unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].len() as u64, XMP_HEAD_SIZE);
+        assert!(parts.iter().any(|part| find_xmp(part).is_some()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sidecars_count_as_modifications() {
+        let dir = std::env::temp_dir().join(format!("wallrustler-sidecar-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("image.jpg");
+        std::fs::write(&path, b"image").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000))
+            .unwrap();
+        assert_eq!(get_metadata_modified(&path), 1000);
+
+        std::fs::write(dir.join("image.jpg.xmp"), XMP).unwrap();
+        assert!(get_metadata_modified(&path) > 1000);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = std::io::Cursor::new(vec![]);
+        image::RgbImage::new(width, height)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        png.into_inner()
+    }
+
+    #[test]
+    fn image_signatures() {
+        let dir =
+            std::env::temp_dir().join(format!("wallrustler-signature-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let files: [(&str, &[u8]); 6] = [
+            // The extension doesn't matter
+            ("png.jpg", &png(2, 2)),
+            (
+                "jpeg",
+                &[0xff, 0xd8, 0xff, 0xe0, 0, 0x10, b'J', b'F', b'I', b'F'],
+            ),
+            ("gif", b"GIF89a\x01\x00\x01\x00"),
+            ("webp", b"RIFF\x00\x00\x00\x00WEBPVP8 "),
+            ("garbage.png", b"<html>not found</html>"),
+            ("empty.png", b""),
+        ];
+        for (file_name, content) in files {
+            std::fs::write(dir.join(file_name), content).unwrap();
+        }
+
+        let signatures: Vec<bool> = files
+            .iter()
+            .map(|(file_name, _)| has_image_signature(&dir.join(file_name)))
+            .collect();
+        let missing = has_image_signature(&dir.join("missing.png"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(signatures, [true, true, true, true, false, false]);
+        assert!(!missing);
+    }
+
+    #[test]
+    fn undecodable_files_are_broken() {
+        let dir = std::env::temp_dir().join(format!("wallrustler-broken-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let png = png(40, 30);
+        std::fs::write(dir.join("whole.png"), &png).unwrap();
+        std::fs::write(dir.join("truncated.png"), &png[..png.len() / 2]).unwrap();
+        std::fs::write(dir.join("garbage.jpg"), b"<html>not found</html>").unwrap();
+
+        let whole = read_metadata(&dir.join("whole.png"));
+        let truncated = read_metadata(&dir.join("truncated.png"));
+        let garbage = read_metadata(&dir.join("garbage.jpg"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(whole.broken, None);
+        assert_eq!((whole.width, whole.height), (40, 30));
+        assert!(truncated.broken.is_some());
+        assert!(garbage.broken.is_some());
+        assert_eq!((garbage.width, garbage.height), (0, 0));
+    }
+}
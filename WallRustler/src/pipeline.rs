@@ -0,0 +1,300 @@
+use crate::rotator::{Extension, Pick};
+use crate::Output;
+use chrono::Timelike;
+use image::{DynamicImage, RgbImage};
+
+const JPEG_QUALITY: u8 = 95;
+const RENDERED_KIND: &str = "rendered";
+/// The blurred background is computed on a downscaled copy, which is both faster and smoother
+const BLUR_DOWNSCALE: u32 = 16;
+const BLUR_SIGMA: f32 = 4.0;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FitMode {
+    /// Scale to cover the output, cropping what doesn't fit
+    Fill,
+    /// Scale to fit inside the output, letterboxing with black bars
+    Fit,
+    /// Like `Fit`, letterboxing with a blurred copy of the wallpaper
+    Blur,
+    /// Keep the original size, centred and cropped
+    Center,
+    /// Keep the original size, repeated from the top-left corner
+    Tile,
+}
+
+impl FitMode {
+    pub fn parse(s: &str) -> Option<FitMode> {
+        match s {
+            "fill" => Some(FitMode::Fill),
+            "fit" => Some(FitMode::Fit),
+            "blur" => Some(FitMode::Blur),
+            "center" => Some(FitMode::Center),
+            "tile" => Some(FitMode::Tile),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct NightDim {
+    /// 0-100, how much darker the wallpaper gets
+    pub percent: u8,
+    pub start_hour: u8,
+    pub end_hour: u8,
+}
+
+impl NightDim {
+    pub fn is_active(&self, hour: u8) -> bool {
+        if self.start_hour <= self.end_hour {
+            (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+/// In-process rendering of output sized wallpaper variants into the cache
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Pipeline {
+    pub fit_mode: Option<FitMode>,
+    /// -100 to 100
+    pub brightness: i8,
+    pub night_dim: Option<NightDim>,
+    pub grayscale: bool,
+}
+
+impl Pipeline {
+    pub fn is_enabled(&self) -> bool {
+        self.fit_mode.is_some()
+            || self.brightness != 0
+            || self.night_dim.is_some()
+            || self.grayscale
+    }
+
+    /// Renders `wallpaper` for `output` and returns the path of the cached variant, the geometry
+    /// is left untouched when the output is unknown
+    pub fn process(
+        &self,
+        wallpaper: &std::path::Path,
+        output: Option<&Output>,
+    ) -> Result<std::path::PathBuf, std::io::Error> {
+        let size = output
+            .filter(|output| output.width != 0 && output.height != 0)
+            .map(|output| (output.width, output.height));
+        let dim = self
+            .night_dim
+            .filter(|night_dim| night_dim.is_active(chrono::Local::now().hour() as u8))
+            .map_or(0, |night_dim| night_dim.percent);
+
+        let key = crate::cache::variant_key(
+            wallpaper,
+            size,
+            (
+                format!("{:?}", self.fit_mode),
+                self.brightness,
+                self.grayscale,
+                dim,
+            ),
+        );
+        if let Some(rendered_path) = crate::cache::lookup(RENDERED_KIND, key, "jpg") {
+            return Ok(rendered_path);
+        }
+
+        info!("Rendering {:?}", wallpaper);
+        let mut img = image::open(wallpaper).map_err(std::io::Error::other)?;
+        img.apply_orientation(crate::metadata::read_orientation(wallpaper));
+
+        let mut img = match (self.fit_mode, size) {
+            (Some(fit_mode), Some((width, height))) => fit(&img, fit_mode, width, height),
+            _ => img.to_rgb8(),
+        };
+        if self.grayscale {
+            img = DynamicImage::ImageRgb8(img).grayscale().to_rgb8();
+        }
+        if self.brightness != 0 {
+            img = image::imageops::brighten(&img, self.brightness as i32 * 255 / 100);
+        }
+        if dim != 0 {
+            let factor = 1.0 - dim.min(100) as f32 / 100.0;
+            img.pixels_mut()
+                .flat_map(|pixel| pixel.0.iter_mut())
+                .for_each(|channel| *channel = (*channel as f32 * factor) as u8);
+        }
+
+        crate::cache::store(RENDERED_KIND, key, "jpg", |path| {
+            let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+            image::codecs::jpeg::JpegEncoder::new_with_quality(file, JPEG_QUALITY)
+                .encode_image(&img)
+                .map_err(std::io::Error::other)
+        })
+    }
+}
+
+/// Renders every still wallpaper when enabled, otherwise only corrects its orientation; the
+/// original file is shown when that fails. Animated images are left alone, rendering would
+/// keep only their first frame
+impl Extension for Pipeline {
+    fn prepare(&mut self, pick: &Pick) -> Option<std::path::PathBuf> {
+        let animated = pick.entry.map_or_else(
+            || crate::metadata::is_animated(pick.wallpaper),
+            |entry| entry.is_live(),
+        );
+        if animated {
+            return None;
+        }
+
+        let prepared = if self.is_enabled() {
+            self.process(pick.wallpaper, pick.output)
+        } else {
+            crate::metadata::apply_orientation(pick.wallpaper)
+        };
+
+        prepared
+            .map_err(|err| error!("Unable to prepare {:?}: {err}", pick.wallpaper))
+            .ok()
+    }
+}
+
+fn fit(img: &DynamicImage, fit_mode: FitMode, width: u32, height: u32) -> RgbImage {
+    let filter = image::imageops::FilterType::Lanczos3;
+    match fit_mode {
+        FitMode::Fill => img.resize_to_fill(width, height, filter).to_rgb8(),
+        FitMode::Fit | FitMode::Blur => {
+            let mut canvas = if fit_mode == FitMode::Blur {
+                blurred_background(img, width, height)
+            } else {
+                RgbImage::new(width, height)
+            };
+            let scaled = img.resize(width, height, filter).to_rgb8();
+            let x = (width - scaled.width()) / 2;
+            let y = (height - scaled.height()) / 2;
+            image::imageops::overlay(&mut canvas, &scaled, x as i64, y as i64);
+            canvas
+        }
+        FitMode::Center => {
+            let mut canvas = RgbImage::new(width, height);
+            let x = (width as i64 - img.width() as i64) / 2;
+            let y = (height as i64 - img.height() as i64) / 2;
+            image::imageops::overlay(&mut canvas, &img.to_rgb8(), x, y);
+            canvas
+        }
+        FitMode::Tile => {
+            let mut canvas = RgbImage::new(width, height);
+            image::imageops::tile(&mut canvas, &img.to_rgb8());
+            canvas
+        }
+    }
+}
+
+fn blurred_background(img: &DynamicImage, width: u32, height: u32) -> RgbImage {
+    let small_width = (width / BLUR_DOWNSCALE).max(1);
+    let small_height = (height / BLUR_DOWNSCALE).max(1);
+    let small = img.resize_to_fill(
+        small_width,
+        small_height,
+        image::imageops::FilterType::Triangle,
+    );
+    let small = image::imageops::blur(&small.to_rgb8(), BLUR_SIGMA);
+
+    image::imageops::resize(&small, width, height, image::imageops::FilterType::Triangle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+    const BLACK: Rgb<u8> = Rgb([0, 0, 0]);
+
+    fn solid(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(width, height, RED))
+    }
+
+    #[test]
+    fn fit_modes_place_the_wallpaper() {
+        // Wider than the output, cropped
+        let filled = fit(&solid(200, 100), FitMode::Fill, 100, 100);
+        assert_eq!(filled.dimensions(), (100, 100));
+        assert!(filled.pixels().all(|pixel| *pixel == RED));
+
+        // Scaled to 100x50, letterboxed at the top and bottom
+        let fitted = fit(&solid(200, 100), FitMode::Fit, 100, 100);
+        assert_eq!(fitted.dimensions(), (100, 100));
+        assert_eq!(*fitted.get_pixel(50, 24), BLACK);
+        assert_eq!(*fitted.get_pixel(50, 25), RED);
+        assert_eq!(*fitted.get_pixel(50, 74), RED);
+        assert_eq!(*fitted.get_pixel(50, 75), BLACK);
+        // Pillarboxed
+        let fitted = fit(&solid(50, 100), FitMode::Fit, 200, 100);
+        assert_eq!(*fitted.get_pixel(74, 50), BLACK);
+        assert_eq!(*fitted.get_pixel(75, 50), RED);
+        assert_eq!(*fitted.get_pixel(124, 50), RED);
+        assert_eq!(*fitted.get_pixel(125, 50), BLACK);
+
+        let blurred = fit(&solid(200, 100), FitMode::Blur, 100, 100);
+        assert_eq!(blurred.dimensions(), (100, 100));
+        assert_ne!(*blurred.get_pixel(50, 10), BLACK);
+        assert_eq!(*blurred.get_pixel(50, 50), RED);
+
+        // Smaller than the output, centred on black
+        let centred = fit(&solid(50, 50), FitMode::Center, 100, 100);
+        assert_eq!(*centred.get_pixel(24, 50), BLACK);
+        assert_eq!(*centred.get_pixel(25, 25), RED);
+        assert_eq!(*centred.get_pixel(74, 74), RED);
+        assert_eq!(*centred.get_pixel(75, 50), BLACK);
+        // Larger, cropped around the centre
+        let mut img = RgbImage::from_pixel(300, 300, BLACK);
+        img.put_pixel(150, 150, RED);
+        let centred = fit(&DynamicImage::ImageRgb8(img), FitMode::Center, 100, 100);
+        assert_eq!(*centred.get_pixel(50, 50), RED);
+        assert_eq!(centred.pixels().filter(|pixel| **pixel == RED).count(), 1);
+
+        let mut img = RgbImage::new(30, 20);
+        img.put_pixel(0, 0, RED);
+        let tiled = fit(&DynamicImage::ImageRgb8(img), FitMode::Tile, 100, 50);
+        let red: Vec<(u32, u32)> = tiled
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| **pixel == RED)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        assert_eq!(
+            red,
+            [
+                (0, 0),
+                (30, 0),
+                (60, 0),
+                (90, 0),
+                (0, 20),
+                (30, 20),
+                (60, 20),
+                (90, 20),
+                (0, 40),
+                (30, 40),
+                (60, 40),
+                (90, 40)
+            ]
+        );
+    }
+
+    #[test]
+    fn night_dim_hours() {
+        let night_dim = |start_hour, end_hour| NightDim {
+            percent: 50,
+            start_hour,
+            end_hour,
+        };
+        let active_hours = |night_dim: NightDim| -> Vec<u8> {
+            (0..24).filter(|hour| night_dim.is_active(*hour)).collect()
+        };
+
+        assert_eq!(
+            active_hours(night_dim(20, 6)),
+            [0, 1, 2, 3, 4, 5, 20, 21, 22, 23]
+        );
+        assert_eq!(active_hours(night_dim(1, 4)), [1, 2, 3]);
+        assert_eq!(active_hours(night_dim(23, 0)), [23]);
+        assert_eq!(active_hours(night_dim(0, 0)), [] as [u8; 0]);
+    }
+}
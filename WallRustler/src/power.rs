@@ -0,0 +1,135 @@
+const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+const LOADAVG_PATH: &str = "/proc/loadavg";
+
+/// Whether a battery is discharging, false on desktops and when sysfs is unavailable
+pub fn on_battery() -> bool {
+    is_discharging(&get_batteries(std::path::Path::new(POWER_SUPPLY_PATH)))
+}
+
+/// Lowest charge in percent among the batteries
+pub fn battery_capacity() -> Option<u8> {
+    lowest_capacity(&get_batteries(std::path::Path::new(POWER_SUPPLY_PATH)))
+}
+
+/// One minute load average divided by the number of available cores
+pub fn load_per_core() -> Option<f64> {
+    let loadavg = std::fs::read_to_string(LOADAVG_PATH).ok()?;
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+
+    parse_load_per_core(&loadavg, cores)
+}
+
+fn is_discharging(batteries: &[std::path::PathBuf]) -> bool {
+    batteries.iter().any(|battery| {
+        read_attribute(battery, "status").is_some_and(|status| status == "Discharging")
+    })
+}
+
+fn lowest_capacity(batteries: &[std::path::PathBuf]) -> Option<u8> {
+    batteries
+        .iter()
+        .filter_map(|battery| read_attribute(battery, "capacity"))
+        .filter_map(|capacity| capacity.parse().ok())
+        .min()
+}
+
+/// `/proc/loadavg` starts with the one minute load average, `0.52 0.58 0.59 1/1013 12345`
+fn parse_load_per_core(loadavg: &str, cores: usize) -> Option<f64> {
+    let load: f64 = loadavg.split_whitespace().next()?.parse().ok()?;
+
+    Some(load / cores.max(1) as f64)
+}
+
+fn get_batteries(power_supply_dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let Ok(supplies) = power_supply_dir.read_dir() else {
+        return vec![];
+    };
+
+    let mut batteries: Vec<std::path::PathBuf> = supplies
+        .filter_map(|supply| supply.ok())
+        .map(|supply| supply.path())
+        .filter(|supply| read_attribute(supply, "type").is_some_and(|t| t == "Battery"))
+        .collect();
+    batteries.sort();
+    batteries
+}
+
+fn read_attribute(supply: &std::path::Path, attribute: &str) -> Option<String> {
+    std::fs::read_to_string(supply.join(attribute))
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_batteries_from_sysfs() {
+        let dir = std::env::temp_dir().join(format!("wallrustler-power-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let supplies: [(&str, &[(&str, &str)]); 4] = [
+            ("AC", &[("type", "Mains\n"), ("online", "0\n")]),
+            (
+                "BAT0",
+                &[
+                    ("type", "Battery\n"),
+                    ("status", "Full\n"),
+                    ("capacity", "97\n"),
+                ],
+            ),
+            (
+                "BAT1",
+                &[
+                    ("type", "Battery\n"),
+                    ("status", "Discharging\n"),
+                    ("capacity", "42\n"),
+                ],
+            ),
+            // A peripheral reporting garbage
+            (
+                "hidpp_battery_0",
+                &[("type", "Battery\n"), ("capacity", "unknown\n")],
+            ),
+        ];
+        for (supply, attributes) in supplies {
+            std::fs::create_dir_all(dir.join(supply)).unwrap();
+            for (attribute, value) in attributes {
+                std::fs::write(dir.join(supply).join(attribute), value).unwrap();
+            }
+        }
+
+        let batteries = get_batteries(&dir);
+        let discharging = is_discharging(&batteries);
+        let capacity = lowest_capacity(&batteries);
+        let charging = is_discharging(&batteries[..1]);
+        let missing = get_batteries(&dir.join("missing"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            batteries,
+            [
+                dir.join("BAT0"),
+                dir.join("BAT1"),
+                dir.join("hidpp_battery_0")
+            ]
+        );
+        assert!(discharging);
+        assert_eq!(capacity, Some(42));
+        assert!(!charging);
+        assert!(missing.is_empty());
+        assert!(!is_discharging(&missing));
+        assert_eq!(lowest_capacity(&missing), None);
+    }
+
+    #[test]
+    fn load_is_divided_among_cores() {
+        assert_eq!(
+            parse_load_per_core("3.00 0.58 0.59 1/1013 12345\n", 4),
+            Some(0.75)
+        );
+        assert_eq!(parse_load_per_core("0.50", 0), Some(0.5));
+        assert_eq!(parse_load_per_core("", 4), None);
+        assert_eq!(parse_load_per_core("busy 1/2", 4), None);
+    }
+}
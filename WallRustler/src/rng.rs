@@ -0,0 +1,60 @@
+use rand::SeedableRng;
+
+const RNG_STATE_FILE_NAME: &str = "rng.bin";
+
+/// Seeds of the picks of every change, derived from `--seed` and advanced on every change. The
+/// state is kept next to the view counts, so both files and the seed reproduce the rotation
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SeededRng {
+    pub seed: u64,
+    /// Seed of the next change
+    pub state: u64,
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> SeededRng {
+        SeededRng { seed, state: seed }
+    }
+
+    /// In the directory of the state at `state_path`
+    pub fn state_path(state_path: &std::path::Path) -> std::path::PathBuf {
+        state_path.with_file_name(RNG_STATE_FILE_NAME)
+    }
+
+    /// Continues from the saved state when it was started from the same seed
+    pub fn load(path: &std::path::Path, seed: u64) -> SeededRng {
+        let saved = std::fs::read(path).ok().and_then(|bytes| {
+            let seed = u64::from_le_bytes(bytes.get(0..8)?.try_into().ok()?);
+            let state = u64::from_le_bytes(bytes.get(8..16)?.try_into().ok()?);
+            Some(SeededRng { seed, state })
+        });
+        match saved {
+            Some(saved) if saved.seed == seed => saved,
+            Some(_) => {
+                info!("Seed changed, starting over");
+                SeededRng::new(seed)
+            }
+            None => SeededRng::new(seed),
+        }
+    }
+
+    pub fn save(&self, path: &std::path::Path) -> Result<(), std::io::Error> {
+        let mut bytes = self.seed.to_le_bytes().to_vec();
+        bytes.extend(self.state.to_le_bytes());
+        std::fs::write(path, bytes)
+    }
+
+    /// The generator of the next change
+    pub fn next_rng(&mut self) -> rand_hc::Hc128Rng {
+        let rng = rand_hc::Hc128Rng::seed_from_u64(self.state);
+        self.state = splitmix64(self.state);
+        rng
+    }
+}
+
+fn splitmix64(state: u64) -> u64 {
+    let mut z = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,992 @@
+use crate::index::WallpaperIndex;
+use crate::rng::SeededRng;
+use crate::schedule::Schedule;
+use crate::wallpaper::WallSetter;
+use crate::watcher::Change;
+use crate::{Output, ResolutionFilter, TagFilter, Wallpaper};
+
+pub const STATE_FILE_NAME: &str = "state.bin";
+/// Picks in a row the extensions may veto before giving up until the next change
+const MAX_VETOES: usize = 5;
+/// Changes remembered for going back to previous wallpapers
+const HISTORY_LEN: usize = 20;
+
+/// Wallpapers picked for an output, `None` standing for every output
+pub type Picks = Vec<(Option<Output>, std::path::PathBuf)>;
+
+/// Reads the wallpapers saved by `save_state`, `NotFound` when there is no state yet
+pub fn load_state(path: &std::path::Path) -> Result<Vec<Wallpaper>, std::io::Error> {
+    let state = std::fs::read(path)?;
+    serde_binary::from_vec(state, serde_binary::binary_stream::Endian::Little)
+        .map_err(|err| std::io::Error::other(err.to_string()))
+}
+
+pub fn save_state(
+    path: &std::path::Path,
+    wallpapers: &WallpaperIndex,
+) -> Result<(), std::io::Error> {
+    let state = serde_binary::to_vec(
+        &wallpapers.wallpapers(),
+        serde_binary::binary_stream::Endian::Little,
+    )
+    .map_err(|err| std::io::Error::other(err.to_string()))?;
+    std::fs::write(path, state)
+}
+
+/// Shows the picked wallpapers, implemented by `WallSetter` and by whatever embeds a `Rotator`
+pub trait Backend {
+    /// Passed to hooks as `WALLRUSTLER_BACKEND`
+    fn name(&self) -> &str {
+        "custom"
+    }
+
+    /// Outputs to pick separately for, none to pick a single wallpaper for all of them
+    fn outputs(&self) -> Vec<Output> {
+        vec![]
+    }
+
+    fn set(&mut self, picks: &[(Option<Output>, std::path::PathBuf)])
+        -> Result<(), std::io::Error>;
+
+    /// When work the backend deferred after a change is due
+    fn next_deadline(&self) -> Option<std::time::Instant> {
+        None
+    }
+
+    fn run_pending(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl Backend for WallSetter {
+    fn name(&self) -> &str {
+        self.backend()
+    }
+
+    fn outputs(&self) -> Vec<Output> {
+        self.get_outputs()
+    }
+
+    fn set(
+        &mut self,
+        picks: &[(Option<Output>, std::path::PathBuf)],
+    ) -> Result<(), std::io::Error> {
+        match picks {
+            [] => Ok(()),
+            [(None, wallpaper)] => self.set_wallpaper(wallpaper),
+            _ => {
+                let output_wallpapers: Vec<(Output, std::path::PathBuf)> = picks
+                    .iter()
+                    .filter_map(|(output, wallpaper)| Some((output.clone()?, wallpaper.clone())))
+                    .collect();
+                self.set_output_wallpapers(&output_wallpapers)
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Option<std::time::Instant> {
+        WallSetter::next_deadline(self)
+    }
+
+    fn run_pending(&mut self) -> Result<(), std::io::Error> {
+        WallSetter::run_pending(self)
+    }
+}
+
+/// A wallpaper about to be shown on an output, or just shown
+#[derive(Debug, Clone, Copy)]
+pub struct Pick<'a> {
+    /// `None` when the same wallpaper is shown on all outputs
+    pub output: Option<&'a Output>,
+    pub wallpaper: &'a std::path::Path,
+    /// Shown before on the same output, or on the first one
+    pub previous: Option<&'a std::path::Path>,
+    /// `None` for files outside of the collection
+    pub entry: Option<&'a Wallpaper>,
+    /// `Backend::name`
+    pub backend: &'a str,
+}
+
+/// What the extensions share during a change
+#[derive(Debug, PartialEq, Clone)]
+pub struct ChangeContext {
+    /// For extensions bounding the time they spend on a change
+    pub started: std::time::Instant,
+    /// `colors.json` of the change, written by the theme for the extensions added after it
+    pub colors: Option<std::path::PathBuf>,
+}
+
+/// Steps around every change, called in the order the extensions were added. The daemon plugs
+/// its hooks, image pipeline, live wallpapers, lock screen, theme and announcements in this way,
+/// extensions report their own errors
+pub trait Extension {
+    /// Whether the pick may be shown, a veto picks again
+    fn accept(&mut self, _change: &ChangeContext, _pick: &Pick) -> bool {
+        true
+    }
+
+    /// Indices of the picks the extension shows itself instead of the backend, like live
+    /// wallpapers. Called on every change, so it can stop showing them as well
+    fn show(&mut self, _picks: &[Pick]) -> Vec<usize> {
+        vec![]
+    }
+
+    /// The file the backend shows instead of the wallpaper, like a rendered variant; the first
+    /// extension returning one wins
+    fn prepare(&mut self, _pick: &Pick) -> Option<std::path::PathBuf> {
+        None
+    }
+
+    /// After the backend showed the picks
+    fn changed(&mut self, _change: &mut ChangeContext, _picks: &[Pick]) {}
+
+    /// When work the extension deferred is due
+    fn next_deadline(&self) -> Option<std::time::Instant> {
+        None
+    }
+
+    fn run_pending(&mut self) {}
+}
+
+type Callback = Box<dyn FnMut(&Event)>;
+
+/// What a `Rotator` reports to the callbacks registered with `on_event`
+#[derive(Debug, PartialEq, Clone)]
+pub enum Event {
+    Changed(Picks),
+    /// Nothing matches the filters, for the output when picking per output
+    NoMatch(Option<Output>),
+    /// The extensions vetoed every pick for the output, it keeps its wallpaper
+    Vetoed(Option<Output>),
+    /// The backend failed to show the picks
+    Failed(String),
+    /// The state couldn't be saved
+    SaveFailed(String),
+}
+
+/// The rotation engine without the daemon around it: the wallpaper collection, its persisted
+/// state, the schedule and filters picks follow and the backend showing them. Nothing blocks,
+/// `tick` changes the wallpapers when due and returns, `deadline` tells when to call it again
+pub struct Rotator {
+    wallpapers_dir_path: std::path::PathBuf,
+    /// `None` when the state isn't persisted
+    state_path: Option<std::path::PathBuf>,
+    wallpapers: WallpaperIndex,
+    schedule: Schedule,
+    tag_filter: TagFilter,
+    resolution_filter: ResolutionFilter,
+    per_output: bool,
+    live: bool,
+    show_new: bool,
+    rescan: bool,
+    backend: Box<dyn Backend>,
+    extensions: Vec<Box<dyn Extension>>,
+    seeded_rng: Option<SeededRng>,
+    next_change: std::time::Instant,
+    scheduled_entry: Option<usize>,
+    paused: bool,
+    /// Added while running, shown before picking when `show_new` is set
+    new_wallpapers: Vec<String>,
+    /// The last one is shown now
+    history: Vec<Picks>,
+    callbacks: Vec<Callback>,
+}
+
+impl Rotator {
+    pub fn builder(wallpapers_dir_path: impl Into<std::path::PathBuf>) -> RotatorBuilder {
+        RotatorBuilder {
+            wallpapers_dir_path: wallpapers_dir_path.into(),
+            state_path: None,
+            persist: true,
+            schedule: Schedule::default(),
+            tag_filter: TagFilter::default(),
+            resolution_filter: ResolutionFilter::default(),
+            per_output: false,
+            live: false,
+            show_new: false,
+            rescan: true,
+            backend: None,
+            extensions: vec![],
+            rng: None,
+            seed: None,
+            callbacks: vec![],
+        }
+    }
+
+    /// Runs deferred work and changes the wallpapers when due, returns whether they changed
+    pub fn tick(&mut self) -> bool {
+        self.run_pending();
+        if !self.is_due() {
+            return false;
+        }
+        let entry = self.scheduled_entry;
+        self.change_with(entry)
+    }
+
+    /// The deferred work of `tick` only, for callers changing the wallpapers by other means
+    pub fn run_pending(&mut self) {
+        if let Err(err) = self.backend.run_pending() {
+            self.emit(&Event::Failed(err.to_string()));
+        }
+        for extension in &mut self.extensions {
+            extension.run_pending();
+        }
+    }
+
+    /// Changes the wallpapers now, out of every scheduled pool, returns whether they changed
+    pub fn change(&mut self) -> bool {
+        self.change_with(None)
+    }
+
+    /// Shows the given wallpapers instead of picking, the next change is scheduled from now on
+    pub fn show(&mut self, picks: Picks) -> bool {
+        let changed = self.apply(picks, Self::change_context());
+        self.finish_change();
+
+        changed
+    }
+
+    /// Shows the wallpapers of the change before, returns whether they are shown now. The history
+    /// is kept as is when there is none or the backend fails
+    pub fn previous(&mut self) -> bool {
+        let Some(index) = self.history.len().checked_sub(2) else {
+            return false;
+        };
+        if !self.show(self.history[index].clone()) {
+            return false;
+        }
+        // Drops the change gone back from and the copy `show` added
+        self.history.truncate(self.history.len() - 2);
+
+        true
+    }
+
+    /// Stops scheduled changes, requested ones still happen
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Schedules the next change from now on
+    pub fn resume(&mut self) {
+        if self.paused {
+            self.paused = false;
+            self.schedule_next();
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Whether `tick` would change the wallpapers
+    pub fn is_due(&self) -> bool {
+        !self.paused && std::time::Instant::now() >= self.next_change
+    }
+
+    /// Delays the next change until `until` when it is due earlier
+    pub fn postpone(&mut self, until: std::time::Instant) {
+        self.next_change = self.next_change.max(until);
+    }
+
+    pub fn next_change(&self) -> std::time::Instant {
+        self.next_change
+    }
+
+    /// When to call `tick` again: the next change or deferred work, whichever is due first
+    pub fn deadline(&self) -> std::time::Instant {
+        let next_change = if self.paused {
+            std::time::Instant::now() + self.schedule.interval.period
+        } else {
+            self.next_change
+        };
+
+        self.extensions
+            .iter()
+            .filter_map(|extension| extension.next_deadline())
+            .chain(self.backend.next_deadline())
+            .fold(next_change, std::time::Instant::min)
+    }
+
+    /// Applies a change of the wallpaper directory, for callers watching it instead of having
+    /// every change rescan it
+    pub fn file_changed(&mut self, change: &Change) {
+        match change {
+            Change::Added(file_name) => {
+                if self.wallpapers.add(&self.wallpapers_dir_path, file_name) && self.show_new {
+                    self.new_wallpapers.push(file_name.clone());
+                }
+            }
+            Change::Removed(file_name) => {
+                self.wallpapers.remove(file_name);
+                self.new_wallpapers.retain(|new_wallpaper| {
+                    !std::path::Path::new(new_wallpaper).starts_with(file_name)
+                });
+                let removed_path = self.wallpapers_dir_path.join(file_name);
+                if self
+                    .current()
+                    .iter()
+                    .any(|(_, current)| current.starts_with(&removed_path))
+                {
+                    info!("Current wallpaper {file_name} was removed");
+                    self.next_change = std::time::Instant::now();
+                }
+            }
+            Change::Rescan => self.wallpapers.sync(&self.wallpapers_dir_path),
+        }
+    }
+
+    /// The wallpapers shown last
+    pub fn current(&self) -> &[(Option<Output>, std::path::PathBuf)] {
+        self.history.last().map_or(&[], |picks| picks.as_slice())
+    }
+
+    pub fn outputs(&self) -> Vec<Output> {
+        self.backend.outputs()
+    }
+
+    pub fn wallpapers(&self) -> &WallpaperIndex {
+        &self.wallpapers
+    }
+
+    pub fn wallpapers_mut(&mut self) -> &mut WallpaperIndex {
+        &mut self.wallpapers
+    }
+
+    pub fn on_event(&mut self, callback: impl FnMut(&Event) + 'static) {
+        self.callbacks.push(Box::new(callback));
+    }
+
+    /// Persists the view counts and, when seeded, the random state next to them
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        let Some(state_path) = &self.state_path else {
+            return Ok(());
+        };
+        save_state(state_path, &self.wallpapers)?;
+        if let Some(seeded_rng) = &self.seeded_rng {
+            seeded_rng.save(&SeededRng::state_path(state_path))?;
+        }
+
+        Ok(())
+    }
+
+    fn change_context() -> ChangeContext {
+        ChangeContext {
+            started: std::time::Instant::now(),
+            colors: None,
+        }
+    }
+
+    fn change_with(&mut self, entry: Option<usize>) -> bool {
+        let change = Self::change_context();
+        let entry_tags = entry.and_then(|index| self.schedule.cron[index].tags.clone());
+        let pool_prefix = entry
+            .filter(|_| entry_tags.is_some())
+            .map(|index| format!("cron{index}/"))
+            .unwrap_or_default();
+
+        if self.rescan {
+            self.wallpapers.sync(&self.wallpapers_dir_path);
+        }
+        if let Some(seeded_rng) = &mut self.seeded_rng {
+            self.wallpapers.set_rng(seeded_rng.next_rng());
+        }
+
+        let outputs: Vec<Option<Output>> = if self.per_output {
+            self.backend.outputs().into_iter().map(Some).collect()
+        } else {
+            vec![]
+        };
+        let outputs = if outputs.is_empty() {
+            vec![None]
+        } else {
+            outputs
+        };
+        let mut picks = vec![];
+        for output in outputs {
+            let pool = match &output {
+                Some(output) => format!(
+                    "{pool_prefix}{}:{}x{}",
+                    output.name, output.width, output.height
+                ),
+                None => pool_prefix.clone(),
+            };
+            let (live, tag_filter, entry_tags, resolution_filter, filter_output) = (
+                self.live,
+                self.tag_filter.clone(),
+                entry_tags.clone(),
+                self.resolution_filter.clone(),
+                output.clone(),
+            );
+            self.wallpapers.add_pool(&pool, move |wallpaper| {
+                (live || !wallpaper.is_video())
+                    && tag_filter.matches(wallpaper)
+                    && entry_tags
+                        .as_ref()
+                        .is_none_or(|tags| tags.matches(wallpaper))
+                    && resolution_filter.matches(wallpaper, filter_output.as_ref())
+            });
+            if let Some(wallpaper) = self.pick(&pool, output.as_ref(), &change) {
+                picks.push((output, wallpaper));
+            }
+        }
+
+        let changed = !picks.is_empty() && self.apply(picks, change);
+        self.finish_change();
+
+        changed
+    }
+
+    /// Picks out of the pool, offering every pick to the extensions, vetoed picks still count as
+    /// shown so they are less likely to come up again
+    fn pick(
+        &mut self,
+        pool: &str,
+        output: Option<&Output>,
+        change: &ChangeContext,
+    ) -> Option<std::path::PathBuf> {
+        let previous = previous_pick(self.current(), output).map(std::path::Path::to_path_buf);
+        for _ in 0..MAX_VETOES {
+            let Some(wallpaper) = self
+                .wallpapers
+                .take_new(&self.wallpapers_dir_path, pool, &mut self.new_wallpapers)
+                .or_else(|| self.wallpapers.pick(&self.wallpapers_dir_path, pool))
+            else {
+                self.emit(&Event::NoMatch(output.cloned()));
+                return None;
+            };
+            let pick = Pick {
+                output,
+                wallpaper: &wallpaper,
+                previous: previous.as_deref(),
+                entry: self
+                    .wallpapers
+                    .get_by_path(&self.wallpapers_dir_path, &wallpaper),
+                backend: self.backend.name(),
+            };
+            if self
+                .extensions
+                .iter_mut()
+                .all(|extension| extension.accept(change, &pick))
+            {
+                return Some(wallpaper);
+            }
+        }
+
+        self.emit(&Event::Vetoed(output.cloned()));
+        None
+    }
+
+    /// Hands the picks to the extensions showing them themselves and the rest, prepared, to the
+    /// backend
+    fn apply(&mut self, picks: Picks, mut change: ChangeContext) -> bool {
+        let backend_name = self.backend.name().to_string();
+        let current = self.current().to_vec();
+        let views: Vec<Pick> = picks
+            .iter()
+            .map(|(output, wallpaper)| Pick {
+                output: output.as_ref(),
+                wallpaper,
+                previous: previous_pick(&current, output.as_ref()),
+                entry: self
+                    .wallpapers
+                    .get_by_path(&self.wallpapers_dir_path, wallpaper),
+                backend: &backend_name,
+            })
+            .collect();
+
+        let mut shown = vec![false; views.len()];
+        for extension in &mut self.extensions {
+            for index in extension.show(&views) {
+                if let Some(shown) = shown.get_mut(index) {
+                    *shown = true;
+                }
+            }
+        }
+        let still_picks: Picks = views
+            .iter()
+            .zip(&shown)
+            .filter(|(_, shown)| !**shown)
+            .map(|(pick, _)| {
+                let wallpaper = self
+                    .extensions
+                    .iter_mut()
+                    .find_map(|extension| extension.prepare(pick))
+                    .unwrap_or_else(|| pick.wallpaper.to_path_buf());
+                (pick.output.cloned(), wallpaper)
+            })
+            .collect();
+        let result = if still_picks.is_empty() {
+            Ok(())
+        } else {
+            self.backend.set(&still_picks)
+        };
+        if result.is_ok() {
+            for extension in &mut self.extensions {
+                extension.changed(&mut change, &views);
+            }
+        }
+        drop(views);
+
+        match result {
+            Ok(()) => {
+                self.history.push(picks.clone());
+                if self.history.len() > HISTORY_LEN {
+                    self.history.remove(0);
+                }
+                self.emit(&Event::Changed(picks));
+                true
+            }
+            Err(err) => {
+                self.emit(&Event::Failed(err.to_string()));
+                false
+            }
+        }
+    }
+
+    fn finish_change(&mut self) {
+        if let Err(err) = self.save() {
+            self.emit(&Event::SaveFailed(err.to_string()));
+        }
+        self.schedule_next();
+    }
+
+    fn schedule_next(&mut self) {
+        let (delay, change) = self.schedule.next(self.wallpapers.rng());
+        debug!("Next change at {}", change.at.format("%Y-%m-%d %H:%M:%S"));
+        self.next_change = std::time::Instant::now() + delay;
+        self.scheduled_entry = change.entry;
+    }
+
+    fn emit(&mut self, event: &Event) {
+        for callback in &mut self.callbacks {
+            callback(event);
+        }
+    }
+}
+
+/// The wallpaper shown on `output`, or on the first output when it had none of its own
+fn previous_pick<'a>(
+    current: &'a [(Option<Output>, std::path::PathBuf)],
+    output: Option<&Output>,
+) -> Option<&'a std::path::Path> {
+    current
+        .iter()
+        .find(|(current_output, _)| {
+            current_output.as_ref().map(|o| &o.name) == output.map(|o| &o.name)
+        })
+        .or(current.first())
+        .map(|(_, wallpaper)| wallpaper.as_path())
+}
+
+pub struct RotatorBuilder {
+    wallpapers_dir_path: std::path::PathBuf,
+    state_path: Option<std::path::PathBuf>,
+    persist: bool,
+    schedule: Schedule,
+    tag_filter: TagFilter,
+    resolution_filter: ResolutionFilter,
+    per_output: bool,
+    live: bool,
+    show_new: bool,
+    rescan: bool,
+    backend: Option<Box<dyn Backend>>,
+    extensions: Vec<Box<dyn Extension>>,
+    rng: Option<Box<dyn rand::RngCore>>,
+    seed: Option<u64>,
+    callbacks: Vec<Callback>,
+}
+
+impl RotatorBuilder {
+    /// Defaults to `state.bin` in the wallpaper directory
+    pub fn state_path(mut self, state_path: impl Into<std::path::PathBuf>) -> RotatorBuilder {
+        self.state_path = Some(state_path.into());
+        self
+    }
+
+    /// Keeps the view counts in memory only
+    pub fn without_persistence(mut self) -> RotatorBuilder {
+        self.persist = false;
+        self
+    }
+
+    pub fn schedule(mut self, schedule: Schedule) -> RotatorBuilder {
+        self.schedule = schedule;
+        self
+    }
+
+    pub fn tags(mut self, tag_filter: TagFilter) -> RotatorBuilder {
+        self.tag_filter = tag_filter;
+        self
+    }
+
+    pub fn resolution(mut self, resolution_filter: ResolutionFilter) -> RotatorBuilder {
+        self.resolution_filter = resolution_filter;
+        self
+    }
+
+    /// Picks a wallpaper per output of the backend instead of one for all of them
+    pub fn per_output(mut self, per_output: bool) -> RotatorBuilder {
+        self.per_output = per_output;
+        self
+    }
+
+    /// Picks videos as well, for an extension playing them
+    pub fn live(mut self, live: bool) -> RotatorBuilder {
+        self.live = live;
+        self
+    }
+
+    /// Shows wallpapers added to the directory before picking
+    pub fn show_new(mut self, show_new: bool) -> RotatorBuilder {
+        self.show_new = show_new;
+        self
+    }
+
+    /// Rescans the directory before every change, on by default. Turn it off when passing the
+    /// changes of a watcher to `file_changed`
+    pub fn rescan(mut self, rescan: bool) -> RotatorBuilder {
+        self.rescan = rescan;
+        self
+    }
+
+    /// Defaults to a `WallSetter`, starting the wallpaper daemon it needs
+    pub fn backend(mut self, backend: impl Backend + 'static) -> RotatorBuilder {
+        self.backend = Some(Box::new(backend));
+        self
+    }
+
+    pub fn extension(mut self, extension: impl Extension + 'static) -> RotatorBuilder {
+        self.extensions.push(Box::new(extension));
+        self
+    }
+
+    /// Draws picks from `rng`, for tests; `seed` takes precedence
+    pub fn rng(mut self, rng: impl rand::RngCore + 'static) -> RotatorBuilder {
+        self.rng = Some(Box::new(rng));
+        self
+    }
+
+    /// Picks reproducibly like `--seed`, continuing from the random state persisted next to the
+    /// state
+    pub fn seed(mut self, seed: u64) -> RotatorBuilder {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn on_event(mut self, callback: impl FnMut(&Event) + 'static) -> RotatorBuilder {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    /// Loads the state, the first `tick` changes the wallpapers right away
+    pub fn build(self) -> Result<Rotator, std::io::Error> {
+        if !self.wallpapers_dir_path.is_dir() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{:?} is not a directory", self.wallpapers_dir_path),
+            ));
+        }
+        let state_path = self.persist.then(|| {
+            self.state_path
+                .unwrap_or_else(|| self.wallpapers_dir_path.join(STATE_FILE_NAME))
+        });
+        let wallpapers = match state_path.as_deref().map(load_state) {
+            Some(Ok(wallpapers)) => {
+                info!("Using previous state");
+                wallpapers
+            }
+            Some(Err(err)) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => vec![],
+        };
+        let mut wallpapers = WallpaperIndex::new(wallpapers);
+        wallpapers.sync(&self.wallpapers_dir_path);
+        if let Some(rng) = self.rng {
+            wallpapers.set_rng(rng);
+        }
+        let seeded_rng = self.seed.map(|seed| match &state_path {
+            Some(state_path) => SeededRng::load(&SeededRng::state_path(state_path), seed),
+            None => SeededRng::new(seed),
+        });
+
+        Ok(Rotator {
+            wallpapers_dir_path: self.wallpapers_dir_path,
+            state_path,
+            wallpapers,
+            schedule: self.schedule,
+            tag_filter: self.tag_filter,
+            resolution_filter: self.resolution_filter,
+            per_output: self.per_output,
+            live: self.live,
+            show_new: self.show_new,
+            rescan: self.rescan,
+            backend: self.backend.unwrap_or_else(|| {
+                let mut wall_setter = WallSetter::new();
+                wall_setter.init();
+                Box::new(wall_setter)
+            }),
+            extensions: self.extensions,
+            seeded_rng,
+            next_change: std::time::Instant::now(),
+            scheduled_entry: None,
+            paused: false,
+            new_wallpapers: vec![],
+            history: vec![],
+            callbacks: self.callbacks,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    type Shown = std::rc::Rc<std::cell::RefCell<Vec<Picks>>>;
+
+    struct FakeBackend {
+        shown: Shown,
+    }
+
+    impl Backend for FakeBackend {
+        fn set(
+            &mut self,
+            picks: &[(Option<Output>, std::path::PathBuf)],
+        ) -> Result<(), std::io::Error> {
+            self.shown.borrow_mut().push(picks.to_vec());
+            Ok(())
+        }
+    }
+
+    /// Fails once `fail` is set
+    struct FailingBackend {
+        fail: std::rc::Rc<std::cell::Cell<bool>>,
+    }
+
+    impl Backend for FailingBackend {
+        fn set(
+            &mut self,
+            _picks: &[(Option<Output>, std::path::PathBuf)],
+        ) -> Result<(), std::io::Error> {
+            if self.fail.get() {
+                return Err(std::io::Error::other("no display"));
+            }
+            Ok(())
+        }
+    }
+
+    /// Vetoes the wallpapers whose file name starts with the prefix
+    struct Veto(&'static str);
+
+    impl Extension for Veto {
+        fn accept(&mut self, _change: &ChangeContext, pick: &Pick) -> bool {
+            pick.entry
+                .is_some_and(|wallpaper| !wallpaper.file_name.starts_with(self.0))
+        }
+    }
+
+    /// Shows the first pick itself and renames the others
+    struct ShowFirst;
+
+    impl Extension for ShowFirst {
+        fn show(&mut self, _picks: &[Pick]) -> Vec<usize> {
+            vec![0]
+        }
+
+        fn prepare(&mut self, pick: &Pick) -> Option<std::path::PathBuf> {
+            Some(pick.wallpaper.with_extension("rendered"))
+        }
+    }
+
+    fn wallpapers_dir(name: &str, file_names: &[&str]) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("wallrustler-rotator-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for file_name in file_names {
+            image::RgbImage::new(2, 2)
+                .save(dir.join(file_name))
+                .unwrap();
+        }
+        dir
+    }
+
+    fn builder(dir: &std::path::Path, shown: &Shown) -> RotatorBuilder {
+        Rotator::builder(dir)
+            .without_persistence()
+            .backend(FakeBackend {
+                shown: shown.clone(),
+            })
+            .rng(rand_hc::Hc128Rng::seed_from_u64(1))
+    }
+
+    #[test]
+    fn tick_changes_when_due() {
+        let dir = wallpapers_dir("tick", &["a.png", "b.png"]);
+        let shown = Shown::default();
+        let mut rotator = builder(&dir, &shown).build().unwrap();
+
+        assert!(rotator.tick());
+        assert!(!rotator.tick());
+        assert_eq!(shown.borrow().len(), 1);
+        assert_eq!(rotator.current(), shown.borrow()[0].as_slice());
+        let (output, wallpaper) = shown.borrow()[0][0].clone();
+        assert_eq!(output, None);
+        assert!(wallpaper.starts_with(&dir));
+        assert!(rotator.next_change() > std::time::Instant::now());
+
+        assert!(rotator.change());
+        assert_eq!(shown.borrow().len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn same_seed_same_picks() {
+        let dir = wallpapers_dir("seed", &["a.png", "b.png", "c.png", "d.png"]);
+        let picks = |seed| {
+            let shown = Shown::default();
+            let mut rotator = builder(&dir, &shown).seed(seed).build().unwrap();
+            for _ in 0..10 {
+                rotator.change();
+            }
+            let shown = shown.borrow().clone();
+            shown
+        };
+
+        assert_eq!(picks(3), picks(3));
+        assert_ne!(picks(3), picks(4));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn vetoed_picks_are_picked_again() {
+        let dir = wallpapers_dir("veto", &["a.png", "b.png"]);
+        let shown = Shown::default();
+        let mut rotator = builder(&dir, &shown).extension(Veto("a")).build().unwrap();
+        for _ in 0..10 {
+            assert!(rotator.change());
+        }
+        assert!(shown
+            .borrow()
+            .iter()
+            .all(|picks| picks[0].1 == dir.join("b.png")));
+
+        let events = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let recorded = events.clone();
+        let shown = Shown::default();
+        let mut rotator = builder(&dir, &shown)
+            .extension(Veto(""))
+            .on_event(move |event| recorded.borrow_mut().push(event.clone()))
+            .build()
+            .unwrap();
+        assert!(!rotator.tick());
+        assert!(shown.borrow().is_empty());
+        assert_eq!(*events.borrow(), vec![Event::Vetoed(None)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn extensions_show_and_prepare_picks() {
+        let dir = wallpapers_dir("extensions", &["a.png"]);
+        let shown = Shown::default();
+        let mut rotator = builder(&dir, &shown).extension(ShowFirst).build().unwrap();
+        let (first, second) = (
+            Output {
+                name: "DP-1".to_string(),
+                width: 0,
+                height: 0,
+            },
+            Output {
+                name: "DP-2".to_string(),
+                width: 0,
+                height: 0,
+            },
+        );
+
+        assert!(rotator.show(vec![(None, dir.join("a.png"))]));
+        assert!(shown.borrow().is_empty());
+        assert_eq!(rotator.current(), [(None, dir.join("a.png"))]);
+
+        assert!(rotator.show(vec![
+            (Some(first), dir.join("a.png")),
+            (Some(second.clone()), dir.join("a.png")),
+        ]));
+        assert_eq!(
+            *shown.borrow(),
+            vec![vec![(Some(second), dir.join("a.rendered"))]]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn previous_pause_and_removal() {
+        let dir = wallpapers_dir("previous", &["a.png", "b.png", "c.png"]);
+        let shown = Shown::default();
+        let mut rotator = builder(&dir, &shown).rescan(false).build().unwrap();
+        assert!(!rotator.previous());
+        rotator.change();
+        rotator.change();
+        let first = shown.borrow()[0].clone();
+        assert!(rotator.previous());
+        assert_eq!(rotator.current(), first.as_slice());
+        assert_eq!(shown.borrow().last(), Some(&first));
+
+        rotator.pause();
+        rotator.next_change = std::time::Instant::now();
+        assert!(!rotator.is_due());
+        assert!(!rotator.tick());
+        rotator.resume();
+        assert!(!rotator.is_due());
+
+        let file_name = first[0].1.file_name().unwrap().to_str().unwrap();
+        rotator.file_changed(&Change::Removed(file_name.to_string()));
+        assert!(rotator.is_due());
+        assert!(rotator.wallpapers().get(file_name).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn random_state_is_saved_next_to_the_state() {
+        let dir = wallpapers_dir("state", &["a.png"]);
+        let state_dir = dir.join("state");
+        std::fs::create_dir(&state_dir).unwrap();
+        let mut rotator = Rotator::builder(&dir)
+            .state_path(state_dir.join("custom.bin"))
+            .backend(FakeBackend {
+                shown: Shown::default(),
+            })
+            .seed(5)
+            .build()
+            .unwrap();
+        rotator.change();
+
+        assert!(state_dir.join("custom.bin").is_file());
+        assert!(SeededRng::state_path(&state_dir.join("custom.bin")).is_file());
+        assert!(!SeededRng::state_path(&dir.join(STATE_FILE_NAME)).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_previous_keeps_the_history() {
+        let dir = wallpapers_dir("failing", &["a.png", "b.png", "c.png"]);
+        let fail = std::rc::Rc::new(std::cell::Cell::new(false));
+        let mut rotator = Rotator::builder(&dir)
+            .without_persistence()
+            .backend(FailingBackend { fail: fail.clone() })
+            .build()
+            .unwrap();
+        rotator.change();
+        let first = rotator.current().to_vec();
+        rotator.change();
+        let second = rotator.current().to_vec();
+
+        fail.set(true);
+        assert!(!rotator.previous());
+        assert_eq!(rotator.current(), second.as_slice());
+
+        fail.set(false);
+        assert!(rotator.previous());
+        assert_eq!(rotator.current(), first.as_slice());
+        assert!(!rotator.previous());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
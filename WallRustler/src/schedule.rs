@@ -0,0 +1,522 @@
+use chrono::{Datelike, Local, NaiveDateTime, TimeZone, Timelike};
+use rand::Rng;
+
+/// Changes never follow each other closer than this, whatever the jitter
+const MIN_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const DAY: u64 = 24 * 60 * 60;
+
+/// Time between changes, `15m±5m` varying it randomly by up to 5 minutes either way
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Interval {
+    pub period: std::time::Duration,
+    pub jitter: std::time::Duration,
+}
+
+impl Interval {
+    pub fn parse(s: &str) -> Option<Interval> {
+        let (period, jitter) = match s.split_once('±').or_else(|| s.split_once("+-")) {
+            Some((period, jitter)) => (period, Some(jitter)),
+            None => (s, None),
+        };
+        let period = parse_duration(period).filter(|period| !period.is_zero())?;
+        let jitter = match jitter {
+            Some(jitter) => parse_duration(jitter).filter(|jitter| *jitter < period)?,
+            None => std::time::Duration::ZERO,
+        };
+
+        Some(Interval { period, jitter })
+    }
+}
+
+impl Default for Interval {
+    fn default() -> Interval {
+        Interval {
+            period: std::time::Duration::from_secs(15 * 60),
+            jitter: std::time::Duration::ZERO,
+        }
+    }
+}
+
+/// `1h30m`, `90s`, `2d` or, like before units were supported, a bare number of minutes
+pub fn parse_duration(s: &str) -> Option<std::time::Duration> {
+    if let Ok(minutes) = s.parse::<u64>() {
+        return Some(std::time::Duration::from_secs(minutes.checked_mul(60)?));
+    }
+
+    let mut secs: u64 = 0;
+    let mut number = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        let unit = match c {
+            'd' => DAY,
+            'h' => 60 * 60,
+            'm' => 60,
+            's' => 1,
+            _ => return None,
+        };
+        secs = number
+            .parse::<u64>()
+            .ok()?
+            .checked_mul(unit)?
+            .checked_add(secs)?;
+        number.clear();
+    }
+    if !number.is_empty() || s.is_empty() {
+        return None;
+    }
+
+    Some(std::time::Duration::from_secs(secs))
+}
+
+/// Cron expressions are searched this far ahead, far enough for the 29th of February
+const CRON_SEARCH_DAYS: i64 = 8 * 366;
+const MONTH_NAMES: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// A five field cron expression, `minute hour day-of-month month day-of-week`, as bit sets
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CronExpr {
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    /// Sunday is bit 0
+    weekdays: u8,
+    /// Like cron, when both day fields are restricted either of them matching is enough
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl CronExpr {
+    /// Cron syntax with lists, ranges, steps and month and weekday names, the `@hourly`,
+    /// `@daily`, `@weekly`, `@monthly` and `@yearly` shorthands, or a calendar event like
+    /// `Mon-Fri 08:30` or `18:00`
+    pub fn parse(s: &str) -> Option<CronExpr> {
+        let s = s.trim();
+        let expr = match s.to_lowercase().as_str() {
+            "@hourly" => "0 * * * *".to_string(),
+            "@daily" | "@midnight" => "0 0 * * *".to_string(),
+            "@weekly" => "0 0 * * 0".to_string(),
+            "@monthly" => "0 0 1 * *".to_string(),
+            "@yearly" | "@annually" => "0 0 1 1 *".to_string(),
+            _ => calendar_event(s).unwrap_or_else(|| s.to_lowercase()),
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return None;
+        };
+
+        // 7 is Sunday as well
+        let weekday_bits = parse_field(weekdays, 0, 7, &WEEKDAY_NAMES, 0)?;
+        Some(CronExpr {
+            minutes: parse_field(minutes, 0, 59, &[], 0)?,
+            hours: parse_field(hours, 0, 23, &[], 0)? as u32,
+            days: parse_field(days, 1, 31, &[], 0)? as u32,
+            months: parse_field(months, 1, 12, &MONTH_NAMES, 1)? as u16,
+            weekdays: ((weekday_bits | weekday_bits >> 7) & 0x7f) as u8,
+            days_restricted: !days.starts_with('*'),
+            weekdays_restricted: !weekdays.starts_with('*'),
+        })
+    }
+
+    /// The first matching minute after `now`
+    pub fn next_after(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let start = now.with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        let first_day = start.date();
+        for date in (0..CRON_SEARCH_DAYS).map(|days| first_day + chrono::Duration::days(days)) {
+            if !self.matches_date(date) {
+                continue;
+            }
+            for hour in (0..24).filter(|hour| self.hours & 1 << hour != 0) {
+                for minute in (0..60).filter(|minute| self.minutes & 1 << minute != 0) {
+                    let candidate = date.and_hms_opt(hour, minute, 0)?;
+                    if candidate >= start {
+                        return Some(candidate);
+                    }
+                }
+            }
+        }
+
+        None
+    }
+
+    fn matches_date(&self, date: chrono::NaiveDate) -> bool {
+        if self.months & 1 << date.month() == 0 {
+            return false;
+        }
+        let day = self.days & 1 << date.day() != 0;
+        let weekday = self.weekdays & 1 << date.weekday().num_days_from_sunday() != 0;
+        if self.days_restricted && self.weekdays_restricted {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+}
+
+/// `Mon-Fri 08:30`, `Sat,Sun 10:00` or `18:00` as cron fields
+fn calendar_event(s: &str) -> Option<String> {
+    let (weekdays, time) = match s.rsplit_once(' ') {
+        Some((weekdays, time)) => (weekdays.trim().to_lowercase().replace("..", "-"), time),
+        None => ("*".to_string(), s),
+    };
+    let (hour, minute) = time.split_once(':')?;
+    let (hour, minute) = (hour.parse::<u8>().ok()?, minute.parse::<u8>().ok()?);
+    if weekdays.contains(' ') {
+        return None;
+    }
+
+    Some(format!("{minute} {hour} * * {weekdays}"))
+}
+
+/// Parses a cron field into a bit set with bit `n` standing for the value `n`, names map to
+/// `name_offset` and up
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str], name_offset: u32) -> Option<u64> {
+    let value = |s: &str| -> Option<u32> {
+        let value = match names.iter().position(|name| *name == s) {
+            Some(index) => index as u32 + name_offset,
+            None => s.parse::<u32>().ok()?,
+        };
+        (min..=max).contains(&value).then_some(value)
+    };
+
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().ok().filter(|step| *step > 0)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (value(start)?, value(end)?),
+                // `5/15` runs from 5 to the end of the range
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            },
+        };
+        if start > end {
+            return None;
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+
+    Some(bits)
+}
+
+/// A cron expression with the tags the wallpapers it changes to are picked by, on top of `--tags`
+#[derive(Debug, PartialEq, Clone)]
+pub struct CronEntry {
+    pub expr: CronExpr,
+    pub tags: Option<crate::TagFilter>,
+}
+
+impl CronEntry {
+    /// `EXPR[|TAGS]`, e.g. `0 18 * * *|evening,-work`
+    pub fn parse(s: &str) -> Option<CronEntry> {
+        let (expr, tags) = match s.split_once('|') {
+            Some((expr, tags)) => (expr, Some(crate::TagFilter::parse(tags)?)),
+            None => (s, None),
+        };
+
+        Some(CronEntry {
+            expr: CronExpr::parse(expr)?,
+            tags,
+        })
+    }
+}
+
+/// When the next change is due
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Schedule {
+    pub interval: Interval,
+    /// Change on multiples of the period counted from local midnight, or from the Unix epoch
+    /// for periods not dividing a day, so that every machine changes at the same moment
+    pub align: bool,
+    /// Replace the interval when not empty
+    pub cron: Vec<CronEntry>,
+}
+
+/// A scheduled change
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Change {
+    pub at: NaiveDateTime,
+    /// Index of the cron entry it is due to
+    pub entry: Option<usize>,
+}
+
+impl Schedule {
+    /// Time from now until the next change, and the change. The jitter is drawn from `rng`, the
+    /// generator of the picks so a seed reproduces the times as well
+    pub fn next(&self, rng: &mut dyn rand::RngCore) -> (std::time::Duration, Change) {
+        let now = Local::now().naive_local();
+        let change = self.next_change(now, rng);
+        let delay = (change.at - now)
+            .to_std()
+            .unwrap_or_default()
+            .max(MIN_DELAY);
+        (delay, change)
+    }
+
+    /// The next change after `now`, the earliest of the cron entries when there are any,
+    /// falling back to the interval when none of them ever matches
+    pub fn next_change(&self, now: NaiveDateTime, rng: &mut dyn rand::RngCore) -> Change {
+        self.cron
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| {
+                entry.expr.next_after(now).map(|at| Change {
+                    at,
+                    entry: Some(index),
+                })
+            })
+            .min_by_key(|change| change.at)
+            .unwrap_or_else(|| Change {
+                at: self.next_after(now, rng),
+                entry: None,
+            })
+    }
+
+    /// The next change of the interval after `now`, in local time
+    pub fn next_after(&self, now: NaiveDateTime, rng: &mut dyn rand::RngCore) -> NaiveDateTime {
+        let period = self.interval.period;
+        let next = if self.align {
+            // A boundary right after a change is skipped rather than changing twice in a row
+            next_boundary(now + MIN_DELAY, period.as_secs().max(1))
+        } else {
+            now + period
+        };
+        if self.interval.jitter.is_zero() {
+            return next;
+        }
+
+        let jitter = self.interval.jitter.as_secs_f64();
+        let offset = rng.gen_range(-jitter..jitter);
+        let jittered = next + chrono::Duration::milliseconds((offset * 1000.0) as i64);
+        jittered.max(now)
+    }
+}
+
+fn next_boundary(now: NaiveDateTime, period: u64) -> NaiveDateTime {
+    if DAY.is_multiple_of(period) {
+        let midnight = now.date().and_hms_opt(0, 0, 0).unwrap();
+        let elapsed = (now - midnight).num_seconds() as u64;
+        return midnight + chrono::Duration::seconds(((elapsed / period + 1) * period) as i64);
+    }
+
+    // Local time is only used to express the result, the epoch anchors it
+    let Some(now_utc) = Local
+        .from_local_datetime(&now)
+        .earliest()
+        .map(|now| now.timestamp())
+    else {
+        return now + chrono::Duration::seconds(period as i64);
+    };
+    let next = (now_utc as u64 / period + 1) * period;
+    chrono::DateTime::from_timestamp(next as i64, 0)
+        .map(|next| next.with_timezone(&Local).naive_local())
+        .unwrap_or(now + chrono::Duration::seconds(period as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> std::time::Duration {
+        std::time::Duration::from_secs(secs)
+    }
+
+    fn at(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("15"), Some(secs(15 * 60)));
+        assert_eq!(parse_duration("90s"), Some(secs(90)));
+        assert_eq!(parse_duration("1h30m"), Some(secs(90 * 60)));
+        assert_eq!(parse_duration("2d1s"), Some(secs(2 * DAY + 1)));
+        assert_eq!(parse_duration("0s"), Some(secs(0)));
+        for invalid in ["", "m", "1h30", "1x", "-5m", "1.5h", "99999999999999999999"] {
+            assert_eq!(parse_duration(invalid), None, "{invalid}");
+        }
+        assert_eq!(parse_duration(&format!("{}d", u64::MAX / DAY + 1)), None);
+    }
+
+    #[test]
+    fn intervals() {
+        assert_eq!(
+            Interval::parse("15m±5m"),
+            Some(Interval {
+                period: secs(15 * 60),
+                jitter: secs(5 * 60)
+            })
+        );
+        assert_eq!(Interval::parse("15m+-5m"), Interval::parse("15m±5m"));
+        assert_eq!(
+            Interval::parse("30s"),
+            Some(Interval {
+                period: secs(30),
+                jitter: secs(0)
+            })
+        );
+        // The jitter has to stay below the period
+        assert_eq!(Interval::parse("5m±5m"), None);
+        assert_eq!(Interval::parse("0m"), None);
+        assert_eq!(Interval::parse("15m±"), None);
+    }
+
+    #[test]
+    fn interval_changes() {
+        let mut rng = crate::rng::SeededRng::new(1).next_rng();
+        let mut schedule = Schedule {
+            interval: Interval::parse("15m").unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(
+            schedule.next_after(at("2024-03-01 10:07:30"), &mut rng),
+            at("2024-03-01 10:22:30")
+        );
+
+        schedule.align = true;
+        assert_eq!(
+            schedule.next_after(at("2024-03-01 10:07:30"), &mut rng),
+            at("2024-03-01 10:15:00")
+        );
+        // Right on a boundary the next one is due, not the same
+        assert_eq!(
+            schedule.next_after(at("2024-03-01 10:15:00"), &mut rng),
+            at("2024-03-01 10:30:00")
+        );
+        assert_eq!(
+            schedule.next_after(at("2024-03-01 23:50:00"), &mut rng),
+            at("2024-03-02 00:00:00")
+        );
+    }
+
+    #[test]
+    fn jitter_is_drawn_from_the_rng() {
+        let schedule = Schedule {
+            interval: Interval::parse("15m±5m").unwrap(),
+            ..Default::default()
+        };
+        let now = at("2024-03-01 10:00:00");
+        let changes = |seed| {
+            let mut rng = crate::rng::SeededRng::new(seed).next_rng();
+            (0..20)
+                .map(|_| schedule.next_after(now, &mut rng))
+                .collect::<Vec<_>>()
+        };
+
+        let changes_1 = changes(1);
+        assert_eq!(changes_1, changes(1));
+        assert_ne!(changes_1, changes(2));
+        for change in changes_1 {
+            assert!(change >= at("2024-03-01 10:10:00") && change <= at("2024-03-01 10:20:00"));
+        }
+    }
+
+    fn next(expr: &str, now: &str) -> Option<NaiveDateTime> {
+        CronExpr::parse(expr).unwrap().next_after(at(now))
+    }
+
+    #[test]
+    fn cron_fields() {
+        assert_eq!(
+            next("*/15 9-17 * * mon-fri", "2024-03-01 17:50:00"),
+            Some(at("2024-03-04 09:00:00"))
+        );
+        assert_eq!(
+            next("5/20 * * * *", "2024-03-01 10:46:00"),
+            Some(at("2024-03-01 11:05:00"))
+        );
+        assert_eq!(
+            next("0 0 29 feb *", "2024-03-01 00:00:00"),
+            Some(at("2028-02-29 00:00:00"))
+        );
+        // 7 is Sunday as well
+        assert_eq!(
+            next("30 8 * * 7", "2024-03-01 00:00:00"),
+            Some(at("2024-03-03 08:30:00"))
+        );
+        assert_eq!(next("0 0 31 2 *", "2024-03-01 00:00:00"), None);
+        for invalid in [
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "5-1 * * * *",
+            "*/0 * * * *",
+        ] {
+            assert_eq!(CronExpr::parse(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn cron_day_fields_match_either() {
+        // The 13th, or any Friday
+        let expr = "0 12 13 * fri";
+        assert_eq!(
+            next(expr, "2024-03-01 12:00:00"),
+            Some(at("2024-03-08 12:00:00"))
+        );
+        assert_eq!(
+            next(expr, "2024-03-11 00:00:00"),
+            Some(at("2024-03-13 12:00:00"))
+        );
+    }
+
+    #[test]
+    fn cron_shorthands_and_calendar_events() {
+        assert_eq!(CronExpr::parse("@daily"), CronExpr::parse("0 0 * * *"));
+        assert_eq!(CronExpr::parse("@weekly"), CronExpr::parse("0 0 * * sun"));
+        assert_eq!(
+            CronExpr::parse("Mon..Fri 08:30"),
+            CronExpr::parse("30 8 * * 1-5")
+        );
+        assert_eq!(CronExpr::parse("18:00"), CronExpr::parse("0 18 * * *"));
+        assert_eq!(CronExpr::parse("Mon Fri 08:30"), None);
+        assert_eq!(CronExpr::parse("25:00"), None);
+    }
+
+    #[test]
+    fn earliest_cron_entry_wins() {
+        let mut rng = crate::rng::SeededRng::new(1).next_rng();
+        let schedule = Schedule {
+            cron: vec![
+                CronEntry::parse("0 18 * * *").unwrap(),
+                CronEntry::parse("0 8 * * *|morning,-work").unwrap(),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            schedule.next_change(at("2024-03-01 07:00:00"), &mut rng),
+            Change {
+                at: at("2024-03-01 08:00:00"),
+                entry: Some(1)
+            }
+        );
+        assert_eq!(
+            schedule.cron[1].tags,
+            crate::TagFilter::parse("morning,-work")
+        );
+
+        let never = Schedule {
+            cron: vec![CronEntry::parse("0 0 31 2 *").unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(
+            never.next_change(at("2024-03-01 07:00:00"), &mut rng),
+            Change {
+                at: at("2024-03-01 07:15:00"),
+                entry: None
+            }
+        );
+    }
+}
@@ -0,0 +1,687 @@
+/// Per request, listings are small and most wallpapers are a few MiB
+const LISTING_TIMEOUT_SECS: u64 = 30;
+const DOWNLOAD_TIMEOUT_SECS: u64 = 300;
+pub const DEFAULT_MAX_SIZE: u64 = 256 * 1024 * 1024;
+pub const DEFAULT_REFRESH: std::time::Duration = std::time::Duration::from_secs(6 * 60 * 60);
+/// Marks a directory as a mirror of a source, nothing else is ever pruned
+const MARKER_FILE_NAME: &str = ".wallrustler-source";
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum SourceKind {
+    /// An HTML page linking to the images, like the index of a directory served over HTTP
+    HttpIndex,
+    /// Image enclosures and media content of an RSS or Atom feed
+    Feed,
+    /// A JSON endpoint describing images, like Bing's image archive or Unsplash's random photos.
+    /// Holds the JSON pointer of the image URLs, `*` matching every array element or object
+    /// member; every string member named `url` when `None`
+    Json(Option<String>),
+}
+
+/// Remote wallpapers mirrored into a subdirectory of the wallpaper directory, so they are
+/// picked, tagged with the directory name and announced like local ones
+#[derive(Debug, PartialEq, Clone)]
+pub struct Source {
+    pub kind: SourceKind,
+    pub url: String,
+    /// Subdirectory of the wallpaper directory, the host name of `url` by default
+    pub name: String,
+    /// Only the first images of the listing fitting into this many bytes are kept
+    pub max_size: u64,
+}
+
+impl Source {
+    /// Parses `http:<url>`, `feed:<url>` and `json:<url>[#<pointer>]`
+    pub fn parse(s: &str) -> Option<Source> {
+        let (kind, url) = s.split_once(':')?;
+        let (kind, url) = match kind {
+            "http" => (SourceKind::HttpIndex, url.to_string()),
+            "feed" => (SourceKind::Feed, url.to_string()),
+            "json" => match url.rsplit_once('#') {
+                Some((url, pointer)) if pointer.starts_with('/') => {
+                    (SourceKind::Json(Some(pointer.to_string())), url.to_string())
+                }
+                _ => (SourceKind::Json(None), url.to_string()),
+            },
+            _ => return None,
+        };
+        let (_, host) = split_url(&url)?;
+        let host = host.split(['/', '?', '#']).next()?;
+        let host = host.rsplit_once('@').map_or(host, |(_, host)| host);
+        let name = host.strip_prefix("www.").unwrap_or(host).replace(':', "_");
+
+        Some(Source {
+            kind,
+            url,
+            name,
+            max_size: DEFAULT_MAX_SIZE,
+        })
+    }
+
+    /// Downloads the images of the listing missing from the source directory, then removes the
+    /// ones no longer listed or over the size limit
+    pub fn fetch(&self, wallpaper_dir_path: &std::path::Path) -> Result<(), std::io::Error> {
+        let source_dir = wallpaper_dir_path.join(&self.name);
+        self.claim_dir(&source_dir)?;
+
+        let listing = download_text(&self.url)?;
+        let urls = match &self.kind {
+            SourceKind::HttpIndex => parse_http_index(&listing),
+            SourceKind::Feed => parse_feed(&listing),
+            SourceKind::Json(pointer) => parse_json(&listing, pointer.as_deref())?,
+        };
+        let mut seen = std::collections::HashSet::new();
+        let urls: Vec<String> = urls
+            .into_iter()
+            .filter_map(|url| resolve_url(&self.url, &url))
+            .filter(|url| seen.insert(url.clone()))
+            .collect();
+        debug!("{} lists {} wallpapers", self.url, urls.len());
+
+        // Only files named like downloads, anything else in there is left alone
+        let mut existing: std::collections::HashMap<String, std::path::PathBuf> =
+            std::fs::read_dir(&source_dir)?
+                .filter_map(|dir_entry| dir_entry.ok())
+                .filter_map(|dir_entry| {
+                    let path = dir_entry.path();
+                    let stem = path.file_stem()?.to_str()?.to_string();
+                    let is_download =
+                        stem.len() == 16 && stem.chars().all(|c| c.is_ascii_hexdigit());
+                    (is_download && path.is_file()).then_some((stem, path))
+                })
+                .collect();
+
+        let mut kept = std::collections::HashSet::new();
+        let mut total_size = 0;
+        for url in urls {
+            if total_size >= self.max_size {
+                break;
+            }
+            let stem = format!("{:016x}", hash_url(&url));
+            let path = match existing.remove(&stem) {
+                Some(path) => Ok(path),
+                None => download_file(&url, &source_dir, &stem, self.max_size - total_size),
+            };
+            match path {
+                Ok(path) => {
+                    total_size += std::fs::metadata(&path)?.len();
+                    kept.insert(path);
+                }
+                Err(err) => warn!("Unable to download {url}: {err}"),
+            }
+        }
+
+        for path in existing.into_values() {
+            if !kept.contains(&path) {
+                debug!("Removing {:?}, no longer listed by {}", path, self.url);
+                std::fs::remove_file(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates the source directory with its marker, an existing directory is only taken over
+    /// when it is marked or empty so a name clashing with a local folder never prunes it
+    fn claim_dir(&self, source_dir: &std::path::Path) -> Result<(), std::io::Error> {
+        let marker_path = source_dir.join(MARKER_FILE_NAME);
+        if marker_path.is_file() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(source_dir)?;
+        if std::fs::read_dir(source_dir)?.next().is_some() {
+            return Err(std::io::Error::other(format!(
+                "{source_dir:?} exists and isn't a source directory, it has no {MARKER_FILE_NAME}"
+            )));
+        }
+
+        std::fs::write(marker_path, format!("{}\n", self.url))
+    }
+}
+
+/// Fetches every source now and then again every `refresh` on a background thread, new files
+/// reach the index through the directory watcher
+pub fn spawn_fetcher(
+    sources: Vec<Source>,
+    wallpaper_dir_path: std::path::PathBuf,
+    refresh: std::time::Duration,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || loop {
+        for source in &sources {
+            info!("Fetching {}", source.url);
+            if let Err(err) = source.fetch(&wallpaper_dir_path) {
+                error!("Unable to fetch {}: {err}", source.url);
+            }
+        }
+        std::thread::sleep(refresh);
+    })
+}
+
+fn download_text(url: &str) -> Result<String, std::io::Error> {
+    let output = crate::run_command(
+        std::process::Command::new("curl")
+            .args(["--fail", "--silent", "--show-error", "--location"])
+            .args(["--max-time", &LISTING_TIMEOUT_SECS.to_string()])
+            .arg("--")
+            .arg(url),
+    )?;
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Downloads into the cache, so the watcher never sees partial files, and names the file after
+/// its detected format, files that are neither images nor videos are dropped
+fn download_file(
+    url: &str,
+    dir: &std::path::Path,
+    stem: &str,
+    max_size: u64,
+) -> Result<std::path::PathBuf, std::io::Error> {
+    let partial_path = crate::get_cache_dir()
+        .join("downloads")
+        .join(format!("{stem}.partial"));
+    std::fs::create_dir_all(partial_path.parent().unwrap())?;
+    let result = crate::run_command(
+        std::process::Command::new("curl")
+            .args(["--fail", "--silent", "--show-error", "--location"])
+            .args(["--max-time", &DOWNLOAD_TIMEOUT_SECS.to_string()])
+            .args(["--max-filesize", &max_size.to_string()])
+            .arg("--output")
+            .arg(&partial_path)
+            .arg("--")
+            .arg(url),
+    );
+    if let Err(err) = result {
+        let _ = std::fs::remove_file(&partial_path);
+        return Err(err);
+    }
+
+    let extension = if crate::metadata::has_image_signature(&partial_path) {
+        image::ImageReader::open(&partial_path)?
+            .with_guessed_format()?
+            .format()
+            .and_then(|format| format.extensions_str().first().copied())
+    } else {
+        url_path(url)
+            .rsplit_once('.')
+            .map(|(_, extension)| extension)
+            .filter(|extension| {
+                crate::is_video_file(std::path::Path::new(&format!("_.{extension}")))
+            })
+    };
+    let Some(extension) = extension else {
+        std::fs::remove_file(&partial_path)?;
+        return Err(std::io::Error::other("Neither an image nor a video"));
+    };
+
+    let path = dir.join(format!("{stem}.{}", extension.to_lowercase()));
+    // The cache and the wallpaper directory might be on different file systems
+    if std::fs::rename(&partial_path, &path).is_err() {
+        std::fs::copy(&partial_path, &path)?;
+        std::fs::remove_file(&partial_path)?;
+    }
+    info!("Downloaded {url}");
+
+    Ok(path)
+}
+
+/// Links of an HTML page pointing at image or video files
+fn parse_http_index(html: &str) -> Vec<String> {
+    let mut urls = vec![];
+    let mut rest = html;
+    while let Some(start) = rest.find("href=") {
+        rest = &rest[start + "href=".len()..];
+        let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            continue;
+        };
+        let Some((href, _)) = rest[1..].split_once(quote) else {
+            break;
+        };
+        let href = unescape_xml(href);
+        if crate::is_wallpaper_file(std::path::Path::new(url_path(&href))) {
+            urls.push(href);
+        }
+    }
+
+    urls
+}
+
+/// `enclosure` and `media:content` URLs of an RSS feed and `enclosure` links of an Atom feed,
+/// skipping the ones whose type says they aren't images or videos
+fn parse_feed(xml: &str) -> Vec<String> {
+    let mut urls = vec![];
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[..end];
+        rest = &rest[end..];
+
+        let name = tag
+            .split(|c: char| c.is_whitespace() || c == '/')
+            .next()
+            .unwrap_or("");
+        let url = match name {
+            "enclosure" | "media:content" => xml_attribute(tag, "url"),
+            "link" if xml_attribute(tag, "rel").as_deref() == Some("enclosure") => {
+                xml_attribute(tag, "href")
+            }
+            _ => None,
+        };
+        let is_media = xml_attribute(tag, "type")
+            .or_else(|| xml_attribute(tag, "medium"))
+            .is_none_or(|media_type| {
+                media_type.starts_with("image") || media_type.starts_with("video")
+            });
+        if let Some(url) = url.filter(|_| is_media) {
+            urls.push(url);
+        }
+    }
+
+    urls
+}
+
+fn xml_attribute(tag: &str, name: &str) -> Option<String> {
+    let mut rest = tag;
+    loop {
+        let start = rest.find(name)?;
+        let preceded_by_space = rest[..start].ends_with(char::is_whitespace);
+        rest = &rest[start + name.len()..];
+        let Some(value) = rest.trim_start().strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let Some(quote) = value.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            continue;
+        };
+        if !preceded_by_space {
+            continue;
+        }
+
+        return value[1..]
+            .split_once(quote)
+            .map(|(value, _)| unescape_xml(value));
+    }
+}
+
+fn unescape_xml(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let c = match &rest[1..end] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            entity => entity
+                .strip_prefix("#x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match c {
+            Some(c) => {
+                unescaped.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+
+    unescaped
+}
+
+fn parse_json(json: &str, pointer: Option<&str>) -> Result<Vec<String>, std::io::Error> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    let mut urls = vec![];
+    match pointer {
+        Some(pointer) => {
+            let segments: Vec<&str> = pointer.split('/').skip(1).collect();
+            select_json(&value, &segments, &mut urls);
+        }
+        None => find_json_urls(&value, &mut urls),
+    }
+
+    Ok(urls)
+}
+
+/// Collects the strings at the JSON pointer `segments`, `*` matching every child
+fn select_json(value: &serde_json::Value, segments: &[&str], urls: &mut Vec<String>) {
+    let Some((segment, rest)) = segments.split_first() else {
+        if let Some(url) = value.as_str() {
+            urls.push(url.to_string());
+        }
+        return;
+    };
+
+    match (value, *segment) {
+        (serde_json::Value::Array(values), "*") => {
+            for value in values {
+                select_json(value, rest, urls);
+            }
+        }
+        (serde_json::Value::Object(members), "*") => {
+            for value in members.values() {
+                select_json(value, rest, urls);
+            }
+        }
+        (value, segment) => {
+            let segment = segment.replace("~1", "/").replace("~0", "~");
+            let child = match value {
+                serde_json::Value::Array(values) => {
+                    segment.parse::<usize>().ok().and_then(|i| values.get(i))
+                }
+                serde_json::Value::Object(members) => members.get(&segment),
+                _ => None,
+            };
+            if let Some(child) = child {
+                select_json(child, rest, urls);
+            }
+        }
+    }
+}
+
+fn find_json_urls(value: &serde_json::Value, urls: &mut Vec<String>) {
+    match value {
+        serde_json::Value::Array(values) => {
+            for value in values {
+                find_json_urls(value, urls);
+            }
+        }
+        serde_json::Value::Object(members) => {
+            for (name, value) in members {
+                match value {
+                    serde_json::Value::String(url) if name == "url" => urls.push(url.clone()),
+                    value => find_json_urls(value, urls),
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Splits `scheme://rest` into the scheme and the rest
+fn split_url(url: &str) -> Option<(&str, &str)> {
+    url.split_once("://").filter(|(scheme, _)| {
+        !scheme.is_empty()
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    })
+}
+
+/// The path of a URL, without its query and fragment
+fn url_path(url: &str) -> &str {
+    let path = split_url(url).map_or(url, |(_, rest)| rest.find('/').map_or("", |i| &rest[i..]));
+    path.split(['?', '#']).next().unwrap_or("")
+}
+
+/// Resolves a link relative to the page it appeared on
+fn resolve_url(base: &str, href: &str) -> Option<String> {
+    let href = href.trim();
+    if href.is_empty() || href.starts_with('#') {
+        return None;
+    }
+    if let Some((scheme, _)) = split_url(href) {
+        return matches!(scheme, "http" | "https").then(|| href.to_string());
+    }
+
+    let (scheme, rest) = split_url(base)?;
+    if let Some(href) = href.strip_prefix("//") {
+        return Some(format!("{scheme}://{href}"));
+    }
+    let host_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let host = &rest[..host_end];
+    if href.starts_with('/') {
+        return Some(format!("{scheme}://{host}{href}"));
+    }
+
+    let base_path = url_path(base);
+    let base_dir = base_path.rfind('/').map_or("/", |i| &base_path[..=i]);
+    let mut segments: Vec<&str> = vec![];
+    for segment in base_dir.split('/').chain(href.split('/')) {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    let mut path = segments.join("/");
+    if href.ends_with('/') {
+        path.push('/');
+    }
+
+    Some(format!("{scheme}://{host}/{path}"))
+}
+
+fn hash_url(url: &str) -> u64 {
+    use std::hash::Hasher;
+    let mut hasher = crate::StableHasher::default();
+    hasher.write(url.as_bytes());
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_http_indexes() {
+        let html = r#"<a href="a.jpg">a</a> <a href='sub/b%20c.PNG?x=1'>b</a>
+            <a href="notes.txt">notes</a> <a href=unquoted.jpg>u</a>
+            <a href="d.jpg?a=1&amp;b=2">d</a> <a href="e.jpg"#;
+        assert_eq!(
+            parse_http_index(html),
+            vec!["a.jpg", "sub/b%20c.PNG?x=1", "d.jpg?a=1&b=2"]
+        );
+    }
+
+    #[test]
+    fn parses_feeds() {
+        let xml = r#"<rss><channel><item>
+            <enclosure url="https://example.com/a.jpg" type="image/jpeg"/>
+            <enclosure url="https://example.com/a.mp3" type="audio/mpeg"/>
+            <media:content medium="image" url='https://example.com/b.png'/>
+            <media:content url="https://example.com/c.webp"/>
+            <link>https://example.com/post</link>
+            </item></channel></rss>
+            <feed><entry><link rel="enclosure" type="image/png" href="https://example.com/d.png?a=1&amp;b=2"/>
+            <link rel="alternate" href="https://example.com/entry"/><thumbnail myurl="x"/></entry></feed>"#;
+        assert_eq!(
+            parse_feed(xml),
+            vec![
+                "https://example.com/a.jpg",
+                "https://example.com/b.png",
+                "https://example.com/c.webp",
+                "https://example.com/d.png?a=1&b=2",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_json() {
+        let json = r#"{"images": [{"url": "/a.jpg", "thumb": {"url": "/a_s.jpg"}},
+            {"url": "/b.jpg"}], "meta": {"a/b": "/c.jpg"}}"#;
+        assert_eq!(
+            parse_json(json, None).unwrap(),
+            vec!["/a_s.jpg", "/a.jpg", "/b.jpg"]
+        );
+        assert_eq!(
+            parse_json(json, Some("/images/*/url")).unwrap(),
+            vec!["/a.jpg", "/b.jpg"]
+        );
+        assert_eq!(
+            parse_json(json, Some("/images/1/url")).unwrap(),
+            vec!["/b.jpg"]
+        );
+        assert_eq!(
+            parse_json(json, Some("/meta/a~1b")).unwrap(),
+            vec!["/c.jpg"]
+        );
+        assert!(parse_json(json, Some("/missing")).unwrap().is_empty());
+        assert!(parse_json("{", None).is_err());
+    }
+
+    #[test]
+    fn resolves_urls() {
+        let base = "https://example.com/walls/index.html?page=2";
+        assert_eq!(
+            resolve_url(base, "a.jpg").as_deref(),
+            Some("https://example.com/walls/a.jpg")
+        );
+        assert_eq!(
+            resolve_url(base, "../b/./c.jpg").as_deref(),
+            Some("https://example.com/b/c.jpg")
+        );
+        assert_eq!(
+            resolve_url(base, "/d.jpg").as_deref(),
+            Some("https://example.com/d.jpg")
+        );
+        assert_eq!(
+            resolve_url(base, "//cdn.example.com/e.jpg").as_deref(),
+            Some("https://cdn.example.com/e.jpg")
+        );
+        assert_eq!(resolve_url(base, "file:///etc/passwd"), None);
+        assert_eq!(resolve_url(base, "#top"), None);
+    }
+
+    #[test]
+    fn only_claims_marked_or_empty_dirs() {
+        let dir = std::env::temp_dir().join(format!("wallrustler-source-{}", std::process::id()));
+        let source = Source::parse("http:https://example.com/walls/").unwrap();
+        assert_eq!(source.name, "example.com");
+        let source_dir = dir.join(&source.name);
+
+        source.claim_dir(&source_dir).unwrap();
+        assert!(source_dir.join(MARKER_FILE_NAME).is_file());
+        std::fs::write(source_dir.join("0123456789abcdef.jpg"), "").unwrap();
+        source.claim_dir(&source_dir).unwrap();
+
+        let local_dir = dir.join("local");
+        std::fs::create_dir_all(&local_dir).unwrap();
+        std::fs::write(local_dir.join("0123456789abcdef.jpg"), "").unwrap();
+        let claimed = source.claim_dir(&local_dir);
+        let marked = local_dir.join(MARKER_FILE_NAME).exists();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(claimed.is_err());
+        assert!(!marked);
+    }
+
+    /// Serves `files` by path on a loopback port, returns the base URL
+    fn serve(files: Vec<(&'static str, Vec<u8>)>) -> String {
+        use std::io::{BufRead, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                let _ = reader.read_line(&mut request_line);
+                let mut header = String::new();
+                while reader.read_line(&mut header).is_ok_and(|len| len > 2) {
+                    header.clear();
+                }
+                let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+                let (status, body) = match files.iter().find(|(file, _)| *file == path) {
+                    Some((_, body)) => ("200 OK", body.as_slice()),
+                    None => ("404 Not Found", &b""[..]),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(body);
+            }
+        });
+
+        format!("http://{address}")
+    }
+
+    fn png(size: u32) -> Vec<u8> {
+        let mut png = std::io::Cursor::new(vec![]);
+        image::RgbImage::new(size, size)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        png.into_inner()
+    }
+
+    #[test]
+    fn fetches_from_a_stand_in_server() {
+        let small = png(4);
+        let base_url = serve(vec![
+            (
+                "/walls/",
+                br#"<a href="a.jpg">a</a> <a href="big.jpg">big</a>
+                    <a href="text.jpg">text</a> <a href="c.jpg">c</a>"#
+                    .to_vec(),
+            ),
+            // Named after its content rather than the URL
+            ("/walls/a.jpg", small.clone()),
+            ("/walls/big.jpg", vec![0; 64 * 1024]),
+            ("/walls/text.jpg", b"not an image".to_vec()),
+            ("/walls/c.jpg", png(2)),
+        ]);
+        let dir =
+            std::env::temp_dir().join(format!("wallrustler-source-fetch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let source = Source {
+            max_size: small.len() as u64 + 1024,
+            ..Source::parse(&format!("http:{base_url}/walls/")).unwrap()
+        };
+        let source_dir = dir.join(&source.name);
+        std::fs::create_dir_all(&source_dir).unwrap();
+        std::fs::write(source_dir.join(MARKER_FILE_NAME), "").unwrap();
+        // A download no longer listed, and a file the source never wrote
+        std::fs::write(source_dir.join("0123456789abcdef.jpg"), "").unwrap();
+        std::fs::write(source_dir.join("notes.txt"), "").unwrap();
+
+        source.fetch(&dir).unwrap();
+        let file_name =
+            |url: &str| format!("{:016x}.png", hash_url(&format!("{base_url}/walls/{url}")));
+        let mut file_names: Vec<String> = std::fs::read_dir(&source_dir)
+            .unwrap()
+            .map(|dir_entry| dir_entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        file_names.sort();
+        let mut expected = vec![
+            file_name("a.jpg"),
+            file_name("c.jpg"),
+            MARKER_FILE_NAME.to_string(),
+            "notes.txt".to_string(),
+        ];
+        expected.sort();
+        assert_eq!(file_names, expected);
+        assert_eq!(
+            std::fs::read(source_dir.join(file_name("a.jpg"))).unwrap(),
+            small
+        );
+
+        // Listed downloads are kept as they are
+        source.fetch(&dir).unwrap();
+        assert_eq!(
+            std::fs::read_dir(&source_dir).unwrap().count(),
+            expected.len()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
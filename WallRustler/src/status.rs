@@ -0,0 +1,219 @@
+use crate::dbus::{CurrentWallpaper, DaemonProxyBlocking};
+
+/// Output formats of `wallrustler status`, for status bar modules polling the daemon
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StatusFormat {
+    Plain,
+    /// JSON with text, tooltip and class for a Waybar custom module
+    Waybar,
+    /// full_text, short_text and color lines
+    I3blocks,
+    /// A single line, greyed out with format tags while paused
+    Polybar,
+}
+
+impl StatusFormat {
+    pub fn parse(s: &str) -> Option<StatusFormat> {
+        match s {
+            "plain" => Some(StatusFormat::Plain),
+            "waybar" => Some(StatusFormat::Waybar),
+            "i3blocks" => Some(StatusFormat::I3blocks),
+            "polybar" => Some(StatusFormat::Polybar),
+            _ => None,
+        }
+    }
+}
+
+const PAUSED_COLOR: &str = "#888888";
+
+/// The status reported by the running daemon over D-Bus
+pub struct DaemonStatus {
+    pub wallpapers: Vec<CurrentWallpaper>,
+    pub paused: bool,
+    /// Seconds until the next change
+    pub next_change: u64,
+}
+
+impl DaemonStatus {
+    pub fn query() -> zbus::Result<DaemonStatus> {
+        let connection = zbus::blocking::Connection::session()?;
+        let (wallpapers, paused, next_change) =
+            DaemonProxyBlocking::new(&connection)?.get_status()?;
+
+        Ok(DaemonStatus {
+            wallpapers,
+            paused,
+            next_change,
+        })
+    }
+
+    pub fn format(&self, format: StatusFormat) -> String {
+        let text = self.text();
+        match format {
+            StatusFormat::Plain => format!("{text}\n{}", self.tooltip()),
+            StatusFormat::Waybar => serde_json::json!({
+                "text": escape_markup(&text),
+                "tooltip": escape_markup(&self.tooltip()),
+                "class": self.class(),
+                "alt": self.class(),
+            })
+            .to_string(),
+            StatusFormat::I3blocks => {
+                let short_text = self.file_name();
+                if self.paused {
+                    format!("{text}\n{short_text}\n{PAUSED_COLOR}")
+                } else {
+                    format!("{text}\n{short_text}")
+                }
+            }
+            StatusFormat::Polybar => {
+                // `%` starts a formatting tag
+                let text = text.replace('%', "%%");
+                if self.paused {
+                    format!("%{{F{PAUSED_COLOR}}}{text}%{{F-}}")
+                } else {
+                    text
+                }
+            }
+        }
+    }
+
+    fn file_name(&self) -> String {
+        self.wallpapers
+            .first()
+            .and_then(|wallpaper| wallpaper.path.file_name())
+            .map(|file_name| file_name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// File name of the first wallpaper and the time until the next change
+    fn text(&self) -> String {
+        if self.paused {
+            format!("{} (paused)", self.file_name())
+        } else {
+            format!(
+                "{} ({})",
+                self.file_name(),
+                format_duration(self.next_change)
+            )
+        }
+    }
+
+    fn tooltip(&self) -> String {
+        let mut lines: Vec<String> = self
+            .wallpapers
+            .iter()
+            .map(|wallpaper| {
+                let mut line = wallpaper.path.to_string_lossy().into_owned();
+                if wallpaper.width != 0 {
+                    line.push_str(&format!(" {}x{}", wallpaper.width, wallpaper.height));
+                }
+                if wallpaper.rating != 0 {
+                    line.push_str(&format!(" rating={}", wallpaper.rating));
+                }
+                line.push_str(&format!(" shown {} times", wallpaper.count));
+                line
+            })
+            .collect();
+        if self.paused {
+            lines.push("Rotation paused".to_string());
+        } else {
+            lines.push(format!(
+                "Next change in {}",
+                format_duration(self.next_change)
+            ));
+        }
+
+        lines.join("\n")
+    }
+
+    fn class(&self) -> &'static str {
+        if self.paused {
+            "paused"
+        } else {
+            "running"
+        }
+    }
+}
+
+/// `1h05m`, `12m` or `45s`
+fn format_duration(secs: u64) -> String {
+    match secs {
+        s if s >= 3600 => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{s}s"),
+    }
+}
+
+/// Waybar renders text and tooltip as Pango markup
+fn escape_markup(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(paused: bool) -> DaemonStatus {
+        DaemonStatus {
+            wallpapers: vec![CurrentWallpaper {
+                path: "/wallpapers/sea & \"sky\".jpg".into(),
+                width: 2560,
+                height: 1440,
+                rating: 4,
+                count: 3,
+            }],
+            paused,
+            next_change: 3900,
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration(45), "45s");
+        assert_eq!(format_duration(720), "12m");
+        assert_eq!(format_duration(3900), "1h05m");
+    }
+
+    #[test]
+    fn formats() {
+        let tooltip = "/wallpapers/sea & \"sky\".jpg 2560x1440 rating=4 shown 3 times";
+        assert_eq!(
+            status(false).format(StatusFormat::Plain),
+            format!("sea & \"sky\".jpg (1h05m)\n{tooltip}\nNext change in 1h05m")
+        );
+        assert_eq!(
+            status(true).format(StatusFormat::I3blocks),
+            "sea & \"sky\".jpg (paused)\nsea & \"sky\".jpg\n#888888"
+        );
+        assert_eq!(
+            status(true).format(StatusFormat::Polybar),
+            "%{F#888888}sea & \"sky\".jpg (paused)%{F-}"
+        );
+        assert_eq!(
+            status(false).format(StatusFormat::Polybar),
+            "sea & \"sky\".jpg (1h05m)"
+        );
+        let mut status = status(true);
+        status.wallpapers[0].path = "/wallpapers/100%{F#f00}.jpg".into();
+        assert_eq!(
+            status.format(StatusFormat::Polybar),
+            "%{F#888888}100%%{F#f00}.jpg (paused)%{F-}"
+        );
+    }
+
+    #[test]
+    fn waybar_output_is_escaped_json() {
+        let json: serde_json::Value =
+            serde_json::from_str(&status(true).format(StatusFormat::Waybar)).unwrap();
+        assert_eq!(json["text"], "sea &amp; \"sky\".jpg (paused)");
+        assert_eq!(json["class"], "paused");
+        assert_eq!(json["alt"], "paused");
+        assert!(json["tooltip"]
+            .as_str()
+            .unwrap()
+            .ends_with("\nRotation paused"));
+    }
+}
@@ -0,0 +1,218 @@
+use std::os::linux::net::SocketAddrExt;
+
+const SERVICE_NAME: &str = "wallrustler.service";
+/// Generous, the main loop might be busy rendering a large wallpaper
+const WATCHDOG_SEC: u64 = 120;
+
+/// Sends a state such as `READY=1` to the service manager, does nothing when not started by
+/// systemd
+pub fn notify(state: &str) -> Result<(), std::io::Error> {
+    let Some(socket_path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket_path = socket_path.to_string_lossy().into_owned();
+
+    let address = match socket_path.strip_prefix('@') {
+        Some(name) => std::os::unix::net::SocketAddr::from_abstract_name(name)?,
+        None => std::os::unix::net::SocketAddr::from_pathname(&socket_path)?,
+    };
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &address)?;
+
+    Ok(())
+}
+
+/// How often the watchdog has to be pinged, half of the configured timeout
+pub fn watchdog_interval() -> Option<std::time::Duration> {
+    parse_watchdog(
+        std::env::var("WATCHDOG_USEC").ok().as_deref(),
+        std::env::var("WATCHDOG_PID").ok().as_deref(),
+        std::process::id(),
+    )
+}
+
+/// `None` unless `WATCHDOG_USEC` is set and `WATCHDOG_PID`, when set, is ours
+fn parse_watchdog(
+    usec: Option<&str>,
+    pid: Option<&str>,
+    own_pid: u32,
+) -> Option<std::time::Duration> {
+    if pid.is_some_and(|pid| pid != own_pid.to_string()) {
+        return None;
+    }
+    let usec: u64 = usec?.parse().ok()?;
+
+    Some(std::time::Duration::from_micros(usec / 2))
+}
+
+/// Pings the watchdog from a thread while the main loop is busy with something long like a full
+/// rescan, until dropped
+pub struct Keepalive {
+    stop: std::sync::mpsc::Sender<()>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Keepalive {
+    pub fn start(interval: std::time::Duration) -> Keepalive {
+        let (stop, stopped) = std::sync::mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            while let Err(std::sync::mpsc::RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(interval)
+            {
+                if let Err(err) = notify("WATCHDOG=1") {
+                    error!("Unable to notify systemd: {err}");
+                }
+            }
+        });
+
+        Keepalive {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Writes a user unit running this executable with `args` and reloads the user manager
+pub fn install_service(args: &[String]) -> Result<std::path::PathBuf, std::io::Error> {
+    let exe = std::env::current_exe()?;
+    let exec_start: Vec<String> = std::iter::once(exe.to_string_lossy().into_owned())
+        .chain(args.iter().cloned())
+        .collect();
+
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".config"))
+        })
+        .ok_or_else(|| std::io::Error::other("Neither XDG_CONFIG_HOME nor HOME is set"))?;
+    let unit_path = config_dir.join("systemd").join("user").join(SERVICE_NAME);
+    std::fs::create_dir_all(unit_path.parent().unwrap())?;
+    std::fs::write(&unit_path, unit(&exec_start))?;
+
+    let output = std::process::Command::new("systemctl")
+        .arg("--user")
+        .arg("daemon-reload")
+        .output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!("{:?}", output)));
+    }
+
+    Ok(unit_path)
+}
+
+/// A notify service started with the graphical session, `exec_start` being the command line
+fn unit(exec_start: &[String]) -> String {
+    let exec_start: Vec<String> = exec_start.iter().map(|arg| quote(arg)).collect();
+
+    format!(
+        "[Unit]
+Description=WallRustler wallpaper rotation
+PartOf=graphical-session.target
+After=graphical-session.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={}
+Restart=on-failure
+WatchdogSec={WATCHDOG_SEC}
+
+[Install]
+WantedBy=graphical-session.target
+",
+        exec_start.join(" ")
+    )
+}
+
+/// Quotes arguments for ExecStart, which splits on whitespace and expands `$` and `%`
+fn quote(arg: &str) -> String {
+    let arg = arg.replace('%', "%%").replace('$', "$$");
+    if arg.contains(char::is_whitespace) || arg.contains(['"', '\\', '\'']) {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_exec_start_arguments() {
+        assert_eq!(quote("/usr/bin/wallrustler"), "/usr/bin/wallrustler");
+        assert_eq!(quote("50%"), "50%%");
+        assert_eq!(quote("$HOME"), "$$HOME");
+        assert_eq!(
+            quote("/home/me/My Wallpapers"),
+            "\"/home/me/My Wallpapers\""
+        );
+        assert_eq!(quote("it's"), "\"it's\"");
+        assert_eq!(quote("a\"b\\c"), "\"a\\\"b\\\\c\"");
+    }
+
+    #[test]
+    fn watchdog_interval_is_half_the_timeout() {
+        assert_eq!(
+            parse_watchdog(Some("120000000"), None, 42),
+            Some(std::time::Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_watchdog(Some("120000000"), Some("42"), 42),
+            Some(std::time::Duration::from_secs(60))
+        );
+        assert_eq!(parse_watchdog(Some("120000000"), Some("43"), 42), None);
+        assert_eq!(parse_watchdog(None, Some("42"), 42), None);
+        assert_eq!(parse_watchdog(Some("soon"), None, 42), None);
+    }
+
+    #[test]
+    fn unit_runs_the_quoted_command() {
+        let unit = unit(&[
+            "/usr/bin/wallrustler".to_string(),
+            "run".to_string(),
+            "/home/me/My Wallpapers".to_string(),
+        ]);
+        assert!(unit
+            .lines()
+            .any(|line| line == "ExecStart=/usr/bin/wallrustler run \"/home/me/My Wallpapers\""));
+        assert!(unit.lines().any(|line| line == "Type=notify"));
+        assert!(unit
+            .lines()
+            .any(|line| line == format!("WatchdogSec={WATCHDOG_SEC}")));
+        assert!(unit.ends_with("[Install]\nWantedBy=graphical-session.target\n"));
+    }
+
+    #[test]
+    fn keepalive_pings_until_dropped() {
+        let socket_path =
+            std::env::temp_dir().join(format!("wallrustler-notify-{}", std::process::id()));
+        let _ = std::fs::remove_file(&socket_path);
+        let socket = std::os::unix::net::UnixDatagram::bind(&socket_path).unwrap();
+        socket.set_nonblocking(true).unwrap();
+        std::env::set_var("NOTIFY_SOCKET", &socket_path);
+
+        let keepalive = Keepalive::start(std::time::Duration::from_millis(20));
+        std::thread::sleep(std::time::Duration::from_millis(150));
+        drop(keepalive);
+        let mut buf = [0; 64];
+        let mut pings = 0;
+        while let Ok(len) = socket.recv(&mut buf) {
+            assert_eq!(&buf[..len], b"WATCHDOG=1");
+            pings += 1;
+        }
+        assert!(pings >= 2);
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        assert!(socket.recv(&mut buf).is_err());
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+}
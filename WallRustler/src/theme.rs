@@ -0,0 +1,385 @@
+use crate::rotator::{ChangeContext, Extension, Pick};
+
+/// Longest side of the thumbnail the palette is extracted from
+const THUMBNAIL_SIZE: u32 = 128;
+/// Colours extracted from the wallpaper, spread over the 16 terminal colours like pywal does
+const PALETTE_SIZE: usize = 8;
+const COLORS_FILE_NAME: &str = "colors.json";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Rgb(pub [u8; 3]);
+
+impl Rgb {
+    pub fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.0[0], self.0[1], self.0[2])
+    }
+
+    fn luminance(&self) -> f64 {
+        0.2126 * self.0[0] as f64 + 0.7152 * self.0[1] as f64 + 0.0722 * self.0[2] as f64
+    }
+
+    /// Mixes with `other`, 0 keeps the colour and 1 gives `other`
+    fn blend(&self, other: Rgb, amount: f64) -> Rgb {
+        let channel = |i: usize| {
+            (self.0[i] as f64 + (other.0[i] as f64 - self.0[i] as f64) * amount).round() as u8
+        };
+        Rgb([channel(0), channel(1), channel(2)])
+    }
+}
+
+/// The 16 colour scheme in pywal's layout: colour 0 is the background, 7 and 15 the
+/// foreground and 8 a dimmed background, 9-14 repeat 1-6
+#[derive(Debug, PartialEq, Clone)]
+pub struct ColorScheme {
+    pub wallpaper: std::path::PathBuf,
+    pub colors: [Rgb; 16],
+}
+
+impl ColorScheme {
+    pub fn from_wallpaper(wallpaper: &std::path::Path) -> Result<ColorScheme, std::io::Error> {
+        let mut img = image::open(wallpaper).map_err(std::io::Error::other)?;
+        img = img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+        let pixels: Vec<[u8; 3]> = img.to_rgb8().pixels().map(|pixel| pixel.0).collect();
+
+        let mut palette = median_cut(pixels, PALETTE_SIZE);
+        if palette.is_empty() {
+            return Err(std::io::Error::other("Empty image"));
+        }
+        palette.sort_by(|a, b| a.luminance().total_cmp(&b.luminance()));
+        // Small images give fewer colours than asked for
+        while palette.len() < PALETTE_SIZE {
+            palette.push(*palette.last().unwrap());
+        }
+
+        let black = Rgb([0, 0, 0]);
+        let white = Rgb([255, 255, 255]);
+        let background = palette[0].blend(black, 0.6);
+        let foreground = palette[PALETTE_SIZE - 1].blend(white, 0.6);
+        let mut colors = [background; 16];
+        colors[1..7].copy_from_slice(&palette[1..7]);
+        colors[7] = foreground;
+        colors[8] = background.blend(foreground, 0.3);
+        colors[9..15].copy_from_slice(&palette[1..7]);
+        colors[15] = foreground;
+
+        Ok(ColorScheme {
+            wallpaper: wallpaper.to_path_buf(),
+            colors,
+        })
+    }
+
+    pub fn background(&self) -> Rgb {
+        self.colors[0]
+    }
+
+    pub fn foreground(&self) -> Rgb {
+        self.colors[15]
+    }
+
+    /// Same structure as pywal's `colors.json`
+    pub fn to_json(&self) -> String {
+        let colors: serde_json::Map<String, serde_json::Value> = self
+            .colors
+            .iter()
+            .enumerate()
+            .map(|(i, color)| (format!("color{i}"), color.hex().into()))
+            .collect();
+
+        let json = serde_json::json!({
+            "wallpaper": self.wallpaper.to_string_lossy(),
+            "alpha": "100",
+            "special": {
+                "background": self.background().hex(),
+                "foreground": self.foreground().hex(),
+                "cursor": self.foreground().hex(),
+            },
+            "colors": colors,
+        });
+        format!("{json:#}\n")
+    }
+
+    /// Fills pywal style placeholders: `{color0}`-`{color15}`, `{background}`, `{foreground}`,
+    /// `{cursor}` and `{wallpaper}`, `.strip` drops the `#`, `{{` and `}}` are literal braces
+    pub fn render_template(&self, template: &str) -> String {
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find(['{', '}']) {
+            rendered.push_str(&rest[..start]);
+            rest = &rest[start..];
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                rendered.push_str(&rest[..1]);
+                rest = &rest[2..];
+                continue;
+            }
+
+            let value = rest
+                .strip_prefix('{')
+                .and_then(|placeholder| placeholder.split_once('}'))
+                .and_then(|(name, _)| Some((name, self.placeholder(name)?)));
+            match value {
+                Some((name, value)) => {
+                    rendered.push_str(&value);
+                    rest = &rest[name.len() + 2..];
+                }
+                None => {
+                    rendered.push_str(&rest[..1]);
+                    rest = &rest[1..];
+                }
+            }
+        }
+        rendered.push_str(rest);
+
+        rendered
+    }
+
+    fn placeholder(&self, name: &str) -> Option<String> {
+        let (name, strip) = match name.strip_suffix(".strip") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        let color = match name {
+            "wallpaper" => return Some(self.wallpaper.to_string_lossy().into_owned()),
+            "background" => self.background(),
+            "foreground" | "cursor" => self.foreground(),
+            name => *self
+                .colors
+                .get(name.strip_prefix("color")?.parse::<usize>().ok()?)?,
+        };
+
+        let hex = color.hex();
+        Some(if strip { hex[1..].to_string() } else { hex })
+    }
+}
+
+/// Writes the colour scheme of every new wallpaper as `colors.json` into `output_dir`, along
+/// with the rendered templates
+#[derive(Debug, PartialEq, Clone)]
+pub struct Theme {
+    pub output_dir: std::path::PathBuf,
+    pub templates_dir: Option<std::path::PathBuf>,
+}
+
+impl Theme {
+    /// `~/.cache/wal`, where pywal users' configurations already look
+    pub fn default_output_dir() -> std::path::PathBuf {
+        crate::get_cache_dir()
+            .parent()
+            .map(|cache_dir| cache_dir.join("wal"))
+            .unwrap_or_else(|| std::path::PathBuf::from("wal"))
+    }
+
+    /// `~/.config/wal/templates` when it exists
+    pub fn default_templates_dir() -> Option<std::path::PathBuf> {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(std::path::PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".config"))
+            })
+            .map(|config_dir| config_dir.join("wal").join("templates"))
+            .filter(|templates_dir| templates_dir.is_dir())
+    }
+
+    /// Returns the path of the written `colors.json`
+    pub fn apply(&self, wallpaper: &std::path::Path) -> Result<std::path::PathBuf, std::io::Error> {
+        let scheme = ColorScheme::from_wallpaper(wallpaper)?;
+        std::fs::create_dir_all(&self.output_dir)?;
+        let colors_path = self.output_dir.join(COLORS_FILE_NAME);
+        std::fs::write(&colors_path, scheme.to_json())?;
+
+        if let Some(templates_dir) = &self.templates_dir {
+            for template in std::fs::read_dir(templates_dir)? {
+                let template = template?.path();
+                let Some(file_name) = template.file_name().filter(|_| template.is_file()) else {
+                    continue;
+                };
+                // It would replace the scheme the hooks are pointed to
+                if file_name == COLORS_FILE_NAME {
+                    warn!(
+                        "Skipping the template {:?}, it would overwrite the colour scheme",
+                        template
+                    );
+                    continue;
+                }
+                let rendered = scheme.render_template(&std::fs::read_to_string(&template)?);
+                std::fs::write(self.output_dir.join(file_name), rendered)?;
+            }
+        }
+        debug!("Wrote the colour scheme of {:?}", wallpaper);
+
+        Ok(colors_path)
+    }
+}
+
+/// Writes the colour scheme of the first wallpaper of every change
+impl Extension for Theme {
+    fn changed(&mut self, change: &mut ChangeContext, picks: &[Pick]) {
+        let Some(pick) = picks.first() else {
+            return;
+        };
+        match self.apply(pick.wallpaper) {
+            Ok(colors_path) => change.colors = Some(colors_path),
+            Err(err) => error!(
+                "Unable to extract the colour scheme of {:?}: {err}",
+                pick.wallpaper
+            ),
+        }
+    }
+}
+
+/// Splits the box with the widest channel range at its median until there are `count` boxes,
+/// each giving its average colour
+fn median_cut(pixels: Vec<[u8; 3]>, count: usize) -> Vec<Rgb> {
+    if pixels.is_empty() {
+        return vec![];
+    }
+
+    let mut boxes = vec![pixels];
+    while boxes.len() < count {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, pixels)| pixels.len() > 1)
+            .map(|(i, pixels)| {
+                let (channel, range) = widest_channel(pixels);
+                (i, channel, range)
+            })
+            .filter(|(_, _, range)| *range > 0)
+            .max_by_key(|(_, _, range)| *range);
+        let Some((i, channel, _)) = widest else {
+            break;
+        };
+
+        let mut pixels = boxes.swap_remove(i);
+        pixels.sort_unstable_by_key(|pixel| pixel[channel]);
+        let upper = pixels.split_off(pixels.len() / 2);
+        boxes.push(pixels);
+        boxes.push(upper);
+    }
+
+    boxes.iter().map(|pixels| average(pixels)).collect()
+}
+
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let (min, max) = pixels.iter().fold((u8::MAX, u8::MIN), |(min, max), pixel| {
+                (min.min(pixel[channel]), max.max(pixel[channel]))
+            });
+            (channel, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap()
+}
+
+fn average(pixels: &[[u8; 3]]) -> Rgb {
+    let mut sum = [0u64; 3];
+    for pixel in pixels {
+        for channel in 0..3 {
+            sum[channel] += pixel[channel] as u64;
+        }
+    }
+    let len = pixels.len() as u64;
+
+    Rgb([
+        (sum[0] / len) as u8,
+        (sum[1] / len) as u8,
+        (sum[2] / len) as u8,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheme() -> ColorScheme {
+        let mut colors = [Rgb([0, 0, 0]); 16];
+        for (i, color) in colors.iter_mut().enumerate() {
+            *color = Rgb([i as u8 * 16, 0x0a, 0xff]);
+        }
+
+        ColorScheme {
+            wallpaper: "/wallpapers/sea.jpg".into(),
+            colors,
+        }
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        let scheme = scheme();
+        assert_eq!(
+            scheme.render_template("bg={background} fg={foreground.strip} c10={color10}"),
+            "bg=#000aff fg=f00aff c10=#a00aff"
+        );
+        assert_eq!(
+            scheme.render_template("{wallpaper} {cursor}"),
+            "/wallpapers/sea.jpg #f00aff"
+        );
+    }
+
+    #[test]
+    fn keeps_literal_and_unknown_braces() {
+        let scheme = scheme();
+        assert_eq!(
+            scheme.render_template("a {{ b }} {{color1}}"),
+            "a { b } {color1}"
+        );
+        assert_eq!(
+            scheme.render_template("{color16} {colour1} {} {color1"),
+            "{color16} {colour1} {} {color1"
+        );
+        assert_eq!(
+            scheme.render_template("x { y } {color1.strip}}"),
+            "x { y } 100aff}"
+        );
+    }
+
+    #[test]
+    fn writes_pywal_json() {
+        let json: serde_json::Value = serde_json::from_str(&scheme().to_json()).unwrap();
+        assert_eq!(json["wallpaper"], "/wallpapers/sea.jpg");
+        assert_eq!(json["special"]["background"], "#000aff");
+        assert_eq!(json["colors"]["color15"], "#f00aff");
+    }
+
+    #[test]
+    fn median_cut_splits_distinct_colours() {
+        assert_eq!(median_cut(vec![], 4), vec![]);
+
+        let pixels = [[0, 0, 0], [255, 0, 0], [0, 0, 250], [0, 0, 255]];
+        let mut palette = median_cut(pixels.repeat(3), 3);
+        palette.sort_by_key(|color| color.0);
+        assert_eq!(
+            palette,
+            vec![Rgb([0, 0, 0]), Rgb([0, 0, 252]), Rgb([255, 0, 0])]
+        );
+
+        // A single colour can't be split further
+        assert_eq!(median_cut(vec![[7, 7, 7]; 10], 8), vec![Rgb([7, 7, 7])]);
+    }
+
+    #[test]
+    fn apply_keeps_the_scheme_over_a_template_of_the_same_name() {
+        let dir = std::env::temp_dir().join(format!("wallrustler-theme-{}", std::process::id()));
+        let templates_dir = dir.join("templates");
+        std::fs::create_dir_all(&templates_dir).unwrap();
+        let wallpaper = dir.join("wallpaper.png");
+        image::RgbImage::from_pixel(4, 4, image::Rgb([200, 30, 30]))
+            .save(&wallpaper)
+            .unwrap();
+        std::fs::write(templates_dir.join(COLORS_FILE_NAME), "{background}").unwrap();
+        std::fs::write(templates_dir.join("colors.sh"), "bg={background.strip}").unwrap();
+
+        let theme = Theme {
+            output_dir: dir.join("wal"),
+            templates_dir: Some(templates_dir),
+        };
+        let colors_path = theme.apply(&wallpaper).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(colors_path).unwrap()).unwrap();
+        let shell = std::fs::read_to_string(dir.join("wal").join("colors.sh")).unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let background = json["special"]["background"].as_str().unwrap();
+        assert_eq!(shell, format!("bg={}", &background[1..]));
+    }
+}
@@ -0,0 +1,173 @@
+use notify::event::{EventKind, ModifyKind};
+use notify::Watcher;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Change {
+    /// Wallpaper file name relative to the watched directory
+    Added(String),
+    Removed(String),
+    /// A directory was added, removed or renamed, the whole tree has to be rescanned
+    Rescan,
+}
+
+/// Watches the wallpaper directory, the paths it reports are turned into changes by
+/// `get_changes` once they stopped changing
+pub struct DirWatcher {
+    dir: std::path::PathBuf,
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl DirWatcher {
+    /// `on_paths` is called from the notify thread with the paths of every relevant event
+    pub fn new(
+        dir: &std::path::Path,
+        on_paths: impl Fn(Vec<std::path::PathBuf>) + Send + 'static,
+    ) -> notify::Result<DirWatcher> {
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                Ok(event) => {
+                    if is_relevant(&event.kind) {
+                        on_paths(event.paths);
+                    }
+                }
+                Err(err) => error!("Watcher error: {err}"),
+            })?;
+        watcher.watch(dir, notify::RecursiveMode::Recursive)?;
+
+        Ok(DirWatcher {
+            dir: dir.to_path_buf(),
+            _watcher: watcher,
+        })
+    }
+
+    /// Decides what happened from the current state of the paths rather than from the event
+    /// kinds, which differ between platforms, especially for renames. A changed sidecar adds its
+    /// wallpaper again, which refreshes its tags and metadata
+    pub fn get_changes(&self, paths: &mut Vec<std::path::PathBuf>) -> Vec<Change> {
+        paths.sort();
+        paths.dedup();
+
+        let mut changes = vec![];
+        for path in paths.drain(..) {
+            let Ok(file_name) = path.strip_prefix(&self.dir) else {
+                continue;
+            };
+            let Some(file_name) = file_name.to_str().map(|s| s.to_string()) else {
+                warn!("Invalid Unicode file name: {:?}", file_name);
+                continue;
+            };
+
+            let change = if let Some(wallpapers) = sidecar_wallpapers(&path) {
+                changes.extend(wallpapers.into_iter().filter_map(|wallpaper| {
+                    let file_name = wallpaper.strip_prefix(&self.dir).ok()?.to_str()?;
+                    Some(Change::Added(file_name.to_string()))
+                }));
+                continue;
+            } else if path.is_dir() {
+                Change::Rescan
+            } else if path.is_file() {
+                if !crate::is_wallpaper_file(&path) {
+                    continue;
+                }
+                Change::Added(file_name)
+            } else {
+                Change::Removed(file_name)
+            };
+            changes.push(change);
+        }
+        let mut unique = vec![];
+        for change in changes {
+            if !unique.contains(&change) {
+                unique.push(change);
+            }
+        }
+
+        unique
+    }
+}
+
+/// The wallpapers a `.tags` or `.xmp` sidecar belongs to, `None` for other files. `image.jpg.tags`
+/// and `image.jpg.xmp` belong to `image.jpg`, `image.xmp` to any `image.*`
+fn sidecar_wallpapers(path: &std::path::Path) -> Option<Vec<std::path::PathBuf>> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    if extension != crate::TAGS_EXTENSION && extension != "xmp" {
+        return None;
+    }
+
+    let mut wallpapers = vec![path.with_extension("")];
+    if extension == "xmp" {
+        let siblings = path
+            .parent()
+            .and_then(|dir| std::fs::read_dir(dir).ok())
+            .into_iter()
+            .flatten()
+            .filter_map(|dir_entry| Some(dir_entry.ok()?.path()))
+            .filter(|sibling| sibling != path && sibling.file_stem() == path.file_stem());
+        wallpapers.extend(siblings);
+    }
+    wallpapers.retain(|wallpaper| wallpaper.is_file() && crate::is_wallpaper_file(wallpaper));
+
+    Some(wallpapers)
+}
+
+fn is_relevant(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Create(_)
+            | EventKind::Remove(_)
+            | EventKind::Modify(ModifyKind::Name(_))
+            | EventKind::Modify(ModifyKind::Data(_))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_changed_paths() {
+        let dir = std::env::temp_dir().join(format!("wallrustler-watcher-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sea")).unwrap();
+        for file_name in ["a.jpg", "renamed.jpg", "b.png", "c.jpg", "notes.txt"] {
+            std::fs::write(dir.join(file_name), "").unwrap();
+        }
+        std::fs::write(dir.join("a.jpg.tags"), "beach").unwrap();
+        std::fs::write(dir.join("b.xmp"), "").unwrap();
+        let watcher = DirWatcher::new(&dir, |_| {}).unwrap();
+
+        let mut paths: Vec<std::path::PathBuf> = [
+            "a.jpg",
+            "a.jpg",
+            // Renamed from old.jpg
+            "old.jpg",
+            "renamed.jpg",
+            "sea",
+            "notes.txt",
+            // Sidecars, written or removed
+            "a.jpg.tags",
+            "b.xmp",
+            "c.jpg.xmp",
+            "orphan.jpg.tags",
+        ]
+        .iter()
+        .map(|file_name| dir.join(file_name))
+        .collect();
+        paths.push("/elsewhere/d.jpg".into());
+        let changes = watcher.get_changes(&mut paths);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(paths.is_empty());
+        assert_eq!(
+            changes,
+            vec![
+                Change::Added("a.jpg".to_string()),
+                Change::Added("b.png".to_string()),
+                Change::Added("c.jpg".to_string()),
+                Change::Removed("old.jpg".to_string()),
+                Change::Added("renamed.jpg".to_string()),
+                Change::Rescan,
+            ]
+        );
+    }
+}
@@ -1,8 +1,15 @@
+use crate::Output;
 use core::ffi::c_void;
 use std::os::windows::ffi::OsStrExt;
 
 pub struct WallSetter {}
 
+impl Default for WallSetter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl WallSetter {
     pub fn new() -> WallSetter {
         WallSetter {}
@@ -14,6 +21,51 @@ impl WallSetter {
         self.set_wallpaper_windows(wallpaper)
     }
 
+    /// Windows applies a single wallpaper to all monitors, the first one is used
+    pub fn set_output_wallpapers(
+        &self,
+        wallpapers: &[(Output, std::path::PathBuf)],
+    ) -> Result<(), std::io::Error> {
+        if let Some((_, wallpaper)) = wallpapers.first() {
+            self.set_wallpaper_windows(wallpaper)?;
+        }
+
+        Ok(())
+    }
+
+    /// Nothing is deferred on Windows
+    pub fn next_deadline(&self) -> Option<std::time::Instant> {
+        None
+    }
+
+    pub fn run_pending(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    pub fn backend(&self) -> &'static str {
+        "SystemParametersInfo"
+    }
+
+    /// Only the primary monitor is reported
+    pub fn get_outputs(&self) -> Vec<Output> {
+        let (width, height) = unsafe {
+            (
+                windows_sys::Win32::UI::WindowsAndMessaging::GetSystemMetrics(
+                    windows_sys::Win32::UI::WindowsAndMessaging::SM_CXSCREEN,
+                ),
+                windows_sys::Win32::UI::WindowsAndMessaging::GetSystemMetrics(
+                    windows_sys::Win32::UI::WindowsAndMessaging::SM_CYSCREEN,
+                ),
+            )
+        };
+
+        vec![Output {
+            name: "primary".to_string(),
+            width: width.max(0) as u32,
+            height: height.max(0) as u32,
+        }]
+    }
+
     pub fn is_running(&self) -> bool {
         let output = std::process::Command::new("tasklist")
             .arg("/fo")
@@ -37,20 +89,13 @@ impl WallSetter {
 
     pub fn kill(&self) -> Result<(), std::io::Error> {
         let pid = self.get_running_pid()?;
-        let output = std::process::Command::new("taskkill")
-            .arg("/f")
-            .arg("/t")
-            .arg("/PID")
-            .arg(pid.to_string())
-            .output()?;
-
-        if !output.status.success() {
-            eprintln!("{:?}", output.stderr);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("{:?}", output),
-            ));
-        }
+        crate::run_command(
+            std::process::Command::new("taskkill")
+                .arg("/f")
+                .arg("/t")
+                .arg("/PID")
+                .arg(pid.to_string()),
+        )?;
 
         Ok(())
     }
@@ -81,19 +126,19 @@ impl WallSetter {
             .to_string();
 
         if pid.is_empty() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("tasklist invalid out: {}", out),
-            ));
+            return Err(std::io::Error::other(format!(
+                "tasklist invalid out: {}",
+                out
+            )));
         }
 
         if let Ok(pid) = pid.parse() {
             Ok(pid)
         } else {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("tasklist invalid pid: {}", pid),
-            ));
+            return Err(std::io::Error::other(format!(
+                "tasklist invalid pid: {}",
+                pid
+            )));
         }
     }
 